@@ -38,6 +38,13 @@ pub(crate) fn api_method(
         input.block.stmts.insert(0, pre_update_stmt);
     }
 
+    // Record the method name for the diagnostics of the conflicting state borrows.
+    let method_scope_stmt = syn::parse2::<syn::Stmt>(quote! {
+        let __method_scope = ::ic_canister::storage::borrow::MethodScope::enter(#method_name);
+    })
+    .unwrap();
+    input.block.stmts.insert(0, method_scope_stmt);
+
     let input = input;
     let method = &input.sig.ident;
     let orig_vis = input.vis.clone();
@@ -190,6 +197,37 @@ pub(crate) fn api_method(
         quote! {}
     };
 
+    // State changes made by a query are treated according to the current `QueryMode`.
+    let finish_query = if method_type == "query" {
+        quote! {
+            if __result.is_ok() {
+                ::ic_canister::testing::finish_query(#method_name, &__snapshot);
+            }
+        }
+    } else {
+        quote! {}
+    };
+
+    // The ingress update calls are inspected with the `InspectMessage` implementation first.
+    let (inspect_message, inspect_notify) = if method_type == "update" || method_type == "oneway" {
+        (
+            quote! {
+                if let Err(message) = ::ic_canister::testing::inspect_message(self, #method_name) {
+                    return Box::pin(async move {
+                        Err((::ic_cdk::api::call::RejectionCode::CanisterReject, message))
+                    });
+                }
+            },
+            quote! {
+                if let Err(message) = ::ic_canister::testing::inspect_message(self, #method_name) {
+                    return Err((::ic_cdk::api::call::RejectionCode::CanisterReject, message));
+                }
+            },
+        )
+    } else {
+        (quote! {}, quote! {})
+    };
+
     let export_function = if parameters.is_trait {
         let mut methods = METHODS_EXPORTS.lock().unwrap();
         methods.push(ExportMethodData {
@@ -230,6 +268,23 @@ pub(crate) fn api_method(
         }
     };
 
+    // A trap can happen both when the method is called and when the returned future is polled.
+    // The body of an async method only runs when the future is polled, so the snapshot is taken by
+    // `CatchTrap`, that rolls back the changes made after the last await point of the method.
+    let (take_snapshot, await_result) = if input.sig.asyncness.is_some() {
+        (
+            quote! {},
+            quote! {
+                let __result = match __result {
+                    Ok(future) => ::ic_canister::testing::CatchTrap::new(future, &mut __snapshot).await,
+                    Err(message) => Err(message),
+                };
+            },
+        )
+    } else {
+        (quote! { __snapshot.refresh(); }, quote! {})
+    };
+
     let expanded = quote! {
         #[allow(dead_code)]
         #input
@@ -239,18 +294,34 @@ pub(crate) fn api_method(
         #[cfg(not(target_arch = "wasm32"))]
         #[allow(dead_code)]
         #orig_vis fn #internal_method<#self_lifetime>(#args) -> ::std::pin::Pin<Box<dyn ::core::future::Future<Output = ::ic_cdk::api::call::CallResult<#inner_return_type>> + #return_lifetime>> {
-            // todo: trap handler
-            let result = self. #method(#args_destr);
-            Box::pin(async move { Ok(result #await_call) })
+            #inspect_message
+            let mut __snapshot = ::ic_canister::Canister::__state_snapshot(self);
+            #take_snapshot
+            let __result = ::ic_canister::testing::catch_trap(move || self. #method(#args_destr));
+            if __result.is_err() {
+                __snapshot.restore();
+            }
+
+            Box::pin(async move {
+                #await_result
+                #finish_query
+                __result.map_err(|message| (::ic_cdk::api::call::RejectionCode::CanisterError, message))
+            })
         }
 
         #[cfg(not(target_arch = "wasm32"))]
         #[allow(unused_mut)]
         #[allow(unused_must_use)]
-        #orig_vis fn #internal_method_notify<#self_lifetime>(#args) -> ::std::result::Result<(), ::ic_cdk::api::call::RejectionCode> {
-            // todo: trap handler
-            self. #method(#args_destr);
-            Ok(())
+        #orig_vis fn #internal_method_notify<#self_lifetime>(#args) -> ::ic_cdk::api::call::CallResult<()> {
+            // The trap is returned to `canister_notify!`, that refunds the attached cycles, but
+            // doesn't report it to the caller.
+            #inspect_notify
+            let mut __snapshot = ::ic_canister::Canister::__state_snapshot(self);
+            __snapshot.refresh();
+            ::ic_canister::testing::catch_trap(move || { self. #method(#args_destr); }).map_err(|message| {
+                __snapshot.restore();
+                (::ic_cdk::api::call::RejectionCode::CanisterError, message)
+            })
         }
     };
 
@@ -48,6 +48,7 @@ pub(crate) fn canister_call(input: TokenStream) -> TokenStream {
     let inner_method = Ident::new(&format!("__{method}"), method.span());
     let args = normalize_args(&input.method_call.args);
     let cycles = input.cycles;
+    let attached_cycles = attached_cycles(&cycles, quote! { u64 });
     let cdk_call = get_cdk_call(
         quote! {#canister.principal()},
         &method_name,
@@ -65,15 +66,13 @@ pub(crate) fn canister_call(input: TokenStream) -> TokenStream {
 
             #[cfg(not(target_arch = "wasm32"))]
             async {
-                let __caller = ::ic_canister::ic_kit::ic::caller();
-                let __id = ::ic_canister::ic_kit::ic::id();
-                ::ic_canister::ic_kit::inject::get_context().update_caller(__id);
-                ::ic_canister::ic_kit::inject::get_context().update_id(#canister.principal());
+                ::ic_canister::testing::await_point().await;
+                let __frame = ::ic_canister::testing::CallFrame::enter(#canister.principal(), #attached_cycles);
 
                 let result = #canister.#inner_method(#args).await;
 
-                ::ic_canister::ic_kit::inject::get_context().update_caller(__caller);
-                ::ic_canister::ic_kit::inject::get_context().update_id(__id);
+                __frame.finish(&result);
+                ::ic_canister::testing::await_point().await;
                 result
             }
         }
@@ -91,6 +90,7 @@ pub(crate) fn canister_notify(input: TokenStream) -> TokenStream {
     let inner_method = Ident::new(&format!("___{method}"), method.span());
     let args = normalize_args(&input.method_call.args);
     let cycles = input.cycles;
+    let attached_cycles = attached_cycles(&cycles, quote! { u128 });
     let cdk_call = get_cdk_notify(quote! {#canister.principal()}, &method_name, &args, cycles);
 
     let expanded = quote! {
@@ -102,16 +102,17 @@ pub(crate) fn canister_notify(input: TokenStream) -> TokenStream {
 
             #[cfg(not(target_arch = "wasm32"))]
             {
-                let __caller = ::ic_canister::ic_kit::ic::caller();
-                let __id = ::ic_canister::ic_kit::ic::id();
-                ::ic_canister::ic_kit::inject::get_context().update_caller(__id);
-                ::ic_canister::ic_kit::inject::get_context().update_id(#canister.principal());
-
+                let __frame = ::ic_canister::testing::CallFrame::enter(#canister.principal(), #attached_cycles);
                 let result = #canister.#inner_method(#args);
-
-                ::ic_canister::ic_kit::inject::get_context().update_caller(__caller);
-                ::ic_canister::ic_kit::inject::get_context().update_id(__id);
-                result            }
+                __frame.finish(&result);
+
+                // The notification is enqueued successfully even if the callee traps, so only the
+                // rejection by the callee is reported to the caller.
+                result.or_else(|(code, _)| match code {
+                    ::ic_cdk::api::call::RejectionCode::CanisterError => Ok(()),
+                    code => Err(code),
+                })
+            }
         }
     };
 
@@ -164,6 +165,7 @@ pub(crate) fn virtual_canister_call(input: TokenStream) -> TokenStream {
     let method_name = input.method_name.value();
     let response_type = &input.response_type;
     let cycles = input.cycles;
+    let attached_cycles = attached_cycles(&cycles, quote! { u64 });
 
     let cdk_call = get_cdk_call(
         quote! {#principal},
@@ -194,7 +196,10 @@ pub(crate) fn virtual_canister_call(input: TokenStream) -> TokenStream {
                 Err(e) => return Err((::ic_cdk::api::call::RejectionCode::Unknown, format!("failed to serialize arguments: {}", e))),
             };
 
-            let result = ::ic_canister::call_virtual_responder(#principal, #method_name, encoded_args)?;
+            ::ic_canister::testing::await_point().await;
+            let result = ::ic_canister::call_virtual_responder(#principal, #method_name, encoded_args, #attached_cycles).await;
+            ::ic_canister::testing::await_point().await;
+            let result = result?;
 
             let result = match #decode {
                 Ok(v) => v #tuple_index,
@@ -230,6 +235,7 @@ pub(crate) fn virtual_canister_notify(input: TokenStream) -> TokenStream {
     let args = normalize_args(&input.args.elems);
     let method_name = input.method_name.value();
     let cycles = input.cycles;
+    let attached_cycles = attached_cycles(&cycles, quote! { u128 });
 
     let cdk_call = get_cdk_notify(quote! {#principal}, &method_name, &args, cycles);
 
@@ -240,7 +246,7 @@ pub(crate) fn virtual_canister_notify(input: TokenStream) -> TokenStream {
                 Err(e) => return Err((::ic_cdk::api::call::RejectionCode::Unknown, format!("failed to serialize arguments: {}", e))),
             };
 
-            let result = ::ic_canister::call_virtual_responder(#principal, #method_name, encoded_args)?;
+            let result = ::ic_canister::call_virtual_responder(#principal, #method_name, encoded_args, #attached_cycles).await?;
             Ok(())
         }
     };
@@ -328,6 +334,23 @@ fn get_cdk_notify(
     }
 }
 
+/// Cycles attached to a call in the testing environment. Calls take the amount as `u64` and
+/// notifications as `u128` (`cycles_type`), so it is converted to the wider type.
+fn attached_cycles(
+    cycles: &Option<Expr>,
+    cycles_type: proc_macro2::TokenStream,
+) -> proc_macro2::TokenStream {
+    match cycles {
+        Some(cycles) => quote! {
+            {
+                let cycles: #cycles_type = #cycles;
+                u128::from(cycles)
+            }
+        },
+        None => quote! { 0u128 },
+    }
+}
+
 fn normalize_args(args: &Punctuated<Expr, Token![,]>) -> Punctuated<Expr, Token![,]> {
     let mut args = args.clone();
     if !args.empty_or_trailing() {
@@ -14,6 +14,7 @@ pub fn derive_canister(input: TokenStream) -> TokenStream {
         .expect("static value parsing always succeeds");
 
     let derive_upgrade = derive_upgrade_methods(&input);
+    let inspect_message = has_struct_attr(&input, "canister_inspect_message");
 
     let name = input.ident;
 
@@ -62,28 +63,59 @@ pub fn derive_canister(input: TokenStream) -> TokenStream {
     let state_fields = state_fields.iter().map(|field| {
         let field_name = field.ident.clone().expect("Fields always have name");
         let field_type = get_state_type(&field.ty);
+        let is_collection = is_stable_collection(field_type);
 
-        if !used_types.insert(field_type) {
+        // Collections are stored by the field name, so several collections can have the same type.
+        if !is_collection && !used_types.insert(field_type) {
             panic!("canister cannot have two fields with the type {field_type:?}",);
         }
 
         let is_stable = is_state_field_stable(field);
-        (field_name, field_type, is_stable)
+        (field_name, field_type, is_stable, is_collection)
     });
 
-    let mut stable_field = None;
+    let mut stable_fields = vec![];
+    let mut snapshot_fields = vec![];
+    let mut has_collections = false;
     let state_fields_wasm = if state_fields.len() > 0 {
         let mut state_fields_wasm = vec![];
 
-        for (field_name, field_type, is_stable) in state_fields {
+        for (field_name, field_type, is_stable, is_collection) in state_fields {
+            // Stable collections already live in the stable memory, so they are neither stored
+            // by the upgrade methods, nor rolled back on traps.
+            if is_collection {
+                has_collections = true;
+                state_fields_wasm.push(quote! {
+                    #field_name : ::std::rc::Rc::new(::std::cell::RefCell::new(
+                        <#field_type as ::ic_storage::collections::StableCollection>::open(stringify!(#field_name))
+                    ))
+                });
+                continue;
+            }
+
             state_fields_wasm
                 .push(quote! {#field_name : <#field_type as ::ic_storage::IcStorage>::get()});
 
+            // All the fields are rolled back if a call traps. The value is copied with `Clone` if
+            // the type implements it, or with candid otherwise.
+            snapshot_fields.push(quote! {
+                snapshot.add_field(stringify!(#field_name), ::std::rc::Rc::clone(&self.#field_name), {
+                    #[allow(unused_imports)]
+                    use ::ic_canister::storage::testing::{CopyByCandid, CopyByClone, CopyUnsupported};
+                    (&&&::ic_canister::storage::testing::CopyProbe::<#field_type>::new()).copier()
+                });
+            });
+
+            // Only the fields that are stored over upgrades are required to be serializable, so
+            // only these are checked for the changes made by queries.
+            if is_stable && derive_upgrade {
+                snapshot_fields.push(quote! {
+                    snapshot.track_changes(stringify!(#field_name), ::std::rc::Rc::clone(&self.#field_name));
+                });
+            }
+
             if is_stable {
-                match stable_field {
-                    None => stable_field = Some((field_name, field_type)),
-                    Some(_) => panic!("only one state field can have the `stable_storage` flag"),
-                }
+                stable_fields.push((field_name, field_type));
             }
         }
 
@@ -104,8 +136,31 @@ pub fn derive_canister(input: TokenStream) -> TokenStream {
         quote! {}
     };
 
+    // The upgrade methods are not generated if there is nothing to store in the stable memory.
+    let upgrade_hooks = if derive_upgrade && (has_collections || !stable_fields.is_empty()) {
+        quote! {
+            #[cfg(not(target_arch = "wasm32"))]
+            fn __pre_upgrade_hook(&self) {
+                self.__pre_upgrade_inst();
+            }
+
+            #[cfg(not(target_arch = "wasm32"))]
+            fn __post_upgrade_hook(&self) {
+                self.__post_upgrade_inst();
+            }
+        }
+    } else {
+        quote! {}
+    };
+
+    let (inspect_hook, inspect_export) = if inspect_message {
+        expand_inspect_message(&name)
+    } else {
+        (quote! {}, quote! {})
+    };
+
     let upgrade_methods = if derive_upgrade {
-        expand_upgrade_methods(&name, stable_field)
+        expand_upgrade_methods(&name, stable_fields, has_collections)
     } else {
         quote! {}
     };
@@ -136,6 +191,7 @@ pub fn derive_canister(input: TokenStream) -> TokenStream {
             #[cfg(not(target_arch = "wasm32"))]
             fn init_instance() -> Self {
                 let principal = ::ic_cdk::export::Principal::from_slice(&__next_id());
+                ::ic_canister::testing::register_instance::<Self>(principal);
                 Self::from_principal(principal)
             }
 
@@ -161,10 +217,24 @@ pub fn derive_canister(input: TokenStream) -> TokenStream {
             fn principal(&self) -> Principal {
                 self.#principal_field
             }
+
+            #[cfg(not(target_arch = "wasm32"))]
+            fn __state_snapshot(&self) -> ::ic_canister::testing::StateSnapshot {
+                #[allow(unused_mut)]
+                let mut snapshot = ::ic_canister::testing::StateSnapshot::default();
+                #(#snapshot_fields)*
+                snapshot
+            }
+
+            #upgrade_hooks
+
+            #inspect_hook
         }
 
         #upgrade_methods
 
+        #inspect_export
+
     };
 
     TokenStream::from(expanded)
@@ -172,40 +242,29 @@ pub fn derive_canister(input: TokenStream) -> TokenStream {
 
 fn expand_upgrade_methods(
     struct_name: &proc_macro2::Ident,
-    stable_field: Option<(proc_macro2::Ident, &Type)>,
+    stable_fields: Vec<(proc_macro2::Ident, &Type)>,
+    has_collections: bool,
 ) -> proc_macro2::TokenStream {
-    let (name, field_type) = match stable_field {
-        None => return quote!(),
-        Some((name, field_type)) => (name, field_type),
+    let (pre_upgrade, post_upgrade) = match &stable_fields[..] {
+        _ if has_collections => expand_regions_upgrade(&stable_fields),
+        [] => return quote!(),
+        [(name, field_type)] => expand_single_field_upgrade(name, field_type),
+        _ => expand_sections_upgrade(&stable_fields),
     };
 
-    let (state_get, state_borrow) = (
-        quote! { let #name = ::std::rc::Rc::clone(&self. #name); },
-        quote! { &* #name.borrow(), },
-    );
-
-    let field_assignment = quote! { self. #name.replace(#name); };
-
     quote! {
         impl #struct_name {
             fn __pre_upgrade_inst(&self) {
                 use ::ic_storage::IcStorage;
 
-                #state_get
-
-                ::ic_storage::stable::write(#state_borrow).unwrap();
+                #pre_upgrade
             }
 
             fn __post_upgrade_inst(&self) {
                 use ::ic_storage::IcStorage;
                 use ::ic_storage::stable::Versioned;
 
-                let #name = match ::ic_storage::stable::read::<#field_type>() {
-                    Ok(val) => val,
-                    Err(e) => ::ic_cdk::trap(&format!("failed to upgrade: {}", e)),
-                };
-
-                #field_assignment
+                #post_upgrade
             }
 
             #[cfg(not(target_arch = "wasm32"))]
@@ -238,6 +297,149 @@ fn expand_upgrade_methods(
     }
 }
 
+/// A single stable field is stored as is, so the layout of the stable memory stays the same as
+/// for the canisters written before multiple stable fields were supported.
+fn expand_single_field_upgrade(
+    name: &proc_macro2::Ident,
+    field_type: &Type,
+) -> (proc_macro2::TokenStream, proc_macro2::TokenStream) {
+    let pre_upgrade = quote! {
+        let #name = ::std::rc::Rc::clone(&self. #name);
+        ::ic_storage::stable::write(&* #name.borrow()).unwrap();
+    };
+
+    let post_upgrade = quote! {
+        let #name = match ::ic_storage::stable::read::<#field_type>() {
+            Ok(val) => val,
+            Err(e) => ::ic_cdk::trap(&format!("failed to upgrade: {}", e)),
+        };
+
+        self. #name.replace(#name);
+    };
+
+    (pre_upgrade, post_upgrade)
+}
+
+/// Multiple stable fields are stored in separate sections tagged with the field names. A field
+/// that is not found in the stable memory (e.g. it was added in the new version of the canister)
+/// is initialized with the default value. If the stable memory contains a single value stored by
+/// the previous version of the canister, it is used for the first stable field.
+fn expand_sections_upgrade(
+    stable_fields: &[(proc_macro2::Ident, &Type)],
+) -> (proc_macro2::TokenStream, proc_macro2::TokenStream) {
+    let inserts = stable_fields.iter().map(|(name, _)| {
+        quote! {
+            sections.insert(stringify!(#name), &*self. #name.borrow()).unwrap();
+        }
+    });
+
+    let pre_upgrade = quote! {
+        let mut sections = ::ic_storage::stable::Sections::default();
+        #(#inserts)*
+        sections.write().unwrap();
+    };
+
+    let reads = stable_fields.iter().enumerate().map(|(index, (name, field_type))| {
+        let fallback = if index == 0 {
+            quote! { sections.get::<#field_type>(::ic_storage::stable::UNTAGGED) }
+        } else {
+            quote! { Ok(None) }
+        };
+
+        quote! {
+            let #name = match sections.get::<#field_type>(stringify!(#name)) {
+                Ok(None) => #fallback,
+                result => result,
+            };
+            let #name = match #name {
+                Ok(Some(val)) => val,
+                Ok(None) => <#field_type as ::std::default::Default>::default(),
+                Err(e) => ::ic_cdk::trap(&format!("failed to upgrade `{}`: {}", stringify!(#name), e)),
+            };
+
+            self. #name.replace(#name);
+        }
+    });
+
+    let post_upgrade = quote! {
+        let sections = match ::ic_storage::stable::Sections::read() {
+            Ok(val) => val,
+            Err(e) => ::ic_cdk::trap(&format!("failed to upgrade: {}", e)),
+        };
+
+        #(#reads)*
+    };
+
+    (pre_upgrade, post_upgrade)
+}
+
+/// Stable collections share the stable memory with the other state fields, so the fields are
+/// stored in separate regions named after the fields. If the stable memory contains the state
+/// stored by the previous version of the canister in another layout, it is restored and the memory
+/// is reformatted to contain regions.
+fn expand_regions_upgrade(
+    stable_fields: &[(proc_macro2::Ident, &Type)],
+) -> (proc_macro2::TokenStream, proc_macro2::TokenStream) {
+    let writes = stable_fields.iter().map(|(name, _)| {
+        quote! {
+            ::ic_storage::stable::regions::write(stringify!(#name), &*self. #name.borrow()).unwrap();
+        }
+    });
+
+    let pre_upgrade = quote! {
+        #(#writes)*
+    };
+
+    let reads = stable_fields.iter().enumerate().map(|(index, (name, field_type))| {
+        let fallback = if index == 0 {
+            quote! { sections.get::<#field_type>(::ic_storage::stable::UNTAGGED) }
+        } else {
+            quote! { Ok(None) }
+        };
+
+        quote! {
+            let #name = match &legacy {
+                Some(sections) => match sections.get::<#field_type>(stringify!(#name)) {
+                    Ok(None) => #fallback,
+                    result => result,
+                },
+                None => ::ic_storage::stable::regions::read::<#field_type>(stringify!(#name)),
+            };
+            let #name = match #name {
+                Ok(Some(val)) => val,
+                Ok(None) => <#field_type as ::std::default::Default>::default(),
+                Err(e) => ::ic_cdk::trap(&format!("failed to upgrade `{}`: {}", stringify!(#name), e)),
+            };
+
+            self. #name.replace(#name);
+        }
+    });
+
+    let post_upgrade = quote! {
+        #[allow(unused_variables)]
+        let legacy = match ::ic_storage::stable::regions::init() {
+            Ok(val) => val,
+            Err(e) => ::ic_cdk::trap(&format!("failed to upgrade: {}", e)),
+        };
+
+        #(#reads)*
+    };
+
+    (pre_upgrade, post_upgrade)
+}
+
+/// Stable collections are recognized by the name of the type.
+fn is_stable_collection(field_type: &Type) -> bool {
+    match field_type {
+        Type::Path(path) => matches!(
+            path.path.segments.last(),
+            Some(segment) if ["StableBTreeMap", "StableVec", "StableLog"]
+                .contains(&segment.ident.to_string().as_str())
+        ),
+        _ => false,
+    }
+}
+
 fn is_state_field_stable(field: &Field) -> bool {
     // Find the "state" field
     let meta = field
@@ -371,11 +573,46 @@ fn state_type_error(input_type: &Type) -> ! {
 }
 
 fn derive_upgrade_methods(input: &DeriveInput) -> bool {
-    !input.attrs.iter().any(|x| {
+    !has_struct_attr(input, "canister_no_upgrade_methods")
+}
+
+fn has_struct_attr(input: &DeriveInput, name: &str) -> bool {
+    input.attrs.iter().any(|x| {
         x.path
             .segments
             .last()
-            .map(|last| last.ident == "canister_no_upgrade_methods")
+            .map(|last| last.ident == name)
             .unwrap_or(false)
     })
 }
+
+/// The `canister_inspect_message` export calls the `InspectMessage` implementation of the canister,
+/// accepting the message if it returns `Ok` and rejecting it with the error message otherwise.
+fn expand_inspect_message(
+    struct_name: &proc_macro2::Ident,
+) -> (proc_macro2::TokenStream, proc_macro2::TokenStream) {
+    let inspect_hook = quote! {
+        #[cfg(not(target_arch = "wasm32"))]
+        fn __inspect_message(&self, method_name: &str, caller: ::ic_cdk::export::Principal) -> ::std::result::Result<(), String> {
+            ::ic_canister::InspectMessage::inspect_message(self, method_name, caller)
+        }
+    };
+
+    let export = quote! {
+        impl #struct_name {
+            #[cfg(all(target_arch = "wasm32", not(feature = "no_api")))]
+            #[export_name = "canister_inspect_message"]
+            fn __inspect_message() {
+                ::ic_cdk::setup();
+                let instance = Self::init_instance();
+                let method_name = ::ic_cdk::api::call::method_name();
+                match ::ic_canister::InspectMessage::inspect_message(&instance, &method_name, ::ic_cdk::caller()) {
+                    Ok(()) => ::ic_cdk::api::call::accept_message(),
+                    Err(message) => ::ic_cdk::trap(&message),
+                }
+            }
+        }
+    };
+
+    (inspect_hook, export)
+}
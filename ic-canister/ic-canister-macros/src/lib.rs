@@ -151,7 +151,10 @@ pub fn generate_exports(input: TokenStream) -> TokenStream {
 }
 
 /// Derives [Canister] trait for a struct.
-#[proc_macro_derive(Canister, attributes(id, state, canister_no_upgrade_methods))]
+#[proc_macro_derive(
+    Canister,
+    attributes(id, state, canister_no_upgrade_methods, canister_inspect_message)
+)]
 pub fn derive_canister(input: TokenStream) -> TokenStream {
     derive::derive_canister(input)
 }
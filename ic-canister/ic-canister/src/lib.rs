@@ -99,15 +99,39 @@
 //!
 //! ## Upgrading
 //!
-//! If there is only one `#[state]` field, `Canister` derive macro will generate `pre_upgrade` and
-//! `post_upgrade` methods automatically. These methods will serialize the state to the stable
-//! storage on `pre_upgrade` and then use `ic_storage::stable::Versioned` trait to upgrade the state
-//! in `post_upgrade`. This approach has some limitations:
-//!
-//! * The canister can contain only one `#[state]` field. If more then one state fields are needed,
-//!   but only one must be stored in the stable memory, the other fields can me marked with
-//!   `#[state(stable_store = false)]`.
-//! * The state structure must implement the `Versioned`, `CandidType` and `Deserialize` traits.
+//! `Canister` derive macro will generate `pre_upgrade` and `post_upgrade` methods automatically.
+//! These methods will serialize the state to the stable storage on `pre_upgrade` and then use
+//! `ic_storage::stable::Versioned` trait to upgrade the state in `post_upgrade`.
+//!
+//! If the canister has more than one `#[state]` field, each of them is stored in a separate
+//! section tagged with the field name (see `ic_storage::stable::Sections`). Fields that are not
+//! found in the stable memory are initialized with their default values. If the stable memory
+//! contains a single state stored by the previous version of the canister, it is restored into the
+//! first `#[state]` field. Fields that must not be stored in the stable memory can be marked with
+//! `#[state(stable_store = false)]`.
+//!
+//! `#[state]` fields can also contain stable collections from `ic_storage::collections`
+//! (e.g. `Rc<RefCell<StableLog<Event>>>`). These already live in the stable memory, so they are not
+//! serialized on upgrade. If a canister has such fields, its other `#[state]` fields are stored in
+//! stable memory regions named after the fields (see `ic_storage::stable::regions`).
+//!
+//! If the state cannot be restored, e.g. because a migration implemented with
+//! `Versioned::try_upgrade` fails, the generated `post_upgrade` traps with the error, naming the
+//! field and the versions the migration failed between. Trapping in `post_upgrade` makes the
+//! upgrade fail, so the canister keeps running the previous version of the code with its state.
+//!
+//! In the testing environment a whole upgrade cycle, including the `Versioned` migrations of the
+//! state, can be run with [testing::upgrade_instance]. It runs the generated `pre_upgrade` of the
+//! canister, resets its in-memory state, keeping the mocked stable memory, and runs the
+//! `post_upgrade` of the given (possibly new) canister type:
+//!
+//! ```ignore
+//! let canister = upgrade_instance::<CanisterV2>(canister_v1.principal());
+//! ```
+//!
+//! This approach has some limitations:
+//!
+//! * The state structures must implement the `Versioned`, `CandidType` and `Deserialize` traits.
 //! * No other data can be stored in the stable storage.
 //!
 //! If any of these conditions is not true, upgrade methods generation can be skipped by adding
@@ -115,6 +139,21 @@
 //! `#[ic_canister::pre_upgrade]` and `#[ic_canister::post_upgrade]` macros to mark the corresponding
 //! manual implementations if needed.
 //!
+//! States that are too large to be upgraded within a single `post_upgrade` call can be stored as a
+//! sequence of records and migrated in batches over the following calls with
+//! `ic_storage::migration`. Until the migration is completed, the `PreUpdate` implementation can
+//! reject the calls that need the whole state:
+//!
+//! ```ignore
+//! impl PreUpdate for MyCanister {
+//!     fn pre_update(&self, method_name: &str, _method_type: MethodType) {
+//!         if ic_storage::migration::is_migrating() && method_name != "migrate" {
+//!             ic_cdk::trap("state migration is in progress");
+//!         }
+//!     }
+//! }
+//! ```
+//!
 //! # API
 //!
 //! The API of the canister can be declared using `#[query]` and `#[update]` macros. To prevent
@@ -165,6 +204,34 @@
 //!
 //! The API methods must be instance methods (taking `self` by reference).
 //!
+//! ## Inspecting messages
+//!
+//! The [PreUpdate] implementation is executed as a part of every update call. To reject unwanted
+//! ingress messages before they are executed, mark the canister struct with the
+//! `#[canister_inspect_message]` attribute and implement the [InspectMessage] trait. The derive
+//! macro exports it as the `canister_inspect_message` method of the canister:
+//!
+//! ```ignore
+//! #[derive(Clone, Canister)]
+//! #[canister_inspect_message]
+//! struct MyCanister {
+//!     // ...
+//! }
+//!
+//! impl InspectMessage for MyCanister {
+//!     fn inspect_message(&self, method_name: &str, caller: Principal) -> Result<(), String> {
+//!         match method_name {
+//!             "add" if caller == Principal::anonymous() => Err("anonymous calls are not allowed".into()),
+//!             _ => Ok(()),
+//!         }
+//!     }
+//! }
+//! ```
+//!
+//! In the testing environment the update calls made with [canister_call] and [canister_notify]
+//! from the test itself (as opposed to the calls made by canisters) are inspected in the same way,
+//! and rejected with `RejectionCode::CanisterReject` if the inspection fails.
+//!
 //! # Traits as canisters
 //!
 //! When we want to enrich a canister with some generic structure, we can define a trait that the
@@ -467,7 +534,110 @@
 //! ```
 //!
 //! If you want to test a virtual call in case the call fails, [register_failing_virtual_responder]
-//! function can be used.
+//! or [register_rejecting_virtual_responder] functions can be used. A responder can also change its
+//! answer over time: the responder functions can mutate their state, [register_fallible_virtual_responder]
+//! allows to reject some of the calls with any rejection code, and [ResponseSequence] returns a
+//! scripted sequence of responses:
+//!
+//! ```ignore
+//! ResponseSequence::new()
+//!     .reject(RejectionCode::SysTransient, "the canister is busy")
+//!     .reply(10u64)
+//!     .register(principal, "remote_method");
+//! ```
+//!
+//! To respond to all the methods of a canister, e.g. to simulate a stopped canister, use
+//! [register_wildcard_virtual_responder].
+//!
+//! A responder registered with [register_async_virtual_responder] can itself call other canisters
+//! (real or virtual) before replying, so that flows spanning several canisters can be tested. The
+//! responders are executed in the context of the virtual canister, i.e. `ic::id()` returns its
+//! principal and `ic::caller()` returns the calling canister.
+//!
+//! All the virtual calls made in a test case are recorded, whether or not a responder is registered
+//! for them. The recorded calls can be inspected with [virtual_calls] and [virtual_calls_to], or
+//! checked with [assert_virtual_call_count] and [assert_virtual_called_with]:
+//!
+//! ```ignore
+//! second_canister.make_remote_call(principal, 10).await.unwrap();
+//!
+//! assert_virtual_call_count(principal, "remote_method", 1);
+//! assert_virtual_called_with(principal, "remote_method", (10u32,));
+//! ```
+//!
+//! [reset_virtual_calls] clears the recorded calls.
+//!
+//! ## Traps in inter-canister calls
+//!
+//! If a method called with [canister_call] traps (or panics) in the testing environment, the
+//! call returns `Err((RejectionCode::CanisterError, trap_message))` instead of failing the whole
+//! test. As the IC does for a trapped message, the `#[state]` fields of the called canister are
+//! rolled back to the values they had before the call. To be rolled back, the state types must
+//! implement `Clone`, or `CandidType` and `Deserialize`. The fields of other types are not rolled
+//! back, and a warning is printed the first time such a field is met. Stable collections are not
+//! rolled back either.
+//!
+//! In the IC an `async` method is executed as several messages split by the awaits on
+//! inter-canister calls, and the changes made by each of them are committed. Likewise, if an
+//! `async` method traps after a [canister_call] or [virtual_canister_call], only the changes made
+//! after the last call are rolled back.
+//!
+//! ## Cycles
+//!
+//! In the testing environment every canister has its own cycles balance, starting with
+//! [testing::DEFAULT_BALANCE]. The cycles attached to a [canister_call] or [virtual_canister_call]
+//! are moved from the balance of the caller to the called method, where they can be accepted with
+//! `ic_kit::ic::msg_cycles_accept`. The cycles that are not accepted are refunded to the caller
+//! when the call is finished, and if the called method traps, all the attached cycles are refunded.
+//!
+//! ```ignore
+//! use ic_canister::testing::{balance_of, set_balance, DEFAULT_BALANCE};
+//!
+//! set_balance(ic::id(), 10_000);
+//! canister_call!(factory.top_up(), u64, 4_000).await.unwrap();
+//!
+//! assert_eq!(balance_of(factory.principal()), DEFAULT_BALANCE + 4_000);
+//! assert_eq!(balance_of(ic::id()), 6_000);
+//! ```
+//!
+//! The balances are kept separate only when the current canister is switched by the calls and the
+//! [testing::Scheduler]: changing the id of the `MockContext` directly doesn't change its balance.
+//!
+//! ## Query calls
+//!
+//! In the IC the state changes made by a `#[query]` method are always discarded. By default, this is
+//! not enforced in the testing environment, but [testing::set_query_mode] can be used to either
+//! discard the changes made by queries called with [canister_call] ([testing::QueryMode::Discard]),
+//! or to fail the test if a query changes the state ([testing::QueryMode::Deny]).
+//!
+//! ```ignore
+//! use ic_canister::testing::{set_query_mode, QueryMode};
+//!
+//! set_query_mode(QueryMode::Deny);
+//! // Panics if `get_counter` changes the canister state.
+//! canister_call!(my_canister.get_counter(), u64).await.unwrap();
+//! ```
+//!
+//! ## Testing message interleaving
+//!
+//! In the IC, while a canister method awaits on an inter-canister call, the canister can execute
+//! other messages that may change its state. Such interleavings can be tested using the
+//! [testing::Scheduler]. It runs several messages concurrently, suspending them at every
+//! [canister_call] and [virtual_canister_call] and choosing the message to continue according to
+//! the given [testing::Interleaving] strategy.
+//!
+//! ```ignore
+//! use ic_canister::testing::{Interleaving, Scheduler};
+//!
+//! for seed in 0..100 {
+//!     let mut scheduler = Scheduler::new(Interleaving::Random(seed));
+//!     let first = scheduler.spawn(canister.withdraw(10));
+//!     let second = scheduler.spawn(canister.withdraw(10));
+//!     scheduler.run();
+//!
+//!     assert!(canister.balance() >= 0, "interleaving {:?} breaks the canister", scheduler.trace());
+//! }
+//! ```
 //!
 //! # Canister crates dependencies
 //!
@@ -505,10 +675,11 @@
 
 use ic_cdk::api::call::{CallResult, RejectionCode};
 use ic_cdk::export::candid::utils::ArgumentDecoder;
-use ic_cdk::export::candid::{CandidType, Deserialize};
+use ic_cdk::export::candid::{CandidType, Deserialize, IDLArgs};
 use ic_cdk::export::Principal;
 use std::cell::RefCell;
-use std::collections::HashMap;
+use std::collections::{HashMap, VecDeque};
+use std::fmt;
 use std::future::Future;
 use std::pin::Pin;
 use std::rc::Rc;
@@ -519,6 +690,8 @@ pub use ic_kit;
 
 pub mod idl;
 pub mod storage;
+#[cfg(not(target_arch = "wasm32"))]
+pub mod testing;
 
 pub use idl::*;
 
@@ -553,6 +726,19 @@ pub trait PreUpdate {
     fn pre_update(&self, _method_name: &str, _method_type: MethodType) {}
 }
 
+/// Inspection of the ingress messages before they are executed. The implementation is exported as
+/// `canister_inspect_message` by the `Canister` derive macro if the canister struct is marked with
+/// the `#[canister_inspect_message]` attribute.
+///
+/// Unlike [PreUpdate], the inspection is done by a single replica before the message is accepted,
+/// so rejecting a message here doesn't cost the canister the execution of the update. Note, that
+/// the IC inspects only the ingress update calls, and a malicious replica can skip the inspection,
+/// so the update methods must still check the access rights themselves.
+pub trait InspectMessage {
+    /// Returns an error to reject the message sent by `caller` to the method `method_name`.
+    fn inspect_message(&self, method_name: &str, caller: Principal) -> Result<(), String>;
+}
+
 /// Main trait for a testable canister. Do not implement this trait manually, use the derive macro.
 pub trait Canister: PreUpdate {
     /// Creates a new instance of the canister with the default state. Call this method to initialize
@@ -575,56 +761,148 @@ pub trait Canister: PreUpdate {
 
     /// Returns the principal of the canister.
     fn principal(&self) -> Principal;
+
+    /// Takes a snapshot of the canister state, that is used to roll back the state if a call to the
+    /// canister traps in the testing environment.
+    #[doc(hidden)]
+    #[cfg(not(target_arch = "wasm32"))]
+    fn __state_snapshot(&self) -> testing::StateSnapshot {
+        testing::StateSnapshot::default()
+    }
+
+    /// Runs the [InspectMessage] implementation of the canister, if it is enabled by the
+    /// `#[canister_inspect_message]` attribute. Used by the testing code of the API macros.
+    #[doc(hidden)]
+    #[cfg(not(target_arch = "wasm32"))]
+    fn __inspect_message(&self, _method_name: &str, _caller: Principal) -> Result<(), String> {
+        Ok(())
+    }
+
+    /// Runs the `pre_upgrade` method generated by the `Canister` derive macro, used by
+    /// [testing::upgrade_instance].
+    #[doc(hidden)]
+    #[cfg(not(target_arch = "wasm32"))]
+    fn __pre_upgrade_hook(&self) {}
+
+    /// Runs the `post_upgrade` method generated by the `Canister` derive macro, used by
+    /// [testing::upgrade_instance].
+    #[doc(hidden)]
+    #[cfg(not(target_arch = "wasm32"))]
+    fn __post_upgrade_hook(&self) {}
 }
 
 // Important: If you're renaming this type, don't forget to update
 // the `ic_canister_macros::api::get_args` as well.
 pub type AsyncReturn<'a, T> = Pin<Box<dyn Future<Output = T> + 'a>>;
 
-type ResponderFn = dyn Fn(Vec<u8>) -> CallResult<Vec<u8>>;
-type ResponderHashMap = HashMap<(Principal, String), Box<ResponderFn>>;
+type ResponderFn = dyn FnMut(&str, Vec<u8>) -> AsyncReturn<'static, CallResult<Vec<u8>>>;
+type Responder = Rc<RefCell<Box<ResponderFn>>>;
+
+/// The responders are registered for a principal-method pair, or for all the methods of a
+/// principal, if the method is `None`.
+type ResponderHashMap = HashMap<(Principal, Option<String>), Responder>;
 
 thread_local! {
     static __RESPONDERS: Rc<RefCell<ResponderHashMap>> = Rc::new(RefCell::new(HashMap::new()));
+    static __VIRTUAL_CALLS: RefCell<Vec<VirtualCall>> = RefCell::new(Vec::new());
 }
 
 fn _register_virtual_responder(
     principal: Principal,
-    method_name: &str,
-    responder: impl Fn(Vec<u8>) -> CallResult<Vec<u8>> + 'static,
+    method_name: Option<&str>,
+    mut responder: impl FnMut(&str, Vec<u8>) -> CallResult<Vec<u8>> + 'static,
+) {
+    _register_async_virtual_responder(principal, method_name, move |method_name, args| {
+        let result = responder(method_name, args);
+        Box::pin(async move { result })
+    });
+}
+
+fn _register_async_virtual_responder(
+    principal: Principal,
+    method_name: Option<&str>,
+    responder: impl FnMut(&str, Vec<u8>) -> AsyncReturn<'static, CallResult<Vec<u8>>> + 'static,
 ) {
     __RESPONDERS.with(|responders| {
-        responders
-            .borrow_mut()
-            .insert((principal, method_name.to_string()), Box::new(responder));
+        responders.borrow_mut().insert(
+            (principal, method_name.map(ToString::to_string)),
+            Rc::new(RefCell::new(Box::new(responder))),
+        );
     })
 }
 
 /// Invokes a virtual canister method. This function is supposed to be called through [virtual_canister_call] macro.
+///
+/// The responder is executed in the context of the virtual canister, so if it calls other
+/// canisters, the virtual canister is the caller of these calls.
 #[doc(hidden)]
 pub fn call_virtual_responder(
     principal: Principal,
     method_name: &str,
     args: Vec<u8>,
-) -> CallResult<Vec<u8>> {
-    __RESPONDERS.with(|responders| {
-        match responders
-            .borrow()
-            .get(&(principal, method_name.to_string()))
-        {
-            Some(responder) => responder(args),
-            None => Err((
-                RejectionCode::Unknown,
-                format!(
-                    "canister method {method_name} is not registered for principal {principal}, METHODS: {:?}",
-                    responders
-                        .borrow()
-                        .keys()
-                        .map(|(k, r)| (k.to_string(), r))
-                        .collect::<Vec<_>>()
-                ),
-            )),
-        }
+    cycles: u128,
+) -> AsyncReturn<'static, CallResult<Vec<u8>>> {
+    __VIRTUAL_CALLS.with(|calls| {
+        calls.borrow_mut().push(VirtualCall {
+            caller: ic_kit::ic::id(),
+            target: principal,
+            method: method_name.to_string(),
+            args: args.clone(),
+            cycles,
+        })
+    });
+
+    let responder = __RESPONDERS.with(|responders| {
+        let responders = responders.borrow();
+        responders
+            .get(&(principal, Some(method_name.to_string())))
+            .or_else(|| responders.get(&(principal, None)))
+            .cloned()
+            .ok_or_else(|| {
+                (
+                    RejectionCode::Unknown,
+                    format!(
+                        "canister method {method_name} is not registered for principal {principal}, METHODS: {:?}",
+                        responders
+                            .keys()
+                            .map(|(k, r)| (k.to_string(), r.as_deref().unwrap_or("*")))
+                            .collect::<Vec<_>>()
+                    ),
+                )
+            })
+    });
+
+    let responder = match responder {
+        Ok(responder) => responder,
+        Err(e) => return Box::pin(async move { Err(e) }),
+    };
+
+    let method_name = method_name.to_string();
+    Box::pin(async move {
+        // The context is switched to the virtual canister only when the call is started, and it is
+        // restored by the frame even if the call does not complete.
+        #[cfg(not(target_arch = "wasm32"))]
+        let frame = testing::CallFrame::enter(principal, cycles);
+
+        // The responders list is not borrowed while the responder is running, so the responder can
+        // register other responders. The responder itself is only borrowed until it returns the
+        // future, so an async responder can be called again while its previous call is in
+        // progress.
+        let response = {
+            let mut responder = responder.try_borrow_mut().unwrap_or_else(|_| {
+                panic!(
+                    "responder of method {method_name} of principal {principal} is called recursively"
+                )
+            });
+            responder(&method_name, args)
+        };
+
+        let result = response.await;
+
+        #[cfg(not(target_arch = "wasm32"))]
+        frame.exit();
+
+        result
     })
 }
 
@@ -634,29 +912,118 @@ pub fn call_virtual_responder(
 /// One function can be registered for every principal-method pair. If a `virtual_canister_call` is
 /// called in testing environment without a registered responder, an error will be returned. This can
 /// be used to test for not existing canisters.
-pub fn register_virtual_responder<F, T, U>(principal: Principal, method: &str, closure: F)
+///
+/// The function can change its state between the calls, e.g. to count them.
+pub fn register_virtual_responder<F, T, U>(principal: Principal, method: &str, mut closure: F)
 where
-    F: Fn(T) -> U + 'static,
+    F: FnMut(T) -> U + 'static,
     for<'a> T: CandidType + ArgumentDecoder<'a>,
     for<'b> U: CandidType + Deserialize<'b>,
 {
-    let inner_closure = move |args: Vec<u8>| {
+    register_fallible_virtual_responder(principal, method, move |args: T| Ok(closure(args)));
+}
+
+/// Same as [register_virtual_responder], but the function can reject the call by returning an
+/// error with any rejection code.
+///
+/// ```ignore
+/// let mut calls = 0;
+/// register_fallible_virtual_responder(principal, "transfer", move |(amount,): (u64,)| {
+///     calls += 1;
+///     if calls == 1 {
+///         Err((RejectionCode::SysTransient, "the canister is busy".into()))
+///     } else {
+///         Ok(amount)
+///     }
+/// });
+/// ```
+pub fn register_fallible_virtual_responder<F, T, U>(
+    principal: Principal,
+    method: &str,
+    mut closure: F,
+) where
+    F: FnMut(T) -> CallResult<U> + 'static,
+    for<'a> T: CandidType + ArgumentDecoder<'a>,
+    for<'b> U: CandidType + Deserialize<'b>,
+{
+    let inner_closure = move |_: &str, args: Vec<u8>| {
         let deserialized_args = ic_cdk::export::candid::decode_args::<T>(&args).map_err(|e| {
             (
                 RejectionCode::Unknown,
                 format!("Failed to decode args: {:?}", e),
             )
         })?;
-        let result = closure(deserialized_args);
-        ic_cdk::export::candid::encode_args((result,)).map_err(|e| {
-            (
-                RejectionCode::Unknown,
-                format!("failed to encode return value: {:?}", e),
-            )
-        })
+        let result = closure(deserialized_args)?;
+        encode_result(result)
     };
 
-    _register_virtual_responder(principal, method, inner_closure);
+    _register_virtual_responder(principal, Some(method), inner_closure);
+}
+
+/// Saves a function that will be called for all the methods of the given principal, that have no
+/// responders registered with the other functions.
+///
+/// The function gets the name of the called method and the candid-encoded arguments, and must
+/// return the candid-encoded result. Use [encode_args](ic_cdk::export::candid::encode_args) to
+/// encode the result, or return an error to reject the calls:
+///
+/// ```ignore
+/// register_wildcard_virtual_responder(principal, |method, _args| {
+///     Err((RejectionCode::DestinationInvalid, format!("method {method} is stopped")))
+/// });
+/// ```
+pub fn register_wildcard_virtual_responder<F>(principal: Principal, closure: F)
+where
+    F: FnMut(&str, Vec<u8>) -> CallResult<Vec<u8>> + 'static,
+{
+    _register_virtual_responder(principal, None, closure);
+}
+
+/// Saves an async function that will be called for the given method of the given principal, see
+/// [register_virtual_responder].
+///
+/// An async responder can make inter-canister calls before replying, so a chain of calls going
+/// through virtual and real canisters can be simulated. While the responder is running, the virtual
+/// canister is the current canister of the mock context, so it is the caller of the calls made by
+/// the responder:
+///
+/// ```ignore
+/// let token = TokenCanister::init_instance();
+/// register_async_virtual_responder(ledger, "transfer", move |(to, amount): (Principal, u64)| {
+///     let mut token = token.clone();
+///     async move { canister_call!(token.mint(to, amount), u64).await }
+/// });
+/// ```
+///
+/// The returned future can reject the call with any rejection code.
+pub fn register_async_virtual_responder<F, Fut, T, U>(
+    principal: Principal,
+    method: &str,
+    mut closure: F,
+) where
+    F: FnMut(T) -> Fut + 'static,
+    Fut: Future<Output = CallResult<U>> + 'static,
+    for<'a> T: CandidType + ArgumentDecoder<'a>,
+    for<'b> U: CandidType + Deserialize<'b>,
+{
+    let inner_closure =
+        move |_: &str, args: Vec<u8>| -> AsyncReturn<'static, CallResult<Vec<u8>>> {
+            let deserialized_args = match ic_cdk::export::candid::decode_args::<T>(&args) {
+                Ok(args) => args,
+                Err(e) => {
+                    let error = (
+                        RejectionCode::Unknown,
+                        format!("Failed to decode args: {:?}", e),
+                    );
+                    return Box::pin(async move { Err(error) });
+                }
+            };
+
+            let response = closure(deserialized_args);
+            Box::pin(async move { encode_result(response.await?) })
+        };
+
+    _register_async_virtual_responder(principal, Some(method), inner_closure);
 }
 
 /// Adds a responder function for a [virtual_canister_call] that will result in an error result with
@@ -666,7 +1033,209 @@ pub fn register_failing_virtual_responder(
     method: &str,
     error_message: String,
 ) {
-    _register_virtual_responder(principal, method, move |_| {
-        Err((RejectionCode::Unknown, error_message.clone()))
+    register_rejecting_virtual_responder(principal, method, RejectionCode::Unknown, error_message);
+}
+
+/// Adds a responder function for a [virtual_canister_call] that will result in an error result with
+/// the given rejection code and error message.
+pub fn register_rejecting_virtual_responder(
+    principal: Principal,
+    method: &str,
+    code: RejectionCode,
+    error_message: String,
+) {
+    _register_virtual_responder(principal, Some(method), move |_, _| {
+        Err((code, error_message.clone()))
+    });
+}
+
+fn encode_result<U: CandidType>(result: U) -> CallResult<Vec<u8>> {
+    ic_cdk::export::candid::encode_args((result,)).map_err(|e| {
+        (
+            RejectionCode::Unknown,
+            format!("failed to encode return value: {:?}", e),
+        )
+    })
+}
+
+/// A scripted sequence of responses of a virtual canister method. Each call to the method gets the
+/// next response of the sequence. The calls made after the sequence is over are rejected.
+///
+/// ```ignore
+/// ResponseSequence::new()
+///     .reject(RejectionCode::SysTransient, "the canister is busy")
+///     .reply(42u64)
+///     .reply(43u64)
+///     .register(principal, "get_value");
+/// ```
+#[derive(Default)]
+pub struct ResponseSequence {
+    responses: VecDeque<CallResult<Vec<u8>>>,
+}
+
+impl ResponseSequence {
+    /// Creates an empty sequence.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Adds a successful response with the given value.
+    ///
+    /// # Panics
+    ///
+    /// If the value cannot be serialized with candid.
+    pub fn reply<U: CandidType>(mut self, value: U) -> Self {
+        let response = encode_result(value).unwrap_or_else(|(_, e)| panic!("{e}"));
+        self.responses.push_back(Ok(response));
+        self
+    }
+
+    /// Adds a rejection with the given code and message.
+    pub fn reject(mut self, code: RejectionCode, message: impl Into<String>) -> Self {
+        self.responses.push_back(Err((code, message.into())));
+        self
+    }
+
+    /// Registers the sequence as the responder of the given method of the given principal.
+    pub fn register(self, principal: Principal, method: &str) {
+        let mut responses = self.responses;
+        _register_virtual_responder(principal, Some(method), move |method_name, _| {
+            responses.pop_front().unwrap_or_else(|| {
+                Err((
+                    RejectionCode::Unknown,
+                    format!("no more scripted responses for method {method_name} of principal {principal}"),
+                ))
+            })
+        });
+    }
+}
+
+/// An inter-canister call made with [virtual_canister_call] or [virtual_canister_notify], recorded
+/// in the testing environment.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct VirtualCall {
+    /// The canister that made the call, i.e. the id of the mock context when the call was made.
+    ///
+    /// When a canister method is invoked with `canister_call!`, the context is switched to the
+    /// called canister, so its calls are recorded with its principal. When a method is called
+    /// directly on the canister struct, the context is not switched, and the calls are recorded
+    /// with the id the test set in the `MockContext`.
+    pub caller: Principal,
+    /// The canister the call was made to.
+    pub target: Principal,
+    /// The name of the called method.
+    pub method: String,
+    /// Candid-encoded arguments of the call.
+    pub args: Vec<u8>,
+    /// Cycles attached to the call.
+    pub cycles: u128,
+}
+
+impl VirtualCall {
+    /// Decodes the arguments of the call as the given tuple type.
+    ///
+    /// # Panics
+    ///
+    /// If the arguments cannot be decoded as `T`.
+    pub fn decode_args<T>(&self) -> T
+    where
+        for<'a> T: ArgumentDecoder<'a>,
+    {
+        ic_cdk::export::candid::decode_args(&self.args).unwrap_or_else(|e| {
+            panic!(
+                "failed to decode arguments of the call to `{}` of canister {}: {e}",
+                self.method, self.target
+            )
+        })
+    }
+}
+
+impl fmt::Display for VirtualCall {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} -> {}.{}", self.caller, self.target, self.method)?;
+        match IDLArgs::from_bytes(&self.args) {
+            Ok(args) => write!(f, "{args}")?,
+            Err(_) => write!(f, "(<{} bytes>)", self.args.len())?,
+        }
+
+        if self.cycles > 0 {
+            write!(f, " with {} cycles", self.cycles)?;
+        }
+
+        Ok(())
+    }
+}
+
+/// Returns all the calls made with [virtual_canister_call] and [virtual_canister_notify] in the
+/// current thread (test case), in the order they were made. The calls are recorded whether or
+/// not a responder is registered for them.
+pub fn virtual_calls() -> Vec<VirtualCall> {
+    __VIRTUAL_CALLS.with(|calls| calls.borrow().clone())
+}
+
+/// Returns the recorded calls to the given method of the given canister, see [virtual_calls].
+pub fn virtual_calls_to(principal: Principal, method: &str) -> Vec<VirtualCall> {
+    __VIRTUAL_CALLS.with(|calls| {
+        calls
+            .borrow()
+            .iter()
+            .filter(|call| call.target == principal && call.method == method)
+            .cloned()
+            .collect()
+    })
+}
+
+/// Clears the list of the recorded virtual calls.
+pub fn reset_virtual_calls() {
+    __VIRTUAL_CALLS.with(|calls| calls.borrow_mut().clear());
+}
+
+/// Asserts that the given method of the given canister was called exactly `count` times.
+#[track_caller]
+pub fn assert_virtual_call_count(principal: Principal, method: &str, count: usize) {
+    let actual = virtual_calls_to(principal, method).len();
+    if actual != count {
+        panic!(
+            "expected {count} calls to `{method}` of canister {principal}, but {actual} were made. \
+             Recorded calls:\n{}",
+            recorded_calls()
+        );
+    }
+}
+
+/// Asserts that the given method of the given canister was called at least once with the given
+/// arguments.
+///
+/// ```ignore
+/// assert_virtual_called_with(ledger, "transfer", (alice(), 100u64));
+/// ```
+#[track_caller]
+pub fn assert_virtual_called_with<T>(principal: Principal, method: &str, args: T)
+where
+    for<'a> T: ArgumentDecoder<'a> + PartialEq + fmt::Debug,
+{
+    let calls = virtual_calls_to(principal, method);
+    let found = calls.iter().any(|call| {
+        ic_cdk::export::candid::decode_args::<T>(&call.args)
+            .map(|call_args| call_args == args)
+            .unwrap_or(false)
     });
+
+    if !found {
+        panic!(
+            "no call to `{method}` of canister {principal} was made with arguments {args:?}. \
+             Recorded calls:\n{}",
+            recorded_calls()
+        );
+    }
+}
+
+fn recorded_calls() -> String {
+    __VIRTUAL_CALLS.with(|calls| {
+        calls
+            .borrow()
+            .iter()
+            .map(|call| format!("  {call}\n"))
+            .collect()
+    })
 }
@@ -0,0 +1,859 @@
+//! Support code for running canisters in the testing environment (non-wasm targets).
+//!
+//! Most of the items in this module are used by the code generated by the `ic-canister` macros
+//! and should not be used directly.
+
+use std::any::{type_name, Any};
+use std::cell::{Cell, RefCell};
+use std::collections::{HashMap, HashSet};
+use std::future::Future;
+use std::panic::{self, AssertUnwindSafe};
+use std::pin::Pin;
+use std::rc::Rc;
+use std::sync::Arc;
+use std::task::{Context, Poll, Wake, Waker};
+
+use ic_cdk::api::call::{CallResult, RejectionCode};
+use ic_cdk::export::candid::{encode_one, CandidType};
+use ic_cdk::export::Principal;
+use ic_kit::{ic, inject};
+use ic_storage::borrow;
+
+use crate::Canister;
+
+/// Function that sets a state field to a previously copied value.
+type Restore = Box<dyn Fn()>;
+
+struct SnapshotField {
+    /// Copies the current value of the field and returns the function that restores it, or `None`
+    /// if the field is mutably borrowed at the moment.
+    copy: Box<dyn Fn() -> Option<Restore>>,
+    restore: Option<Restore>,
+}
+
+struct TrackedField {
+    name: &'static str,
+    bytes: Option<Vec<u8>>,
+    /// Returns the candid encoding of the field, or `None` if it cannot be read at the moment.
+    encode: Box<dyn Fn() -> Option<Vec<u8>>>,
+}
+
+thread_local! {
+    // Types of the state fields, that were reported as not rolled back on traps.
+    static NOT_ROLLED_BACK: RefCell<HashSet<&'static str>> = RefCell::new(HashSet::new());
+}
+
+/// A copy of the canister `#[state]` fields taken before a canister method is executed.
+///
+/// If the method traps, the state is rolled back to the snapshot, the same way the IC discards
+/// all the changes made by a trapped message.
+///
+/// The snapshot is created empty, and the values of the fields are copied by
+/// [`StateSnapshot::refresh`], so a snapshot is only taken when the method is executed.
+#[doc(hidden)]
+#[derive(Default)]
+pub struct StateSnapshot {
+    fields: Vec<SnapshotField>,
+    tracked: Vec<TrackedField>,
+}
+
+impl StateSnapshot {
+    /// Adds a state field to the snapshot. The value is copied with the `copier` selected by
+    /// `ic_storage::testing::CopyProbe`.
+    ///
+    /// If the field type can be neither cloned nor serialized with candid (`copier` is `None`),
+    /// the field is not rolled back, and a warning is printed once for the type.
+    pub fn add_field<T: 'static>(
+        &mut self,
+        name: &'static str,
+        state: Rc<RefCell<T>>,
+        copier: Option<fn(&T) -> T>,
+    ) {
+        let copier = match copier {
+            Some(copier) => copier,
+            None => {
+                let type_name = type_name::<T>();
+                if NOT_ROLLED_BACK.with(|types| types.borrow_mut().insert(type_name)) {
+                    eprintln!(
+                        "warning: state field `{name}` is not rolled back if the canister traps: \
+                         `{type_name}` must implement `Clone`, or `CandidType` and `Deserialize`"
+                    );
+                }
+
+                return;
+            }
+        };
+
+        let copy = move || {
+            let saved = copier(&*state.try_borrow().ok()?);
+            let state = state.clone();
+            Some(Box::new(move || {
+                state.replace(copier(&saved));
+            }) as Restore)
+        };
+
+        self.fields.push(SnapshotField {
+            copy: Box::new(copy),
+            restore: None,
+        });
+    }
+
+    /// Adds a state field, which changes are reported by [`StateSnapshot::changed_fields`]. The
+    /// changes are detected by comparing the candid encoding of the field.
+    pub fn track_changes<T>(&mut self, name: &'static str, state: Rc<RefCell<T>>)
+    where
+        T: CandidType + 'static,
+    {
+        let encode = move || encode_one(&*state.try_borrow().ok()?).ok();
+
+        self.tracked.push(TrackedField {
+            name,
+            bytes: None,
+            encode: Box::new(encode),
+        });
+    }
+
+    /// Copies the current values of the state fields into the snapshot, replacing the values
+    /// stored before.
+    ///
+    /// A field that is mutably borrowed at the moment keeps its previous value in the snapshot, or
+    /// is not rolled back if it has no value yet.
+    pub fn refresh(&mut self) {
+        for field in &mut self.fields {
+            if let Some(restore) = (field.copy)() {
+                field.restore = Some(restore);
+            }
+        }
+
+        for field in &mut self.tracked {
+            if let Some(bytes) = (field.encode)() {
+                field.bytes = Some(bytes);
+            }
+        }
+    }
+
+    /// Rolls the state fields back to the values stored in the snapshot.
+    pub fn restore(&self) {
+        for field in &self.fields {
+            if let Some(restore) = &field.restore {
+                restore();
+            }
+        }
+    }
+
+    /// Returns the names of the tracked state fields, which current values differ from the
+    /// snapshot.
+    pub fn changed_fields(&self) -> Vec<&'static str> {
+        self.tracked
+            .iter()
+            .filter(|field| field.bytes.is_some() && (field.encode)() != field.bytes)
+            .map(|field| field.name)
+            .collect()
+    }
+}
+
+thread_local! {
+    // Snapshots of the canister methods, which are being polled by `CatchTrap`. The last one
+    // belongs to the innermost method.
+    static SEGMENTS: RefCell<Vec<StateSnapshot>> = RefCell::new(Vec::new());
+}
+
+/// Commits the changes made by the message being executed so far, as the IC does when a message
+/// awaits on an inter-canister call: a trap after this point doesn't roll them back.
+fn commit_segment() {
+    SEGMENTS.with(|segments| {
+        if let Some(snapshot) = segments.borrow_mut().last_mut() {
+            snapshot.refresh();
+        }
+    });
+}
+
+/// How the changes to the canister state made by `#[query]` methods are treated when the query is
+/// called with `canister_call!` in the testing environment.
+///
+/// In the IC all the state changes made by a query are discarded after the query is executed.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum QueryMode {
+    /// The changes made by queries persist. This is the default mode.
+    Persist,
+
+    /// The changes made by queries are discarded, as it would be in the IC.
+    Discard,
+
+    /// If a query changes the state, the test fails. Use this mode to verify, that all the methods
+    /// that change the state are marked as `#[update]`.
+    Deny,
+}
+
+thread_local! {
+    static QUERY_MODE: Cell<QueryMode> = Cell::new(QueryMode::Persist);
+}
+
+/// Sets the [`QueryMode`] for the current thread (test case).
+///
+/// In [`QueryMode::Deny`] mode only the `#[state]` fields, that are preserved over upgrades, are
+/// checked, as the changes are detected by comparing their candid encoding.
+pub fn set_query_mode(mode: QueryMode) {
+    QUERY_MODE.with(|query_mode| query_mode.set(mode));
+}
+
+/// Returns the [`QueryMode`] of the current thread (test case).
+pub fn query_mode() -> QueryMode {
+    QUERY_MODE.with(|query_mode| query_mode.get())
+}
+
+/// Panic payload of a query, that changed the state in [`QueryMode::Deny`] mode. Unlike other
+/// panics, this one is not converted into a canister trap, so it fails the test.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct QueryDenied(pub String);
+
+impl std::fmt::Display for QueryDenied {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+/// Applies the current [`QueryMode`] after a query method is executed.
+#[doc(hidden)]
+pub fn finish_query(method_name: &str, snapshot: &StateSnapshot) {
+    match query_mode() {
+        QueryMode::Persist => {}
+        QueryMode::Discard => snapshot.restore(),
+        QueryMode::Deny => {
+            let changed = snapshot.changed_fields();
+            if !changed.is_empty() {
+                let message =
+                    format!("query method `{method_name}` changed the state fields {changed:?}");
+                // The panic hook doesn't print the message of a payload that is not a string.
+                eprintln!("{message}");
+                panic::panic_any(QueryDenied(message));
+            }
+        }
+    }
+}
+
+/// Runs the given function, returning the trap message as an error if it traps.
+///
+/// In the testing environment both `ic_cdk::trap` and `ic_kit::ic::trap` panic, so a trap is
+/// caught as a panic.
+#[doc(hidden)]
+pub fn catch_trap<R>(f: impl FnOnce() -> R) -> Result<R, String> {
+    panic::catch_unwind(AssertUnwindSafe(f)).map_err(|payload| {
+        if payload.is::<QueryDenied>() {
+            panic::resume_unwind(payload);
+        }
+
+        trap_message(payload)
+    })
+}
+
+fn trap_message(payload: Box<dyn Any + Send>) -> String {
+    if let Some(message) = payload.downcast_ref::<&str>() {
+        message.to_string()
+    } else if let Some(message) = payload.downcast_ref::<String>() {
+        message.clone()
+    } else {
+        "canister trapped".to_string()
+    }
+}
+
+/// Future that resolves to an error with the trap message if the inner future traps while
+/// being polled.
+///
+/// As in the IC, the changes made by the method before an await on an inter-canister call are
+/// committed: if the method traps, the `#[state]` fields of the canister are rolled back only to
+/// the values they had at the last await point, or when the method was last continued.
+///
+/// The snapshot is taken when the future is polled for the first time, as the body of an async
+/// method doesn't run before that.
+#[doc(hidden)]
+pub struct CatchTrap<'a, F> {
+    inner: Pin<Box<F>>,
+    snapshot: &'a mut StateSnapshot,
+}
+
+impl<'a, F: Future> CatchTrap<'a, F> {
+    pub fn new(inner: F, snapshot: &'a mut StateSnapshot) -> Self {
+        Self {
+            inner: Box::pin(inner),
+            snapshot,
+        }
+    }
+}
+
+impl<F: Future> Future for CatchTrap<'_, F> {
+    type Output = Result<F::Output, String>;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        // The changes made by other messages while this one was suspended are committed.
+        let mut snapshot = std::mem::take(&mut *self.snapshot);
+        snapshot.refresh();
+        SEGMENTS.with(|segments| segments.borrow_mut().push(snapshot));
+
+        let inner = self.inner.as_mut();
+        let result = catch_trap(move || inner.poll(cx));
+
+        *self.snapshot = SEGMENTS
+            .with(|segments| segments.borrow_mut().pop())
+            .expect("snapshot of the method is on the stack");
+
+        match result {
+            Ok(Poll::Ready(value)) => Poll::Ready(Ok(value)),
+            Ok(Poll::Pending) => Poll::Pending,
+            Err(message) => {
+                self.snapshot.restore();
+                Poll::Ready(Err(message))
+            }
+        }
+    }
+}
+
+/// Execution context of the caller of an inter-canister call.
+///
+/// When a call is made, the mock context is switched to the callee (its `id` is set to the callee
+/// principal and its `caller` is set to the calling canister). When the call is finished, the
+/// frame is used to return to the context of the caller. If the frame is dropped without being
+/// finished, e.g. because the callee panicked or the call future was dropped, the context of the
+/// caller is restored as well.
+///
+/// The cycles attached to the call are moved from the balance of the caller to the message of the
+/// callee. The cycles not accepted by the callee are refunded to the caller when the call is
+/// finished.
+#[doc(hidden)]
+pub struct CallFrame {
+    id: Principal,
+    caller: Principal,
+    msg_cycles: u64,
+    cycles: u64,
+    /// Call depth of the caller.
+    depth: usize,
+    restored: bool,
+}
+
+impl CallFrame {
+    /// Switches the mock context to the given callee, attaching the given amount of cycles to the
+    /// call.
+    ///
+    /// # Panics
+    ///
+    /// If the caller doesn't have enough cycles.
+    pub fn enter(callee: Principal, cycles: u128) -> Self {
+        let id = ic::id();
+        let balance = ic::balance();
+        let cycles = match u64::try_from(cycles) {
+            Ok(cycles) if cycles <= balance => cycles,
+            _ => panic!("canister {id} cannot attach {cycles} cycles to a call, its balance is {balance} cycles"),
+        };
+
+        inject::get_context().update_balance(balance - cycles);
+        let frame = Self {
+            id,
+            caller: ic::caller(),
+            msg_cycles: ic::msg_cycles_available(),
+            cycles,
+            depth: CALL_DEPTH.with(|depth| depth.replace(depth.get() + 1)),
+            restored: false,
+        };
+
+        switch_canister(callee);
+        inject::get_context().update_caller(frame.id);
+        inject::get_context().update_msg_cycles(cycles);
+        frame
+    }
+
+    /// Restores the mock context of the caller, refunding the cycles not accepted by the callee.
+    pub fn exit(mut self) {
+        let refund = ic::msg_cycles_available();
+        self.restore(refund);
+    }
+
+    /// Restores the mock context of the caller after the call is finished with the given result.
+    /// If the callee trapped, the changes made by it are discarded, so the cycles it accepted in
+    /// this call are taken back from its balance and refunded together with the cycles it didn't
+    /// accept.
+    pub fn finish<T>(mut self, result: &CallResult<T>) {
+        match result {
+            Err((RejectionCode::CanisterError, _)) => {
+                let available = ic::msg_cycles_available();
+                let accepted = self.cycles.saturating_sub(available);
+                let balance = ic::balance().saturating_sub(accepted);
+                inject::get_context().update_balance(balance);
+                self.restore(available + accepted);
+            }
+            _ => self.exit(),
+        }
+    }
+
+    fn restore(&mut self, refund: u64) {
+        self.restored = true;
+        CALL_DEPTH.with(|depth| depth.set(self.depth));
+        inject::get_context().update_msg_cycles(self.msg_cycles);
+        switch_canister(self.id);
+        inject::get_context().update_balance(ic::balance() + refund);
+        inject::get_context().update_caller(self.caller);
+    }
+}
+
+impl Drop for CallFrame {
+    fn drop(&mut self) {
+        if !self.restored {
+            let refund = ic::msg_cycles_available();
+            self.restore(refund);
+        }
+    }
+}
+
+thread_local! {
+    // Number of the nested calls being executed, i.e. of the active call frames. The calls made
+    // at depth 0 are ingress messages sent by the test, and the others are inter-canister calls.
+    static CALL_DEPTH: Cell<usize> = Cell::new(0);
+}
+
+/// Runs the `InspectMessage` implementation of the canister before an update method is called
+/// with `canister_call!` or `canister_notify!`. The inter-canister calls are not inspected, as it
+/// is done by the IC only for ingress messages.
+///
+/// Must be called right after the [`CallFrame`] of the call is entered. A trap in the inspection
+/// rejects the message.
+#[doc(hidden)]
+pub fn inspect_message<C: Canister + ?Sized>(
+    canister: &C,
+    method_name: &str,
+) -> Result<(), String> {
+    // Only the frame of this call is active for an ingress message. The methods called directly,
+    // without a frame, are not inspected either.
+    if CALL_DEPTH.with(|depth| depth.get()) != 1 {
+        return Ok(());
+    }
+
+    catch_trap(|| canister.__inspect_message(method_name, ic::caller())).and_then(|result| result)
+}
+
+/// Initial cycles balance of the canisters in the testing environment. This is the same as the
+/// default balance of the `MockContext`.
+pub const DEFAULT_BALANCE: u64 = 100_000_000_000_000;
+
+thread_local! {
+    // Balances of all the canisters except the current one, which balance is stored in the mock
+    // context.
+    static BALANCES: RefCell<HashMap<Principal, u64>> = RefCell::new(HashMap::default());
+}
+
+/// Returns the cycles balance of the given canister.
+///
+/// Every canister starts with the [`DEFAULT_BALANCE`]. The balance of the current canister of the
+/// mock context can also be set with `MockContext::with_balance`.
+pub fn balance_of(principal: Principal) -> u64 {
+    if principal == ic::id() {
+        ic::balance()
+    } else {
+        BALANCES.with(|balances| {
+            balances
+                .borrow()
+                .get(&principal)
+                .copied()
+                .unwrap_or(DEFAULT_BALANCE)
+        })
+    }
+}
+
+/// Sets the cycles balance of the given canister.
+pub fn set_balance(principal: Principal, balance: u64) {
+    if principal == ic::id() {
+        inject::get_context().update_balance(balance);
+    } else {
+        BALANCES.with(|balances| balances.borrow_mut().insert(principal, balance));
+    }
+}
+
+/// Sets the current canister of the mock context, keeping the balance of each canister separate.
+fn switch_canister(id: Principal) {
+    let current = ic::id();
+    if current == id {
+        return;
+    }
+
+    let balance = balance_of(id);
+    BALANCES.with(|balances| balances.borrow_mut().insert(current, ic::balance()));
+    inject::get_context().update_id(id);
+    inject::get_context().update_balance(balance);
+}
+
+type PreUpgradeFn = fn(Principal);
+
+thread_local! {
+    static INSTANCES: RefCell<HashMap<Principal, PreUpgradeFn>> = RefCell::new(HashMap::default());
+}
+
+/// Remembers the type of the canister created with `Canister::init_instance`, so that its
+/// `pre_upgrade` method can be run by [`upgrade_instance`].
+#[doc(hidden)]
+pub fn register_instance<C: Canister>(principal: Principal) {
+    INSTANCES.with(|instances| {
+        instances.borrow_mut().insert(principal, pre_upgrade::<C>);
+    });
+}
+
+fn pre_upgrade<C: Canister>(principal: Principal) {
+    C::from_principal(principal).__pre_upgrade_hook();
+}
+
+/// Simulates the upgrade of the canister with the given principal to the canister type `C`, and
+/// returns the upgraded canister instance.
+///
+/// As the IC does on upgrade:
+/// 1. the `pre_upgrade` method of the current canister type is run;
+/// 2. all the in-memory `IcStorage` states of the canister are reset to their default values (see
+///    `ic_storage::testing::reset_storage`), while the mocked stable memory is kept;
+/// 3. the `post_upgrade` method of the canister type `C` is run.
+///
+/// Only the upgrade methods generated by the `Canister` derive macro are run. `C` can be the same
+/// type as the current one, or a new version of the canister with `Versioned` state types:
+///
+/// ```ignore
+/// use ic_canister::testing::upgrade_instance;
+///
+/// let canister = CanisterV1::init_instance();
+/// canister_call!(canister.add_user(alice()), ()).await.unwrap();
+///
+/// let canister = upgrade_instance::<CanisterV2>(canister.principal());
+/// assert_eq!(canister_call!(canister.users_count(), u64).await.unwrap(), 1);
+/// ```
+///
+/// The mocked stable memory is shared by all the canisters of a test case, so only one canister
+/// storing its state in the stable memory can be upgraded in a test case.
+///
+/// # Panics
+///
+/// If the canister was not created with `Canister::init_instance`, or if one of the upgrade
+/// methods traps.
+pub fn upgrade_instance<C: Canister>(principal: Principal) -> C {
+    let pre_upgrade = INSTANCES
+        .with(|instances| instances.borrow().get(&principal).copied())
+        .unwrap_or_else(|| panic!("canister {principal} was not created with `init_instance`"));
+
+    // The context is restored even if an upgrade method traps.
+    let _context = ContextGuard::enter(principal);
+
+    pre_upgrade(principal);
+    ic_storage::testing::reset_storage(principal);
+
+    register_instance::<C>(principal);
+    let instance = C::from_principal(principal);
+    instance.__post_upgrade_hook();
+
+    instance
+}
+
+/// Switches the mock context to another canister, and back to the current one when dropped.
+struct ContextGuard {
+    id: Principal,
+}
+
+impl ContextGuard {
+    fn enter(principal: Principal) -> Self {
+        let id = ic::id();
+        switch_canister(principal);
+        Self { id }
+    }
+}
+
+impl Drop for ContextGuard {
+    fn drop(&mut self) {
+        switch_canister(self.id);
+    }
+}
+
+thread_local! {
+    static SCHEDULER_ACTIVE: Cell<bool> = Cell::new(false);
+    static MESSAGE_YIELDED: Cell<bool> = Cell::new(false);
+}
+
+/// Returns a future, that gives the [`Scheduler`] a chance to execute other messages.
+///
+/// In the IC other messages can be executed by a canister while it awaits on an inter-canister
+/// call. To simulate this, `canister_call!` and `virtual_canister_call!` macros await on this
+/// future right before the call is sent, and right after the response is received. If the scheduler
+/// is not running, the returned future is ready immediately.
+///
+/// The state changes made by the calling method before the await point are committed, so they are
+/// not rolled back if the method traps later (see [`CatchTrap`]).
+#[doc(hidden)]
+pub fn await_point() -> AwaitPoint {
+    AwaitPoint { yielded: false }
+}
+
+#[doc(hidden)]
+pub struct AwaitPoint {
+    yielded: bool,
+}
+
+impl Future for AwaitPoint {
+    type Output = ();
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<()> {
+        commit_segment();
+
+        if self.yielded || !SCHEDULER_ACTIVE.with(|active| active.get()) {
+            return Poll::Ready(());
+        }
+
+        self.yielded = true;
+        MESSAGE_YIELDED.with(|yielded| yielded.set(true));
+        cx.waker().wake_by_ref();
+        Poll::Pending
+    }
+}
+
+/// Order in which the [`Scheduler`] continues the messages suspended at inter-canister calls.
+#[derive(Debug, Clone)]
+pub enum Interleaving {
+    /// Every message runs till completion before the next one is started. This is the same as
+    /// awaiting the messages one after another.
+    Sequential,
+
+    /// The messages take turns at every await point.
+    RoundRobin,
+
+    /// At every await point the message to continue is chosen pseudo-randomly. The same seed
+    /// always produces the same interleaving.
+    Random(u64),
+
+    /// At every step the message with the given index (in the order the messages were spawned) is
+    /// continued. If the script is over, or the scripted message is already finished, the first
+    /// unfinished message is continued.
+    Scripted(Vec<usize>),
+}
+
+/// Result of a message executed by the [`Scheduler`].
+pub struct MessageHandle<T> {
+    result: Rc<RefCell<Option<T>>>,
+}
+
+impl<T> MessageHandle<T> {
+    /// Returns `true` if the message was executed till completion.
+    pub fn is_finished(&self) -> bool {
+        self.result.borrow().is_some()
+    }
+
+    /// Takes the result of the message.
+    ///
+    /// # Panics
+    ///
+    /// If the message is not finished yet, or the result was already taken.
+    pub fn result(&self) -> T {
+        self.result
+            .borrow_mut()
+            .take()
+            .expect("message is not finished or its result was already taken")
+    }
+}
+
+struct Message<'a> {
+    future: Option<Pin<Box<dyn Future<Output = ()> + 'a>>>,
+    id: Principal,
+    caller: Principal,
+    msg_cycles: u64,
+    depth: usize,
+    /// The canister method the message is executing, for the diagnostics of conflicting borrows.
+    method: Option<&'static str>,
+}
+
+/// Deterministic executor of canister messages for tests.
+///
+/// In the IC an update method that makes an inter-canister call is split into several messages,
+/// and the canister can execute other messages while waiting for the response. Simply awaiting on
+/// the canister methods in tests never produces such interleavings, so reentrancy bugs stay hidden.
+///
+/// The scheduler executes a set of messages concurrently, suspending them at every
+/// `canister_call!` and `virtual_canister_call!` boundary and choosing which one to continue
+/// according to the given [`Interleaving`].
+///
+/// ```ignore
+/// use ic_canister::testing::{Interleaving, Scheduler};
+///
+/// let mut scheduler = Scheduler::new(Interleaving::Random(42));
+/// let first = scheduler.spawn(canister.transfer(alice(), 10));
+/// let second = scheduler.spawn(canister.transfer(bob(), 10));
+/// scheduler.run();
+///
+/// assert!(first.result().is_ok() || second.result().is_ok());
+/// ```
+///
+/// Every message starts in the mock context (`id`, `caller` and the cycles attached to the message)
+/// that was current when it was spawned, and the context of each message is preserved when the
+/// execution switches between them.
+pub struct Scheduler<'a> {
+    interleaving: Interleaving,
+    messages: Vec<Message<'a>>,
+    trace: Vec<usize>,
+    random_state: u64,
+}
+
+impl<'a> Scheduler<'a> {
+    /// Creates a new scheduler with the given interleaving strategy.
+    pub fn new(interleaving: Interleaving) -> Self {
+        let random_state = match interleaving {
+            Interleaving::Random(seed) => seed,
+            _ => 0,
+        };
+
+        Self {
+            interleaving,
+            messages: vec![],
+            trace: vec![],
+            random_state,
+        }
+    }
+
+    /// Adds a message to be executed by the scheduler. The message is not started until
+    /// [`Scheduler::run`] is called.
+    pub fn spawn<F>(&mut self, message: F) -> MessageHandle<F::Output>
+    where
+        F: Future + 'a,
+        F::Output: 'a,
+    {
+        let result = Rc::new(RefCell::new(None));
+        let message_result = result.clone();
+        let future = async move {
+            let value = message.await;
+            message_result.replace(Some(value));
+        };
+
+        self.messages.push(Message {
+            future: Some(Box::pin(future)),
+            id: ic::id(),
+            caller: ic::caller(),
+            msg_cycles: ic::msg_cycles_available(),
+            depth: CALL_DEPTH.with(|depth| depth.get()),
+            method: borrow::current_method(),
+        });
+
+        MessageHandle { result }
+    }
+
+    /// Runs all the spawned messages till completion.
+    ///
+    /// # Panics
+    ///
+    /// If a message is waiting on something other than an inter-canister call (e.g. a timer of an
+    /// async runtime), as the scheduler has no way to know when it can be continued.
+    pub fn run(&mut self) {
+        let _guard = ActiveGuard::activate();
+        let waker = Waker::from(Arc::new(NoopWaker));
+        let mut cx = Context::from_waker(&waker);
+        let (id, caller) = (ic::id(), ic::caller());
+        let msg_cycles = ic::msg_cycles_available();
+        let depth = CALL_DEPTH.with(|depth| depth.get());
+        let method = borrow::current_method();
+
+        loop {
+            let pending = self
+                .messages
+                .iter()
+                .enumerate()
+                .filter(|(_, message)| message.future.is_some())
+                .map(|(index, _)| index)
+                .collect::<Vec<_>>();
+
+            if pending.is_empty() {
+                break;
+            }
+
+            let index = self.next_message(&pending);
+            self.trace.push(index);
+
+            let message = &mut self.messages[index];
+            switch_canister(message.id);
+            inject::get_context().update_caller(message.caller);
+            inject::get_context().update_msg_cycles(message.msg_cycles);
+            CALL_DEPTH.with(|depth| depth.set(message.depth));
+            borrow::set_current_method(message.method);
+            MESSAGE_YIELDED.with(|yielded| yielded.set(false));
+
+            let future = message.future.as_mut().expect("message is pending");
+            let is_ready = future.as_mut().poll(&mut cx).is_ready();
+
+            message.id = ic::id();
+            message.caller = ic::caller();
+            message.msg_cycles = ic::msg_cycles_available();
+            message.depth = CALL_DEPTH.with(|depth| depth.get());
+            message.method = borrow::current_method();
+
+            if is_ready {
+                message.future = None;
+            } else if !MESSAGE_YIELDED.with(|yielded| yielded.get()) {
+                panic!("message {index} is waiting on an event that is not controlled by the scheduler");
+            }
+        }
+
+        switch_canister(id);
+        inject::get_context().update_caller(caller);
+        inject::get_context().update_msg_cycles(msg_cycles);
+        CALL_DEPTH.with(|current| current.set(depth));
+        borrow::set_current_method(method);
+    }
+
+    /// Returns the indices of the messages in the order they were continued by the scheduler.
+    ///
+    /// This can be used to reproduce a failing interleaving with [`Interleaving::Scripted`].
+    pub fn trace(&self) -> &[usize] {
+        &self.trace
+    }
+
+    fn next_message(&mut self, pending: &[usize]) -> usize {
+        match &self.interleaving {
+            Interleaving::Sequential => pending[0],
+            Interleaving::RoundRobin => {
+                let last = self.trace.last().copied();
+                pending
+                    .iter()
+                    .copied()
+                    .find(|index| Some(*index) > last)
+                    .unwrap_or(pending[0])
+            }
+            Interleaving::Random(_) => {
+                let random = next_random(&mut self.random_state);
+                pending[(random % pending.len() as u64) as usize]
+            }
+            Interleaving::Scripted(script) => script
+                .get(self.trace.len())
+                .copied()
+                .filter(|index| pending.contains(index))
+                .unwrap_or(pending[0]),
+        }
+    }
+}
+
+// SplitMix64 generator. We don't need a good random generator here, but we need it to produce the
+// same sequence for the same seed on every platform.
+fn next_random(state: &mut u64) -> u64 {
+    *state = state.wrapping_add(0x9E37_79B9_7F4A_7C15);
+    let mut z = *state;
+    z = (z ^ (z >> 30)).wrapping_mul(0xBF58_476D_1CE4_E5B9);
+    z = (z ^ (z >> 27)).wrapping_mul(0x94D0_49BB_1331_11EB);
+    z ^ (z >> 31)
+}
+
+struct NoopWaker;
+
+impl Wake for NoopWaker {
+    fn wake(self: Arc<Self>) {}
+}
+
+struct ActiveGuard {
+    was_active: bool,
+}
+
+impl ActiveGuard {
+    fn activate() -> Self {
+        let was_active = SCHEDULER_ACTIVE.with(|active| active.replace(true));
+        Self { was_active }
+    }
+}
+
+impl Drop for ActiveGuard {
+    fn drop(&mut self) {
+        SCHEDULER_ACTIVE.with(|active| active.set(self.was_active));
+    }
+}
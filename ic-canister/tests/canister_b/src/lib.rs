@@ -102,6 +102,14 @@ impl CanisterB {
 
         (ic_canister::ic_kit::ic::caller(), canister_a_caller)
     }
+
+    #[update]
+    async fn cycles_after_call(&self) -> u64 {
+        let canister_a = CanisterAImpl::from_principal(self.state.borrow().canister_a);
+        canister_call!(canister_a.get_counter(), u32).await.unwrap();
+
+        ic_canister::ic_kit::ic::msg_cycles_available()
+    }
 }
 
 impl CanisterA for CanisterB {}
@@ -111,6 +119,13 @@ mod tests {
     use super::*;
     use ic_canister::ic_kit::mock_principals::alice;
     use ic_canister::ic_kit::MockContext;
+    use ic_canister::testing::{balance_of, set_balance, Interleaving, Scheduler};
+    use ic_canister::{
+        assert_virtual_call_count, assert_virtual_called_with, register_async_virtual_responder,
+        register_virtual_responder, register_wildcard_virtual_responder, reset_virtual_calls,
+        virtual_calls, virtual_calls_to, ResponseSequence,
+    };
+    use ic_cdk::api::call::RejectionCode;
 
     fn get_canister_b(canister_a: Principal) -> CanisterB {
         let canister = CanisterB::init_instance();
@@ -174,6 +189,81 @@ mod tests {
         );
     }
 
+    #[test]
+    fn interleaved_calls() {
+        MockContext::new().with_id(alice()).inject();
+
+        let canister_a = CanisterAImpl::init_instance();
+        let canister_b = get_canister_b(canister_a.principal());
+
+        let mut scheduler = Scheduler::new(Interleaving::Sequential);
+        let first = scheduler.spawn(canister_b.call_increment(5));
+        let second = scheduler.spawn(canister_b.call_increment(15));
+        scheduler.run();
+
+        assert_eq!(first.result(), 5);
+        assert_eq!(second.result(), 20);
+
+        // The second message increments the counter while the first one is waiting for the
+        // response of the `inc_counter` call.
+        let mut scheduler = Scheduler::new(Interleaving::RoundRobin);
+        let first = scheduler.spawn(canister_b.call_increment(5));
+        let second = scheduler.spawn(canister_b.call_increment(15));
+        scheduler.run();
+
+        assert_eq!(first.result(), 40);
+        assert_eq!(second.result(), 40);
+        assert_eq!(ic_canister::ic_kit::ic::id(), alice());
+    }
+
+    #[test]
+    fn interleaved_messages_keep_their_cycles() {
+        MockContext::new().with_id(alice()).inject();
+        set_balance(alice(), 10_000);
+
+        let canister_a = CanisterAImpl::init_instance();
+        let canister_b = get_canister_b(canister_a.principal());
+
+        // Both messages are suspended at the call to `canister_a` before any of them continues.
+        let mut scheduler = Scheduler::new(Interleaving::RoundRobin);
+        let first = scheduler.spawn(canister_call!(
+            canister_b.cycles_after_call(),
+            u64,
+            1_000u64
+        ));
+        let second = scheduler.spawn(canister_call!(
+            canister_b.cycles_after_call(),
+            u64,
+            3_000u64
+        ));
+        scheduler.run();
+
+        assert_eq!(first.result(), Ok(1_000));
+        assert_eq!(second.result(), Ok(3_000));
+        assert_eq!(balance_of(alice()), 10_000);
+    }
+
+    #[test]
+    fn seeded_interleaving_is_reproducible() {
+        MockContext::new().with_id(alice()).inject();
+
+        let canister_a = CanisterAImpl::init_instance();
+        let canister_b = get_canister_b(canister_a.principal());
+
+        let run = |interleaving| {
+            let mut scheduler = Scheduler::new(interleaving);
+            let _ = scheduler.spawn(canister_b.call_increment(1));
+            let _ = scheduler.spawn(canister_b.call_increment(2));
+            let _ = scheduler.spawn(canister_b.ids());
+            scheduler.run();
+            scheduler.trace().to_vec()
+        };
+
+        let trace = run(Interleaving::Random(42));
+        assert_eq!(trace, run(Interleaving::Random(42)));
+        assert_eq!(trace, run(Interleaving::Scripted(trace.clone())));
+    }
+
     #[tokio::test]
     async fn trait_methods() {
         MockContext::new().with_id(alice()).inject();
@@ -189,4 +279,161 @@ mod tests {
             18
         );
     }
+
+    #[tokio::test]
+    async fn virtual_calls_are_recorded() {
+        MockContext::new().with_id(alice()).inject();
+
+        let canister_a = ic_canister::ic_kit::mock_principals::bob();
+        let canister_b = get_canister_b(canister_a);
+        register_virtual_responder(canister_a, "inc_counter", |(_,): (u32,)| ());
+        register_virtual_responder(canister_a, "get_counter", |(): ()| 7u32);
+
+        assert_eq!(
+            canister_call!(canister_b.call_increment_virtual(5), u32)
+                .await
+                .unwrap(),
+            7
+        );
+        assert!(canister_call!(canister_b.notify_increment_virtual(3), bool)
+            .await
+            .unwrap());
+
+        assert_virtual_call_count(canister_a, "inc_counter", 2);
+        assert_virtual_call_count(canister_a, "get_counter", 1);
+        assert_virtual_called_with(canister_a, "inc_counter", (3u32,));
+
+        let calls = virtual_calls();
+        assert_eq!(calls.len(), 3);
+        assert_eq!(calls[0].caller, canister_b.principal());
+        assert_eq!(calls[0].method, "inc_counter");
+        assert_eq!(calls[0].decode_args::<(u32,)>(), (5,));
+        assert_eq!(calls[1].method, "get_counter");
+        assert_eq!(calls[2].cycles, 0);
+
+        reset_virtual_calls();
+        assert!(virtual_calls_to(canister_a, "inc_counter").is_empty());
+    }
+
+    #[test]
+    fn context_is_restored_when_responder_panics() {
+        MockContext::new().with_id(alice()).inject();
+
+        let canister_a = ic_canister::ic_kit::mock_principals::bob();
+        register_virtual_responder(canister_a, "get_counter", |(): ()| -> u32 {
+            assert_eq!(ic_canister::ic_kit::ic::id(), canister_a);
+            panic!("responder failed")
+        });
+
+        let result = std::panic::catch_unwind(|| {
+            let runtime = tokio::runtime::Builder::new_current_thread()
+                .build()
+                .unwrap();
+            runtime.block_on(virtual_canister_call!(canister_a, "get_counter", (), u32))
+        });
+
+        assert!(result.is_err());
+        assert_eq!(ic_canister::ic_kit::ic::id(), alice());
+    }
+
+    #[tokio::test]
+    async fn stateful_and_sequenced_responders() {
+        MockContext::new().with_id(alice()).inject();
+
+        let canister_a = ic_canister::ic_kit::mock_principals::bob();
+        let mut counter = 0u32;
+        register_virtual_responder(canister_a, "inc_counter", move |(value,): (u32,)| {
+            counter += value;
+            counter
+        });
+
+        ResponseSequence::new()
+            .reject(RejectionCode::SysTransient, "busy")
+            .reply(10u32)
+            .register(canister_a, "get_counter");
+
+        register_wildcard_virtual_responder(canister_a, |method, _| {
+            Err((
+                RejectionCode::DestinationInvalid,
+                format!("{method} is stopped"),
+            ))
+        });
+
+        let result = virtual_canister_call!(canister_a, "inc_counter", (2u32,), u32).await;
+        assert_eq!(result, Ok(2));
+        let result = virtual_canister_call!(canister_a, "inc_counter", (3u32,), u32).await;
+        assert_eq!(result, Ok(5));
+
+        let result = virtual_canister_call!(canister_a, "get_counter", (), u32).await;
+        assert_eq!(
+            result,
+            Err((RejectionCode::SysTransient, "busy".to_string()))
+        );
+        let result = virtual_canister_call!(canister_a, "get_counter", (), u32).await;
+        assert_eq!(result, Ok(10));
+        let result = virtual_canister_call!(canister_a, "get_counter", (), u32).await;
+        assert!(result.is_err());
+
+        let result = virtual_canister_call!(canister_a, "reset", (), ()).await;
+        assert_eq!(
+            result,
+            Err((
+                RejectionCode::DestinationInvalid,
+                "reset is stopped".to_string()
+            ))
+        );
+    }
+
+    #[tokio::test]
+    async fn async_responder_calls_other_canisters() {
+        MockContext::new().with_id(alice()).inject();
+
+        let canister_a = CanisterAImpl::init_instance();
+        let proxy = ic_canister::ic_kit::mock_principals::bob();
+        let canister_b = get_canister_b(proxy);
+
+        let target = canister_a.clone();
+        register_async_virtual_responder(proxy, "inc_counter", move |(value,): (u32,)| {
+            let mut target = target.clone();
+            async move { canister_call!(target.inc_counter(value), ()).await }
+        });
+
+        let target = canister_a.clone();
+        register_async_virtual_responder(proxy, "get_counter", move |(): ()| {
+            let target = target.clone();
+            async move { canister_call!(target.get_counter(), u32).await }
+        });
+
+        let target = canister_a.clone();
+        register_async_virtual_responder(proxy, "caller", move |(): ()| {
+            let target = target.clone();
+            async move { canister_call!(target.caller(), Principal).await }
+        });
+
+        assert_eq!(canister_b.call_increment_virtual(4).await, 4);
+        assert_eq!(canister_b.call_increment_virtual(6).await, 10);
+
+        let caller = virtual_canister_call!(proxy, "caller", (), Principal).await;
+        assert_eq!(caller, Ok(proxy));
+        assert_eq!(ic_canister::ic_kit::ic::id(), alice());
+    }
+
+    #[tokio::test]
+    async fn attached_cycles_are_moved_and_refunded() {
+        MockContext::new().with_id(alice()).inject();
+
+        let wallet = ic_canister::ic_kit::mock_principals::bob();
+        set_balance(alice(), 10_000);
+        set_balance(wallet, 0);
+        register_virtual_responder(wallet, "deposit", |(): ()| {
+            let available = ic_canister::ic_kit::ic::msg_cycles_available();
+            ic_canister::ic_kit::ic::msg_cycles_accept(available / 4)
+        });
+
+        let accepted = virtual_canister_call!(wallet, "deposit", (), u64, 4_000u64).await;
+        assert_eq!(accepted, Ok(1_000));
+        assert_eq!(balance_of(alice()), 9_000);
+        assert_eq!(balance_of(wallet), 1_000);
+        assert_eq!(ic_canister::ic_kit::ic::balance(), 9_000);
+    }
 }
@@ -1,10 +1,18 @@
 use candid::{CandidType, Deserialize, Principal};
 use ic_helpers::metrics::Metrics;
+use ic_helpers::stable_export::StableExport;
+use ic_storage::collections::StableLog;
 use ic_storage::stable::Versioned;
 use ic_storage::IcStorage;
 use std::{cell::RefCell, rc::Rc};
 
-use ic_canister::{update, Canister, MethodType, PreUpdate};
+use ic_canister::{
+    canister_call, query, update, virtual_canister_call, Canister, InspectMessage, MethodType,
+    PreUpdate,
+};
+
+#[cfg(test)]
+mod v2;
 
 #[derive(Default, CandidType, Deserialize, IcStorage)]
 pub struct State {
@@ -19,18 +27,57 @@ impl Versioned for State {
     }
 }
 
+#[derive(Default, CandidType, Deserialize, IcStorage)]
+pub struct Settings {
+    label: String,
+}
+
+impl Versioned for Settings {
+    type Previous = ();
+
+    fn upgrade((): ()) -> Self {
+        Self::default()
+    }
+}
+
+/// State that is not stored over upgrades, so it is only required to implement `Clone` to be
+/// rolled back on traps.
+#[derive(Default, Clone, IcStorage)]
+pub struct Cache {
+    hits: u32,
+}
+
+/// State that can be neither cloned nor serialized, so it is not rolled back on traps.
+#[derive(Default, IcStorage)]
+pub struct Session {
+    calls: u32,
+}
+
 #[derive(CandidType, Deserialize, IcStorage, Default, Clone)]
 pub struct MetricsSnapshot {
     pub cycles: u64,
 }
 
 #[derive(Clone, Canister)]
+#[canister_inspect_message]
 pub struct CanisterC {
     #[id]
     principal: Principal,
 
     #[state]
     state: Rc<RefCell<State>>,
+
+    #[state]
+    settings: Rc<RefCell<Settings>>,
+
+    #[state]
+    events: Rc<RefCell<StableLog<String>>>,
+
+    #[state(stable_store = false)]
+    cache: Rc<RefCell<Cache>>,
+
+    #[state(stable_store = false)]
+    session: Rc<RefCell<Session>>,
 }
 
 impl CanisterC {
@@ -38,10 +85,116 @@ impl CanisterC {
     fn inc_counter(&mut self, value: u32) {
         self.state.borrow_mut().counter += value;
     }
+
+    #[update]
+    fn inc_counter_and_trap(&mut self, value: u32) {
+        self.state.borrow_mut().counter += value;
+        ic_canister::ic_kit::ic::trap("counter is broken");
+    }
+
+    #[update]
+    async fn inc_counter_and_trap_async(&mut self, value: u32) {
+        self.state.borrow_mut().counter += value;
+        ic_canister::ic_kit::ic::trap("counter is broken");
+    }
+
+    #[update]
+    async fn inc_counter_call_and_trap(&mut self, value: u32) {
+        self.state.borrow_mut().counter += value;
+        let _ = virtual_canister_call!(Principal::anonymous(), "ping", (), ()).await;
+        self.state.borrow_mut().counter += value;
+        ic_canister::ic_kit::ic::trap("counter is broken");
+    }
+
+    #[update]
+    fn accept_cycles_and_trap(&mut self, amount: u64) {
+        ic_canister::ic_kit::ic::msg_cycles_accept(amount);
+        ic_canister::ic_kit::ic::trap("cycles are broken");
+    }
+
+    #[update]
+    fn inc_hits_and_trap(&mut self) {
+        self.cache.borrow_mut().hits += 1;
+        ic_canister::ic_kit::ic::trap("cache is broken");
+    }
+
+    #[update]
+    fn inc_calls_and_trap(&mut self) {
+        self.session.borrow_mut().calls += 1;
+        ic_canister::ic_kit::ic::trap("session is broken");
+    }
+
+    #[query]
+    fn get_calls(&self) -> u32 {
+        self.session.borrow().calls
+    }
+
+    #[query]
+    fn get_hits(&self) -> u32 {
+        self.cache.borrow().hits
+    }
+
+    #[update]
+    fn reset_counter(&mut self) {
+        self.state.borrow_mut().counter = 0;
+    }
+
+    #[update]
+    #[allow(unused_mut)]
+    async fn reset_remote_counter(&self, canister: Principal) {
+        let mut canister = CanisterC::from_principal(canister);
+        canister_call!(canister.reset_counter(), ()).await.unwrap();
+    }
+
+    #[update]
+    fn set_label(&mut self, label: String) {
+        self.settings.borrow_mut().label = label;
+    }
+
+    #[update]
+    fn log_event(&mut self, event: String) {
+        self.events.borrow_mut().append(&event).unwrap();
+    }
+
+    #[query]
+    fn get_events(&self) -> Vec<String> {
+        self.events
+            .borrow()
+            .iter()
+            .collect::<Result<_, _>>()
+            .unwrap()
+    }
+
+    #[query]
+    fn get_counter(&self) -> u32 {
+        self.state.borrow().counter
+    }
+
+    #[query]
+    fn inc_counter_in_query(&self) -> u32 {
+        let mut state = self.state.borrow_mut();
+        state.counter += 1;
+        state.counter
+    }
 }
 
 impl Metrics for CanisterC {}
 
+impl StableExport for CanisterC {
+    fn is_export_allowed(&self, caller: Principal) -> bool {
+        caller != Principal::anonymous()
+    }
+}
+
+impl InspectMessage for CanisterC {
+    fn inspect_message(&self, method_name: &str, caller: Principal) -> Result<(), String> {
+        match method_name {
+            "reset_counter" => Err(format!("{caller} cannot reset the counter")),
+            _ => Ok(()),
+        }
+    }
+}
+
 impl PreUpdate for CanisterC {
     fn pre_update(&self, _method_name: &str, _method_type: MethodType) {
         self.update_metrics();
@@ -51,7 +204,14 @@ impl PreUpdate for CanisterC {
 #[cfg(test)]
 mod tests {
     use super::*;
-    use ic_canister::{canister_call, ic_kit::MockContext};
+    use ic_canister::testing::{
+        balance_of, set_query_mode, upgrade_instance, QueryDenied, QueryMode,
+    };
+    use ic_canister::{canister_call, canister_notify, ic_kit::MockContext};
+    use ic_cdk::api::call::RejectionCode;
+    use ic_storage::stable::inspect::{inspect, Layout, StablePage, MAX_PAGE_SIZE};
+    use ic_storage::stable::Codec;
+    use std::panic::AssertUnwindSafe;
 
     #[tokio::test]
     async fn get_metrics() {
@@ -77,4 +237,368 @@ mod tests {
         assert_eq!(metrics_snapshot.cycles, 1e+14 as u64);
         assert_eq!(metrics_snapshot.stable_memory_size, 0);
     }
+
+    #[tokio::test]
+    async fn export_stable_memory() {
+        let owner = ic_canister::ic_kit::mock_principals::alice();
+        MockContext::new().with_id(owner).inject();
+
+        let mut canister_c = CanisterC::init_instance();
+        canister_call!(canister_c.inc_counter(5), ()).await.unwrap();
+        canister_c.__pre_upgrade_inst();
+
+        let mut dump = vec![];
+        loop {
+            let offset = dump.len() as u64;
+            let page = canister_call!(
+                canister_c.export_stable_memory(offset, MAX_PAGE_SIZE),
+                StablePage
+            )
+            .await
+            .unwrap();
+            dump.extend_from_slice(&page.data);
+            if dump.len() as u64 >= page.total_size {
+                break;
+            }
+        }
+
+        let info = inspect(&dump).unwrap();
+        assert_eq!(info.layout, Layout::Regions);
+        let state = info
+            .values
+            .iter()
+            .find(|value| value.tag == "state")
+            .unwrap();
+        assert_eq!(state.codec, Some(Codec::Candid));
+        assert_eq!(state.len, state.data.len() as u64);
+        assert!(state.pretty().unwrap().contains('5'));
+    }
+
+    #[tokio::test]
+    async fn export_stable_memory_is_denied() {
+        MockContext::new().with_id(Principal::anonymous()).inject();
+
+        let canister_c = CanisterC::init_instance();
+        let (code, message) = canister_call!(
+            canister_c.export_stable_memory(0, MAX_PAGE_SIZE),
+            StablePage
+        )
+        .await
+        .unwrap_err();
+        assert_eq!(code, RejectionCode::CanisterError);
+        assert!(message.contains("not allowed"), "{message}");
+    }
+
+    #[tokio::test]
+    async fn trap_rolls_back_state() {
+        MockContext::new().inject();
+
+        let mut canister_c = CanisterC::init_instance();
+        canister_call!(canister_c.inc_counter(5), ()).await.unwrap();
+
+        let (code, message) = canister_call!(canister_c.inc_counter_and_trap(10), ())
+            .await
+            .unwrap_err();
+        assert_eq!(code, RejectionCode::CanisterError);
+        assert!(message.contains("counter is broken"), "{message}");
+
+        let (code, message) = canister_call!(canister_c.inc_counter_and_trap_async(10), ())
+            .await
+            .unwrap_err();
+        assert_eq!(code, RejectionCode::CanisterError);
+        assert!(message.contains("counter is broken"), "{message}");
+
+        assert_eq!(
+            canister_call!(canister_c.get_counter(), u32).await.unwrap(),
+            5
+        );
+    }
+
+    #[tokio::test]
+    async fn trap_rolls_back_fields_not_stored_over_upgrade() {
+        MockContext::new().inject();
+
+        let mut canister_c = CanisterC::init_instance();
+        let result = canister_call!(canister_c.inc_hits_and_trap(), ()).await;
+        assert!(result.is_err());
+
+        assert_eq!(canister_call!(canister_c.get_hits(), u32).await.unwrap(), 0);
+    }
+
+    #[tokio::test]
+    async fn trap_keeps_changes_of_fields_that_cannot_be_copied() {
+        MockContext::new().inject();
+
+        let mut canister_c = CanisterC::init_instance();
+        let (code, message) = canister_call!(canister_c.inc_calls_and_trap(), ())
+            .await
+            .unwrap_err();
+        assert_eq!(code, RejectionCode::CanisterError);
+        assert!(message.contains("session is broken"), "{message}");
+
+        assert_eq!(
+            canister_call!(canister_c.get_calls(), u32).await.unwrap(),
+            1
+        );
+    }
+
+    #[tokio::test]
+    async fn trap_keeps_changes_made_before_await() {
+        MockContext::new().inject();
+
+        let mut canister_c = CanisterC::init_instance();
+        let result = canister_call!(canister_c.inc_counter_call_and_trap(5), ()).await;
+        assert!(result.is_err());
+
+        // The changes made before the inter-canister call are committed, as in the IC.
+        assert_eq!(
+            canister_call!(canister_c.get_counter(), u32).await.unwrap(),
+            5
+        );
+    }
+
+    #[tokio::test]
+    async fn query_changes_persist_by_default() {
+        MockContext::new().inject();
+
+        let canister_c = CanisterC::init_instance();
+        canister_call!(canister_c.inc_counter_in_query(), u32)
+            .await
+            .unwrap();
+
+        assert_eq!(
+            canister_call!(canister_c.get_counter(), u32).await.unwrap(),
+            1
+        );
+    }
+
+    #[tokio::test]
+    async fn query_changes_are_discarded() {
+        MockContext::new().inject();
+        set_query_mode(QueryMode::Discard);
+
+        let canister_c = CanisterC::init_instance();
+        let result = canister_call!(canister_c.inc_counter_in_query(), u32)
+            .await
+            .unwrap();
+
+        assert_eq!(result, 1);
+        assert_eq!(
+            canister_call!(canister_c.get_counter(), u32).await.unwrap(),
+            0
+        );
+    }
+
+    fn block_on<F: std::future::Future>(future: F) -> F::Output {
+        tokio::runtime::Builder::new_current_thread()
+            .build()
+            .unwrap()
+            .block_on(future)
+    }
+
+    #[test]
+    fn query_changes_are_denied() {
+        MockContext::new().inject();
+        set_query_mode(QueryMode::Deny);
+
+        let canister_c = CanisterC::init_instance();
+        assert_eq!(
+            block_on(canister_call!(canister_c.get_counter(), u32)).unwrap(),
+            0
+        );
+
+        let payload = std::panic::catch_unwind(AssertUnwindSafe(|| {
+            block_on(canister_call!(canister_c.inc_counter_in_query(), u32))
+        }))
+        .unwrap_err();
+        let denied = payload.downcast::<QueryDenied>().unwrap();
+        assert!(denied.0.contains("changed the state fields"), "{denied}");
+    }
+
+    #[test]
+    fn trap_after_denied_query_is_rejection() {
+        MockContext::new().inject();
+        set_query_mode(QueryMode::Deny);
+
+        let mut canister_c = CanisterC::init_instance();
+        let result = std::panic::catch_unwind(AssertUnwindSafe(|| {
+            block_on(canister_call!(canister_c.inc_counter_in_query(), u32))
+        }));
+        assert!(result.is_err());
+
+        let (code, message) =
+            block_on(canister_call!(canister_c.inc_counter_and_trap(10), ())).unwrap_err();
+        assert_eq!(code, RejectionCode::CanisterError);
+        assert!(message.contains("counter is broken"), "{message}");
+    }
+
+    #[tokio::test]
+    async fn all_state_fields_are_preserved_over_upgrade() {
+        MockContext::new().inject();
+
+        let mut canister_c = CanisterC::init_instance();
+        canister_call!(canister_c.inc_counter(5), ()).await.unwrap();
+        canister_call!(canister_c.set_label("label".into()), ())
+            .await
+            .unwrap();
+
+        canister_call!(canister_c.log_event("first".into()), ())
+            .await
+            .unwrap();
+
+        canister_c.__pre_upgrade_inst();
+        canister_c.state.replace(State::default());
+        canister_c.settings.replace(Settings::default());
+        canister_c.__post_upgrade_inst();
+
+        canister_call!(canister_c.log_event("second".into()), ())
+            .await
+            .unwrap();
+
+        assert_eq!(
+            canister_call!(canister_c.get_counter(), u32).await.unwrap(),
+            5
+        );
+        assert_eq!(canister_c.settings.borrow().label, "label");
+        assert_eq!(
+            canister_call!(canister_c.get_events(), Vec<String>)
+                .await
+                .unwrap(),
+            vec!["first", "second"]
+        );
+    }
+
+    #[tokio::test]
+    async fn upgrade_instance_restores_state() {
+        MockContext::new().inject();
+
+        let mut canister_c = CanisterC::init_instance();
+        canister_call!(canister_c.inc_counter(5), ()).await.unwrap();
+        canister_call!(canister_c.log_event("first".into()), ())
+            .await
+            .unwrap();
+
+        let upgraded = upgrade_instance::<CanisterC>(canister_c.principal());
+        assert_eq!(upgraded.principal(), canister_c.principal());
+        assert_eq!(
+            canister_call!(upgraded.get_counter(), u32).await.unwrap(),
+            5
+        );
+        assert_eq!(
+            canister_call!(upgraded.get_events(), Vec<String>)
+                .await
+                .unwrap(),
+            vec!["first"]
+        );
+    }
+
+    #[tokio::test]
+    async fn upgrade_instance_migrates_state() {
+        MockContext::new().inject();
+
+        let mut canister_c = CanisterC::init_instance();
+        canister_call!(canister_c.inc_counter(5), ()).await.unwrap();
+        canister_call!(canister_c.set_label("label".into()), ())
+            .await
+            .unwrap();
+        canister_call!(canister_c.log_event("first".into()), ())
+            .await
+            .unwrap();
+
+        let upgraded = upgrade_instance::<v2::CanisterCV2>(canister_c.principal());
+        assert_eq!(
+            canister_call!(upgraded.get_counter_v2(), (u64, u32))
+                .await
+                .unwrap(),
+            (5, 5)
+        );
+        assert_eq!(
+            canister_call!(upgraded.get_label(), String).await.unwrap(),
+            "label"
+        );
+        assert_eq!(
+            canister_call!(upgraded.count_events(), u64).await.unwrap(),
+            1
+        );
+    }
+
+    #[tokio::test]
+    async fn cycles_are_refunded_on_trap() {
+        MockContext::new().inject();
+
+        let mut canister_c = CanisterC::init_instance();
+        let caller = ic_canister::ic_kit::ic::id();
+        let caller_balance = balance_of(caller);
+        let canister_balance = balance_of(canister_c.principal());
+
+        let result = canister_call!(canister_c.inc_counter_and_trap(1), (), 1_000).await;
+        assert!(result.is_err());
+        assert_eq!(balance_of(caller), caller_balance);
+        assert_eq!(balance_of(canister_c.principal()), canister_balance);
+
+        // The cycles are not accepted by the method, so they are refunded.
+        canister_call!(canister_c.inc_counter(1), (), 1_000)
+            .await
+            .unwrap();
+        assert_eq!(balance_of(caller), caller_balance);
+    }
+
+    #[tokio::test]
+    async fn accepted_cycles_are_refunded_on_trap() {
+        MockContext::new().inject();
+
+        let mut canister_c = CanisterC::init_instance();
+        let caller = ic_canister::ic_kit::ic::id();
+        let caller_balance = balance_of(caller);
+        let canister_balance = balance_of(canister_c.principal());
+
+        let result = canister_call!(canister_c.accept_cycles_and_trap(400), (), 1_000).await;
+        assert!(result.is_err());
+        assert_eq!(balance_of(caller), caller_balance);
+        assert_eq!(balance_of(canister_c.principal()), canister_balance);
+
+        // The notification is delivered even though the method traps.
+        canister_notify!(canister_c.accept_cycles_and_trap(400), (), 1_000).unwrap();
+        assert_eq!(balance_of(caller), caller_balance);
+        assert_eq!(balance_of(canister_c.principal()), canister_balance);
+    }
+
+    #[tokio::test]
+    async fn ingress_messages_are_inspected() {
+        MockContext::new().inject();
+
+        let mut canister_c = CanisterC::init_instance();
+        canister_call!(canister_c.inc_counter(5), ()).await.unwrap();
+
+        let (code, message) = canister_call!(canister_c.reset_counter(), ())
+            .await
+            .unwrap_err();
+        assert_eq!(code, RejectionCode::CanisterReject);
+        assert!(message.contains("cannot reset the counter"), "{message}");
+        assert_eq!(
+            canister_call!(canister_c.get_counter(), u32).await.unwrap(),
+            5
+        );
+
+        // The methods called directly are not inspected.
+        canister_c.reset_counter();
+        assert_eq!(canister_c.get_counter(), 0);
+    }
+
+    #[tokio::test]
+    async fn inter_canister_calls_are_not_inspected() {
+        MockContext::new().inject();
+
+        let mut canister_c = CanisterC::init_instance();
+        let other = CanisterC::init_instance();
+        canister_call!(canister_c.inc_counter(5), ()).await.unwrap();
+
+        canister_call!(other.reset_remote_counter(canister_c.principal()), ())
+            .await
+            .unwrap();
+        assert_eq!(
+            canister_call!(canister_c.get_counter(), u32).await.unwrap(),
+            0
+        );
+    }
 }
@@ -0,0 +1,63 @@
+//! The next version of [`CanisterC`](crate::CanisterC), which state is migrated from the previous
+//! version on upgrade.
+
+use candid::{CandidType, Deserialize, Principal};
+use ic_canister::{query, Canister, PreUpdate};
+use ic_storage::collections::StableLog;
+use ic_storage::stable::Versioned;
+use ic_storage::IcStorage;
+use std::{cell::RefCell, rc::Rc};
+
+use crate::{Settings, State};
+
+#[derive(Default, CandidType, Deserialize, IcStorage)]
+pub struct StateV2 {
+    counter: u64,
+    counter_before_upgrade: u32,
+}
+
+impl Versioned for StateV2 {
+    type Previous = State;
+
+    fn upgrade(previous: State) -> Self {
+        Self {
+            counter: previous.counter.into(),
+            counter_before_upgrade: previous.counter,
+        }
+    }
+}
+
+#[derive(Clone, Canister)]
+pub struct CanisterCV2 {
+    #[id]
+    principal: Principal,
+
+    #[state]
+    state: Rc<RefCell<StateV2>>,
+
+    #[state]
+    settings: Rc<RefCell<Settings>>,
+
+    #[state]
+    events: Rc<RefCell<StableLog<String>>>,
+}
+
+impl CanisterCV2 {
+    #[query]
+    pub fn get_counter_v2(&self) -> (u64, u32) {
+        let state = self.state.borrow();
+        (state.counter, state.counter_before_upgrade)
+    }
+
+    #[query]
+    pub fn get_label(&self) -> String {
+        self.settings.borrow().label.clone()
+    }
+
+    #[query]
+    pub fn count_events(&self) -> u64 {
+        self.events.borrow().len().unwrap()
+    }
+}
+
+impl PreUpdate for CanisterCV2 {}
@@ -27,3 +27,5 @@ pub use types::*;
 pub mod tokens;
 
 pub mod candid_header;
+
+pub mod stable_export;
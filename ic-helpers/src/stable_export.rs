@@ -0,0 +1,47 @@
+//! Canister API to download the stable memory of a canister, e.g. for offline backups.
+//!
+//! A canister implementing the [`StableExport`] trait serves its stable memory page by page with
+//! the `export_stable_memory` query. The downloaded copy can be decoded with
+//! [`ic_storage::stable::inspect::inspect`].
+//!
+//! ```ignore
+//! impl StableExport for MyCanister {
+//!     fn is_export_allowed(&self, caller: Principal) -> bool {
+//!         caller == self.state.borrow().owner
+//!     }
+//! }
+//! ```
+//!
+//! As the stable memory contains the whole state of the canister, nobody is allowed to download it
+//! unless [`StableExport::is_export_allowed`] is implemented.
+
+use candid::Principal;
+use ic_canister::{query, Canister, PreUpdate};
+use ic_storage::stable::inspect::{self, StablePage};
+
+/// Adds the `export_stable_memory` query, which returns the raw stable memory of the canister in
+/// pages of at most [`inspect::MAX_PAGE_SIZE`] bytes (2 MiB).
+///
+/// The pages are meant to be concatenated into an offline backup of the canister state, which can
+/// be decoded with [`inspect::inspect`]. Only the principals accepted by
+/// [`is_export_allowed`](Self::is_export_allowed) can download the memory.
+pub trait StableExport: Canister + Sized + PreUpdate {
+    /// Returns true if the principal is allowed to download the stable memory of the canister.
+    fn is_export_allowed(&self, _caller: Principal) -> bool {
+        false
+    }
+
+    /// Returns at most `len` bytes of the stable memory starting from the given offset. The size
+    /// of a page is limited by [`inspect::MAX_PAGE_SIZE`], so the whole memory must be downloaded
+    /// by reading the pages until [`StablePage::total_size`] is reached.
+    ///
+    /// Traps if the caller is not allowed to download the stable memory.
+    #[query(trait = true)]
+    fn export_stable_memory(&self, offset: u64, len: u64) -> StablePage {
+        if !self.is_export_allowed(ic_kit::ic::caller()) {
+            ic_cdk::trap("the caller is not allowed to export the stable memory");
+        }
+
+        inspect::read_page(offset, len)
+    }
+}
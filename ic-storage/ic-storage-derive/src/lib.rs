@@ -1,11 +1,20 @@
 extern crate proc_macro;
 
 use proc_macro::TokenStream;
-use syn::DeriveInput;
+use syn::{DeriveInput, Generics, Ident};
+
+mod versioned;
 
 #[proc_macro_derive(IcStorage)]
 pub fn derive_ic_storage(input: TokenStream) -> TokenStream {
-    let DeriveInput { ident, .. } = syn::parse_macro_input!(input);
+    let DeriveInput {
+        ident, generics, ..
+    } = syn::parse_macro_input!(input);
+
+    if !generics.params.is_empty() {
+        return derive_generic_ic_storage(ident, generics);
+    }
+
     let output = quote::quote! {
         #[cfg(target_arch = "wasm32")]
         impl ::ic_canister::storage::IcStorage for #ident {
@@ -36,7 +45,19 @@ pub fn derive_ic_storage(input: TokenStream) -> TokenStream {
                 let id = ::ic_canister::ic_kit::ic::id();
                 store.with(|v| {
                     let mut borrowed_store = v.borrow_mut();
-                    (*borrowed_store.entry(id).or_default()).clone()
+                    let state = borrowed_store.entry(id).or_insert_with(|| {
+                        // Selects the way to copy the state for the snapshots, see
+                        // `ic_storage::testing::CopyProbe`.
+                        #[allow(unused_imports)]
+                        use ::ic_canister::storage::testing::{CopyByCandid, CopyByClone, CopyUnsupported};
+
+                        let state = Rc::new(RefCell::new(<#ident as ::std::default::Default>::default()));
+                        let copier = (&&&::ic_canister::storage::testing::CopyProbe::<#ident>::new()).copier();
+                        ::ic_canister::storage::testing::register_state(id, &state, copier);
+                        state
+                    });
+
+                    state.clone()
                 })
             }
         }
@@ -44,3 +65,39 @@ pub fn derive_ic_storage(input: TokenStream) -> TokenStream {
 
     output.into()
 }
+
+/// A `thread_local` static cannot be declared for each concrete type of a generic type, so the
+/// states of generic types are stored in a registry indexed by their type ids.
+fn derive_generic_ic_storage(ident: Ident, mut generics: Generics) -> TokenStream {
+    generics
+        .make_where_clause()
+        .predicates
+        .push(syn::parse_quote! { Self: ::std::default::Default + 'static });
+    let (impl_generics, ty_generics, where_clause) = generics.split_for_impl();
+
+    let output = quote::quote! {
+        #[cfg(target_arch = "wasm32")]
+        impl #impl_generics ::ic_canister::storage::IcStorage for #ident #ty_generics #where_clause {
+            fn get() -> ::std::rc::Rc<::std::cell::RefCell<Self>> {
+                ::ic_canister::storage::generic::get::<Self>()
+            }
+        }
+
+        #[cfg(not(target_arch = "wasm32"))]
+        impl #impl_generics ::ic_canister::storage::IcStorage for #ident #ty_generics #where_clause {
+            fn get() -> ::std::rc::Rc<::std::cell::RefCell<Self>> {
+                ::ic_canister::storage::generic::get::<Self>(::ic_canister::ic_kit::ic::id())
+            }
+        }
+    };
+
+    output.into()
+}
+
+#[proc_macro_derive(Versioned, attributes(versioned))]
+pub fn derive_versioned(input: TokenStream) -> TokenStream {
+    let input = syn::parse_macro_input!(input as DeriveInput);
+    versioned::expand(input)
+        .unwrap_or_else(|e| e.to_compile_error())
+        .into()
+}
@@ -0,0 +1,186 @@
+use proc_macro2::TokenStream;
+use quote::quote;
+use syn::parse::{Parse, ParseStream};
+use syn::punctuated::Punctuated;
+use syn::{Attribute, DeriveInput, ExprPath, Ident, LitInt, Token, Type};
+
+/// Arguments of the `#[versioned(...)]` attribute.
+#[derive(Default)]
+struct VersionedArgs {
+    previous: Option<Type>,
+    upgrade: Option<ExprPath>,
+    try_upgrade: Option<ExprPath>,
+    downgrade: Option<ExprPath>,
+    version: Option<LitInt>,
+    codec: Option<Ident>,
+}
+
+enum VersionedArg {
+    Previous(Type),
+    Upgrade(ExprPath),
+    TryUpgrade(ExprPath),
+    Downgrade(ExprPath),
+    Version(LitInt),
+    Codec(Ident),
+}
+
+impl Parse for VersionedArg {
+    fn parse(input: ParseStream) -> syn::Result<Self> {
+        let name: Ident = input.parse()?;
+        input.parse::<Token![=]>()?;
+
+        match name.to_string().as_str() {
+            "previous" => Ok(Self::Previous(input.parse()?)),
+            "upgrade" => Ok(Self::Upgrade(input.parse()?)),
+            "try_upgrade" => Ok(Self::TryUpgrade(input.parse()?)),
+            "downgrade" => Ok(Self::Downgrade(input.parse()?)),
+            "version" => {
+                let version: LitInt = input.parse()?;
+                version.base10_parse::<u32>()?;
+                Ok(Self::Version(version))
+            }
+            "codec" => Ok(Self::Codec(input.parse()?)),
+            _ => Err(syn::Error::new(
+                name.span(),
+                "unknown argument, expected `previous`, `upgrade`, `try_upgrade`, `downgrade`, \
+                 `version` or `codec`",
+            )),
+        }
+    }
+}
+
+impl VersionedArgs {
+    fn from_attrs(attrs: &[Attribute]) -> syn::Result<Self> {
+        let mut args = Self::default();
+        for attr in attrs.iter().filter(|attr| attr.path.is_ident("versioned")) {
+            let parsed =
+                attr.parse_args_with(Punctuated::<VersionedArg, Token![,]>::parse_terminated)?;
+
+            for arg in parsed {
+                let duplicate = match arg {
+                    VersionedArg::Previous(v) => args.previous.replace(v).is_some(),
+                    VersionedArg::Upgrade(v) => args.upgrade.replace(v).is_some(),
+                    VersionedArg::TryUpgrade(v) => args.try_upgrade.replace(v).is_some(),
+                    VersionedArg::Downgrade(v) => args.downgrade.replace(v).is_some(),
+                    VersionedArg::Version(v) => args.version.replace(v).is_some(),
+                    VersionedArg::Codec(v) => args.codec.replace(v).is_some(),
+                };
+
+                if duplicate {
+                    return Err(syn::Error::new_spanned(
+                        attr,
+                        "duplicate argument in `versioned` attribute",
+                    ));
+                }
+            }
+        }
+
+        Ok(args)
+    }
+}
+
+pub fn expand(input: DeriveInput) -> syn::Result<TokenStream> {
+    if !input.generics.params.is_empty() {
+        return Err(syn::Error::new_spanned(
+            &input.generics,
+            "`Versioned` cannot be derived for generic types",
+        ));
+    }
+
+    let args = VersionedArgs::from_attrs(&input.attrs)?;
+    let ident = &input.ident;
+
+    if args.upgrade.is_some() && args.try_upgrade.is_some() {
+        return Err(syn::Error::new_spanned(
+            ident,
+            "only one of `upgrade` and `try_upgrade` can be specified",
+        ));
+    }
+
+    let is_first = match &args.previous {
+        None => true,
+        Some(Type::Tuple(tuple)) => tuple.elems.is_empty(),
+        Some(_) => false,
+    };
+    let previous = args.previous.unwrap_or_else(|| syn::parse_quote!(()));
+
+    // The first version is created with `Default` and any other version with `From` unless the
+    // upgrade function is specified.
+    let upgrade = match (args.upgrade, &args.try_upgrade, is_first) {
+        (Some(upgrade), _, _) => quote! { #upgrade(previous) },
+        (None, Some(_), _) => quote! {
+            match <Self as ::ic_storage::stable::Versioned>::try_upgrade(previous) {
+                ::std::result::Result::Ok(value) => value,
+                ::std::result::Result::Err(e) => ::std::panic!("{}", e),
+            }
+        },
+        (None, None, true) => quote! { ::std::default::Default::default() },
+        (None, None, false) => quote! { ::std::convert::From::from(previous) },
+    };
+
+    let try_upgrade = args.try_upgrade.map(|try_upgrade| {
+        quote! {
+            fn try_upgrade(
+                previous: Self::Previous,
+            ) -> ::std::result::Result<Self, ::ic_storage::MigrationError> {
+                #try_upgrade(previous)
+            }
+        }
+    });
+
+    let downgrade = args.downgrade.map(|downgrade| {
+        quote! {
+            fn downgrade(self) -> ::std::option::Option<Self::Previous> {
+                ::std::option::Option::Some(#downgrade(self))
+            }
+        }
+    });
+
+    let encode = args.codec.map(|codec| {
+        quote! {
+            fn encode(&self) -> ::ic_storage::Result<::ic_storage::stable::Encoded> {
+                ::ic_storage::stable::Codec::#codec.encode(self)
+            }
+        }
+    });
+
+    let version = match args.version {
+        Some(version) => quote! { #version },
+        None => quote! { <#previous as ::ic_storage::stable::VersionChain>::VERSION + 1 },
+    };
+
+    let message =
+        format!("the version of `{ident}` must be greater than the version of its previous type");
+
+    Ok(quote! {
+        impl ::ic_storage::stable::Versioned for #ident {
+            type Previous = #previous;
+
+            fn version() -> u32 {
+                <Self as ::ic_storage::stable::VersionChain>::VERSION
+            }
+
+            #[allow(unused_variables)]
+            fn upgrade(previous: Self::Previous) -> Self {
+                #upgrade
+            }
+
+            #try_upgrade
+
+            #downgrade
+
+            #encode
+        }
+
+        impl ::ic_storage::stable::VersionChain for #ident {
+            type Previous = #previous;
+            const VERSION: u32 = #version;
+        }
+
+        const _: () = ::std::assert!(
+            <#ident as ::ic_storage::stable::VersionChain>::VERSION
+                > <#previous as ::ic_storage::stable::VersionChain>::VERSION,
+            #message
+        );
+    })
+}
@@ -0,0 +1,385 @@
+//! Diagnostics of conflicting borrows of the canister state.
+//!
+//! [`IcStorage::get`](crate::IcStorage::get) returns the state in a `RefCell`, and borrowing it
+//! mutably while it is already borrowed panics with a `BorrowMutError`, that doesn't tell where the
+//! state was borrowed. Borrowing the state with the methods of [`TrackedBorrow`] instead of
+//! `borrow()` and `borrow_mut()` records the location and the canister method of every borrow when
+//! the `track-borrows` feature is enabled, and the panic message of a conflicting borrow contains
+//! both the location of the borrow that is still held and the location of the conflicting one:
+//!
+//! ```text
+//! `my_canister::State` is already mutably borrowed at src/lib.rs:42:30 in method `transfer`,
+//! conflicting borrow at src/lib.rs:57:26 in method `transfer`
+//! ```
+//!
+//! ```
+//! # ic_canister::ic_kit::MockContext::new().inject();
+//! use ic_storage::borrow::TrackedBorrow;
+//! use ic_storage::IcStorage;
+//!
+//! #[derive(IcStorage, Default)]
+//! struct State {
+//!     value: u32,
+//! }
+//!
+//! let state = State::get();
+//! state.tracked_borrow_mut().value = 42;
+//! assert_eq!(state.tracked_borrow().value, 42);
+//! ```
+//!
+//! The borrows are recorded in a side table, so the state types and the `#[state]` fields of the
+//! canisters stay `Rc<RefCell<T>>`, and the feature can be enabled only for debug builds and
+//! tests. Tracked and untracked borrows of the same state can be mixed, but only the tracked ones
+//! are reported.
+//!
+//! The canister methods declared with the `ic-canister` macros are recorded automatically. The
+//! borrows made outside of them are reported without a method name.
+
+use std::cell::{Ref, RefCell, RefMut};
+use std::fmt;
+use std::ops::{Deref, DerefMut};
+use std::panic::Location;
+
+/// Borrowing of a `RefCell` that reports the locations of the conflicting borrows if the
+/// `track-borrows` feature is enabled.
+pub trait TrackedBorrow<T: ?Sized> {
+    /// Immutably borrows the value, see [`RefCell::borrow`].
+    ///
+    /// # Panics
+    ///
+    /// Panics if the value is currently mutably borrowed.
+    fn tracked_borrow(&self) -> TrackedRef<'_, T>;
+
+    /// Mutably borrows the value, see [`RefCell::borrow_mut`].
+    ///
+    /// # Panics
+    ///
+    /// Panics if the value is currently borrowed.
+    fn tracked_borrow_mut(&self) -> TrackedRefMut<'_, T>;
+}
+
+impl<T: ?Sized> TrackedBorrow<T> for RefCell<T> {
+    #[track_caller]
+    fn tracked_borrow(&self) -> TrackedRef<'_, T> {
+        let location = Location::caller();
+        match self.try_borrow() {
+            Ok(inner) => TrackedRef {
+                inner,
+                _record: Record::new(self, location, false),
+            },
+            Err(_) => conflict::<T>(self, location),
+        }
+    }
+
+    #[track_caller]
+    fn tracked_borrow_mut(&self) -> TrackedRefMut<'_, T> {
+        let location = Location::caller();
+        match self.try_borrow_mut() {
+            Ok(inner) => TrackedRefMut {
+                inner,
+                _record: Record::new(self, location, true),
+            },
+            Err(_) => conflict::<T>(self, location),
+        }
+    }
+}
+
+/// Immutable borrow returned by [`TrackedBorrow::tracked_borrow`].
+pub struct TrackedRef<'a, T: ?Sized> {
+    inner: Ref<'a, T>,
+    _record: Record,
+}
+
+impl<T: ?Sized> Deref for TrackedRef<'_, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        &self.inner
+    }
+}
+
+impl<T: ?Sized + fmt::Debug> fmt::Debug for TrackedRef<'_, T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        self.inner.fmt(f)
+    }
+}
+
+/// Mutable borrow returned by [`TrackedBorrow::tracked_borrow_mut`].
+pub struct TrackedRefMut<'a, T: ?Sized> {
+    inner: RefMut<'a, T>,
+    _record: Record,
+}
+
+impl<T: ?Sized> Deref for TrackedRefMut<'_, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        &self.inner
+    }
+}
+
+impl<T: ?Sized> DerefMut for TrackedRefMut<'_, T> {
+    fn deref_mut(&mut self) -> &mut T {
+        &mut self.inner
+    }
+}
+
+impl<T: ?Sized + fmt::Debug> fmt::Debug for TrackedRefMut<'_, T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        self.inner.fmt(f)
+    }
+}
+
+/// Sets the name of the canister method the following borrows are made in, until the scope is
+/// dropped. Used by the `ic-canister` macros.
+///
+/// The scope of an async method is held across its await points, so the testing `Scheduler`
+/// saves and restores the current method of each message with [`current_method`] and
+/// [`set_current_method`] when it switches between them.
+#[doc(hidden)]
+pub struct MethodScope {
+    #[cfg(feature = "track-borrows")]
+    previous: Option<&'static str>,
+}
+
+impl MethodScope {
+    pub fn enter(_method: &'static str) -> Self {
+        Self {
+            #[cfg(feature = "track-borrows")]
+            previous: tracking::CURRENT_METHOD.with(|current| current.replace(Some(_method))),
+        }
+    }
+}
+
+#[cfg(feature = "track-borrows")]
+impl Drop for MethodScope {
+    fn drop(&mut self) {
+        tracking::CURRENT_METHOD.with(|current| current.set(self.previous));
+    }
+}
+
+/// Returns the name of the canister method the borrows are currently recorded in.
+#[doc(hidden)]
+#[cfg(feature = "track-borrows")]
+pub fn current_method() -> Option<&'static str> {
+    tracking::CURRENT_METHOD.with(|current| current.get())
+}
+
+/// Sets the name of the canister method the following borrows are recorded in.
+#[doc(hidden)]
+#[cfg(feature = "track-borrows")]
+pub fn set_current_method(method: Option<&'static str>) {
+    tracking::CURRENT_METHOD.with(|current| current.set(method));
+}
+
+#[doc(hidden)]
+#[cfg(not(feature = "track-borrows"))]
+pub fn current_method() -> Option<&'static str> {
+    None
+}
+
+#[doc(hidden)]
+#[cfg(not(feature = "track-borrows"))]
+pub fn set_current_method(_method: Option<&'static str>) {}
+
+#[cfg(not(feature = "track-borrows"))]
+struct Record;
+
+#[cfg(not(feature = "track-borrows"))]
+impl Record {
+    fn new<T: ?Sized>(_cell: &RefCell<T>, _location: &'static Location<'static>, _: bool) -> Self {
+        Self
+    }
+}
+
+#[cfg(not(feature = "track-borrows"))]
+fn conflict<T: ?Sized>(cell: &RefCell<T>, _location: &'static Location<'static>) -> ! {
+    // Panics with the `RefCell` error.
+    drop(cell.borrow_mut());
+    unreachable!("the cell is borrowed")
+}
+
+#[cfg(feature = "track-borrows")]
+use tracking::{conflict, Record};
+
+#[cfg(feature = "track-borrows")]
+mod tracking {
+    use std::cell::{Cell, RefCell};
+    use std::collections::HashMap;
+    use std::fmt::Write;
+    use std::panic::Location;
+
+    thread_local! {
+        pub(super) static CURRENT_METHOD: Cell<Option<&'static str>> = Cell::new(None);
+        static BORROWS: RefCell<HashMap<usize, Vec<Borrow>>> = RefCell::new(HashMap::default());
+        static NEXT_ID: Cell<u64> = Cell::new(0);
+    }
+
+    struct Borrow {
+        id: u64,
+        location: &'static Location<'static>,
+        method: Option<&'static str>,
+        mutable: bool,
+    }
+
+    /// A borrow that is removed from the list of the active borrows when dropped.
+    pub(super) struct Record {
+        cell: usize,
+        id: u64,
+    }
+
+    impl Record {
+        pub(super) fn new<T: ?Sized>(
+            cell: &RefCell<T>,
+            location: &'static Location<'static>,
+            mutable: bool,
+        ) -> Self {
+            let cell = address(cell);
+            let id = NEXT_ID.with(|next| next.replace(next.get() + 1));
+            let method = CURRENT_METHOD.with(|current| current.get());
+
+            BORROWS.with(|borrows| {
+                borrows.borrow_mut().entry(cell).or_default().push(Borrow {
+                    id,
+                    location,
+                    method,
+                    mutable,
+                })
+            });
+
+            Self { cell, id }
+        }
+    }
+
+    impl Drop for Record {
+        fn drop(&mut self) {
+            BORROWS.with(|borrows| {
+                let mut borrows = borrows.borrow_mut();
+                if let Some(active) = borrows.get_mut(&self.cell) {
+                    active.retain(|borrow| borrow.id != self.id);
+                    if active.is_empty() {
+                        borrows.remove(&self.cell);
+                    }
+                }
+            });
+        }
+    }
+
+    pub(super) fn conflict<T: ?Sized>(
+        cell: &RefCell<T>,
+        location: &'static Location<'static>,
+    ) -> ! {
+        let mut message = format!("`{}` is already ", std::any::type_name::<T>());
+        BORROWS.with(|borrows| match borrows.borrow().get(&address(cell)) {
+            Some(active) if !active.is_empty() => {
+                for (index, borrow) in active.iter().enumerate() {
+                    if index > 0 {
+                        message.push_str(" and ");
+                    }
+
+                    let kind = if borrow.mutable { "mutably " } else { "" };
+                    let _ = write!(message, "{kind}borrowed at {}", borrow.location);
+                    write_method(&mut message, borrow.method);
+                }
+            }
+            _ => message.push_str("borrowed without tracking"),
+        });
+
+        let _ = write!(message, ", conflicting borrow at {location}");
+        write_method(&mut message, CURRENT_METHOD.with(|current| current.get()));
+
+        panic!("{}", message)
+    }
+
+    fn write_method(message: &mut String, method: Option<&str>) {
+        if let Some(method) = method {
+            let _ = write!(message, " in method `{method}`");
+        }
+    }
+
+    fn address<T: ?Sized>(cell: &RefCell<T>) -> usize {
+        cell as *const RefCell<T> as *const () as usize
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::panic::{catch_unwind, AssertUnwindSafe};
+    use std::rc::Rc;
+
+    #[derive(Default)]
+    struct State {
+        value: u32,
+    }
+
+    fn panic_message(f: impl FnOnce()) -> String {
+        let err = catch_unwind(AssertUnwindSafe(f)).unwrap_err();
+        match err.downcast::<String>() {
+            Ok(message) => *message,
+            Err(err) => err.downcast_ref::<&str>().unwrap().to_string(),
+        }
+    }
+
+    #[test]
+    fn tracked_borrows() {
+        let state = Rc::new(RefCell::new(State::default()));
+        state.tracked_borrow_mut().value = 1;
+
+        let first = state.tracked_borrow();
+        let second = state.tracked_borrow();
+        assert_eq!(first.value + second.value, 2);
+        drop((first, second));
+
+        state.tracked_borrow_mut().value += 1;
+        assert_eq!(state.borrow().value, 2);
+    }
+
+    #[test]
+    fn conflicting_borrow_panics() {
+        let state = RefCell::new(State::default());
+        let _held = state.tracked_borrow();
+        let message = panic_message(|| {
+            state.tracked_borrow_mut();
+        });
+        assert!(message.contains("already borrowed"));
+    }
+
+    #[cfg(feature = "track-borrows")]
+    #[test]
+    fn conflicting_borrow_reports_locations() {
+        let state = RefCell::new(State::default());
+
+        let _scope = MethodScope::enter("transfer");
+        let held = state.tracked_borrow_mut();
+        let held_line = line!() - 1;
+
+        let message = {
+            let _scope = MethodScope::enter("balance");
+            panic_message(|| {
+                state.tracked_borrow();
+            })
+        };
+
+        let this_file = file!();
+        assert!(message.contains("State` is already mutably borrowed at"));
+        assert!(message.contains(&format!("{this_file}:{held_line}:")));
+        assert!(message.contains("in method `transfer`, conflicting borrow at"));
+        assert!(message.ends_with("in method `balance`"));
+
+        drop(held);
+        state.tracked_borrow_mut().value = 1;
+    }
+
+    #[cfg(feature = "track-borrows")]
+    #[test]
+    fn untracked_borrow_is_reported() {
+        let state = RefCell::new(State::default());
+        let _held = state.borrow();
+
+        let message = panic_message(|| {
+            state.tracked_borrow_mut();
+        });
+        assert!(message.contains("borrowed without tracking, conflicting borrow at"));
+    }
+}
@@ -0,0 +1,305 @@
+//! Data structures stored directly in stable memory.
+//!
+//! Values stored with [`IcStorage`](crate::IcStorage) live in the heap and must be serialized as a
+//! whole on every upgrade. The collections in this module read and write stable memory in place
+//! instead, so they survive upgrades without any serialization and are not limited by the heap
+//! size:
+//!
+//! * [`StableVec`] - a vector of values that can be pushed, popped and replaced.
+//! * [`StableLog`] - an append-only log of values.
+//! * [`StableBTreeMap`] - an ordered map.
+//!
+//! A collection is a light handle to the data stored in stable memory
+//! [regions](crate::stable::regions) with the names derived from the collection name, so any
+//! number of handles with the same name can be created, and all of them will see the same data.
+//! The name of a collection must not be longer than [`MAX_NAME_LEN`] bytes.
+//!
+//! The values are serialized with candid. Space of the removed or replaced values is not always
+//! reclaimed.
+//!
+//! ```
+//! use ic_storage::collections::StableLog;
+//!
+//! let mut log = StableLog::<String>::new("events");
+//! log.append(&"created".to_string()).unwrap();
+//! log.append(&"updated".to_string()).unwrap();
+//!
+//! assert_eq!(log.len().unwrap(), 2);
+//! assert_eq!(log.get(1).unwrap().unwrap(), "updated");
+//! ```
+//!
+//! # Canister state
+//!
+//! The collections can be used as `#[state]` fields of a canister using the `Canister` derive
+//! macro. In this case the name of the field is used as the name of the collection, and the
+//! collection is not serialized by the generated upgrade methods:
+//!
+//! ```ignore
+//! #[derive(Clone, Canister)]
+//! struct MyCanister {
+//!     #[id]
+//!     principal: Principal,
+//!
+//!     #[state]
+//!     events: Rc<RefCell<StableLog<Event>>>,
+//! }
+//! ```
+//!
+//! The collection fields are recognized by the type name, so type aliases cannot be used for them.
+//! If a canister has collection fields, its other `#[state]` fields are stored in stable memory
+//! regions named after the fields on upgrade.
+//!
+//! In the testing environment all canister instances share the same mocked stable memory, so
+//! collections with the same name in different instances contain the same data.
+
+use std::marker::PhantomData;
+
+use candid::de::IDLDeserialize;
+use candid::ser::IDLBuilder;
+use candid::CandidType;
+use serde::Deserialize;
+
+use crate::stable::regions::{RawRegion, RawRegions, MAX_NAME_LEN as MAX_REGION_NAME_LEN};
+use crate::{Error, Result};
+
+mod btreemap;
+mod log;
+mod vec;
+
+pub use btreemap::{MapIter, StableBTreeMap};
+pub use log::StableLog;
+pub use vec::StableVec;
+
+/// Maximum length of a collection name in bytes.
+pub const MAX_NAME_LEN: usize = MAX_REGION_NAME_LEN - 2;
+
+/// Collection stored directly in stable memory, that can be used as a `#[state]` field of a
+/// canister.
+pub trait StableCollection {
+    /// Opens the collection with the given name.
+    fn open(name: &str) -> Self;
+}
+
+/// Iterator over the values of a [`StableVec`] or a [`StableLog`].
+pub struct Iter<T> {
+    entries: Entries,
+    index: u64,
+    len: Option<u64>,
+    _item: PhantomData<T>,
+}
+
+impl<T> Iter<T> {
+    fn new(entries: &Entries) -> Self {
+        Self {
+            entries: entries.clone(),
+            index: 0,
+            len: None,
+            _item: PhantomData,
+        }
+    }
+}
+
+impl<T: CandidType + for<'de> Deserialize<'de>> Iterator for Iter<T> {
+    type Item = Result<T>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let index = self.index;
+        let cached_len = self.len;
+        let entry = operation(|regions| {
+            let entries = self.entries.open(regions)?;
+            let len = cached_len.unwrap_or_else(|| entries.len(regions));
+            if index >= len {
+                return Ok((len, None));
+            }
+
+            Ok((len, Some(entries.get(regions, index)?)))
+        });
+
+        let bytes = match entry {
+            Ok((len, bytes)) => {
+                self.len = Some(len);
+                bytes?
+            }
+            Err(e) => {
+                self.len = Some(0);
+                return Some(Err(e));
+            }
+        };
+
+        self.index += 1;
+        Some(
+            bytes
+                .ok_or(Error::CorruptedLayout)
+                .and_then(|bytes| decode(&bytes)),
+        )
+    }
+}
+
+/// Size of an index record: offset (`u64`), length (`u32`) and capacity (`u32`) of an entry.
+const RECORD_SIZE: u64 = 16;
+
+/// Runs a collection operation with the region directory loaded once for the whole operation.
+/// The changes of the directory are stored when the operation succeeds.
+fn operation<R>(f: impl FnOnce(&mut RawRegions) -> Result<R>) -> Result<R> {
+    let mut regions = RawRegions::load()?;
+    let result = f(&mut regions)?;
+    regions.commit()?;
+    Ok(result)
+}
+
+/// Variable-size byte entries addressed by their index. The entries are stored in two raw
+/// regions: the index of the entries locations and the data of the entries.
+#[derive(Debug, Clone)]
+struct Entries {
+    index: String,
+    data: String,
+}
+
+impl Entries {
+    fn new(name: &str) -> Self {
+        Self {
+            index: format!("{name}.i"),
+            data: format!("{name}.d"),
+        }
+    }
+
+    /// Resolves the regions of the entries in the directory loaded for an operation.
+    fn open(&self, regions: &mut RawRegions) -> Result<OpenEntries> {
+        Ok(OpenEntries {
+            index: regions.open(&self.index)?,
+            data: regions.open(&self.data)?,
+        })
+    }
+}
+
+#[derive(Debug)]
+struct Record {
+    offset: u64,
+    len: u32,
+    capacity: u32,
+}
+
+/// [`Entries`] with their regions resolved in the directory loaded for one operation.
+#[derive(Debug, Clone, Copy)]
+struct OpenEntries {
+    index: RawRegion,
+    data: RawRegion,
+}
+
+impl OpenEntries {
+    fn len(&self, regions: &RawRegions) -> u64 {
+        regions.len(self.index) / RECORD_SIZE
+    }
+
+    fn get(&self, regions: &RawRegions, index: u64) -> Result<Option<Vec<u8>>> {
+        if index >= self.len(regions) {
+            return Ok(None);
+        }
+
+        let record = self.record(regions, index)?;
+        let mut bytes = vec![0; record.len as usize];
+        regions.read(self.data, record.offset, &mut bytes)?;
+
+        Ok(Some(bytes))
+    }
+
+    /// Adds an entry, reserving at least `capacity` bytes for it. Returns the index of the entry.
+    fn push(&self, regions: &mut RawRegions, bytes: &[u8], capacity: u64) -> Result<u64> {
+        let index = self.len(regions);
+        let record = self.append_data(regions, bytes, capacity)?;
+        self.set_record(regions, index, &record)?;
+
+        Ok(index)
+    }
+
+    /// Replaces the entry. The new value is written in place if it fits into the capacity of the
+    /// entry, otherwise it is moved to the end of the data.
+    fn set(&self, regions: &mut RawRegions, index: u64, bytes: &[u8]) -> Result<()> {
+        if index >= self.len(regions) {
+            return Err(Error::IndexOutOfBounds(index));
+        }
+
+        let mut record = self.record(regions, index)?;
+        if bytes.len() as u64 <= u64::from(record.capacity) {
+            regions.write(self.data, record.offset, bytes)?;
+            record.len = bytes.len() as u32;
+        } else {
+            record = self.append_data(regions, bytes, u64::from(record.capacity) * 2)?;
+        }
+
+        self.set_record(regions, index, &record)
+    }
+
+    fn pop(&self, regions: &mut RawRegions) -> Result<Option<Vec<u8>>> {
+        let len = self.len(regions);
+        if len == 0 {
+            return Ok(None);
+        }
+
+        let record = self.record(regions, len - 1)?;
+        let bytes = self.get(regions, len - 1)?;
+        regions.truncate(self.index, (len - 1) * RECORD_SIZE);
+
+        if record.offset + u64::from(record.capacity) == regions.len(self.data) {
+            regions.truncate(self.data, record.offset);
+        }
+
+        Ok(bytes)
+    }
+
+    fn clear(&self, regions: &mut RawRegions) {
+        regions.truncate(self.index, 0);
+        regions.truncate(self.data, 0);
+    }
+
+    /// Writes the entry to the end of the data. The capacity of an entry is stored as `u32`, so
+    /// it is limited to `u32::MAX` bytes, and larger entries cannot be stored.
+    fn append_data(&self, regions: &mut RawRegions, bytes: &[u8], capacity: u64) -> Result<Record> {
+        let len =
+            u32::try_from(bytes.len()).map_err(|_| Error::EntryTooLarge(bytes.len() as u64))?;
+        let capacity = capacity.clamp(u64::from(len), u64::from(u32::MAX)) as u32;
+
+        let mut padded = bytes.to_vec();
+        padded.resize(capacity as usize, 0);
+
+        let offset = regions.len(self.data);
+        regions.write(self.data, offset, &padded)?;
+
+        Ok(Record {
+            offset,
+            len,
+            capacity,
+        })
+    }
+
+    fn record(&self, regions: &RawRegions, index: u64) -> Result<Record> {
+        let mut buf = [0; RECORD_SIZE as usize];
+        regions.read(self.index, index * RECORD_SIZE, &mut buf)?;
+
+        Ok(Record {
+            offset: u64::from_le_bytes(buf[0..8].try_into().expect("slice has correct length")),
+            len: u32::from_le_bytes(buf[8..12].try_into().expect("slice has correct length")),
+            capacity: u32::from_le_bytes(buf[12..16].try_into().expect("slice has correct length")),
+        })
+    }
+
+    fn set_record(&self, regions: &mut RawRegions, index: u64, record: &Record) -> Result<()> {
+        let mut buf = [0; RECORD_SIZE as usize];
+        buf[0..8].copy_from_slice(&record.offset.to_le_bytes());
+        buf[8..12].copy_from_slice(&record.len.to_le_bytes());
+        buf[12..16].copy_from_slice(&record.capacity.to_le_bytes());
+
+        regions.write(self.index, index * RECORD_SIZE, &buf)
+    }
+}
+
+fn encode<T: CandidType>(value: &T) -> Result<Vec<u8>> {
+    let mut bytes = vec![];
+    IDLBuilder::new().arg(value)?.serialize(&mut bytes)?;
+    Ok(bytes)
+}
+
+fn decode<T: CandidType + for<'de> Deserialize<'de>>(bytes: &[u8]) -> Result<T> {
+    let mut de = IDLDeserialize::new(bytes)?;
+    Ok(de.get_value()?)
+}
@@ -0,0 +1,474 @@
+use std::marker::PhantomData;
+
+use candid::CandidType;
+use serde::Deserialize;
+
+use super::{decode, encode, operation, Entries, OpenEntries, StableCollection};
+use crate::stable::regions::{RawRegion, RawRegions};
+use crate::{Error, Result};
+
+/// Maximum number of keys in a node. When a node gets more keys, it is split in two.
+const MAX_KEYS: usize = 11;
+
+/// Id of the root node, the root is never moved.
+const ROOT: u64 = 0;
+
+/// A node of the tree: the key-value pairs and the ids of the child nodes. Leaf nodes have no
+/// children, and internal nodes have one child more than keys.
+type Node<K, V> = (Vec<(K, V)>, Vec<u64>);
+
+/// Result of an insertion into a subtree: the previous value of the key, and the median entry
+/// with the id of the new right sibling node if the node was split.
+type Inserted<K, V> = (Option<V>, Option<((K, V), u64)>);
+
+/// Iterators over the remaining entries and children of a node.
+type NodeIter<K, V> = (std::vec::IntoIter<(K, V)>, std::vec::IntoIter<u64>);
+
+/// An ordered map stored in stable memory as a B-tree.
+///
+/// Each node of the tree is stored as a separate entry, so only the nodes on the path from the
+/// root to the key are read or written by an operation. Nodes are not merged when keys are
+/// removed, so the space of the removed keys is not reclaimed.
+pub struct StableBTreeMap<K, V> {
+    nodes: Entries,
+    len: String,
+    _item: PhantomData<(K, V)>,
+}
+
+impl<K, V> StableBTreeMap<K, V>
+where
+    K: CandidType + for<'de> Deserialize<'de> + Ord,
+    V: CandidType + for<'de> Deserialize<'de>,
+{
+    /// Creates a handle to the map with the given name.
+    pub fn new(name: &str) -> Self {
+        Self {
+            nodes: Entries::new(name),
+            len: format!("{name}.l"),
+            _item: PhantomData,
+        }
+    }
+
+    /// Number of entries in the map.
+    pub fn len(&self) -> Result<u64> {
+        operation(|regions| self.open(regions)?.len())
+    }
+
+    /// Returns true if the map contains no entries.
+    pub fn is_empty(&self) -> Result<bool> {
+        Ok(self.len()? == 0)
+    }
+
+    /// Returns the value of the key.
+    pub fn get(&self, key: &K) -> Result<Option<V>> {
+        operation(|regions| self.open(regions)?.get(key))
+    }
+
+    /// Returns true if the map contains the key.
+    pub fn contains_key(&self, key: &K) -> Result<bool> {
+        Ok(self.get(key)?.is_some())
+    }
+
+    /// Inserts the value for the key, returning the previous value of the key.
+    pub fn insert(&mut self, key: K, value: V) -> Result<Option<V>> {
+        operation(|regions| self.open(regions)?.insert(key, value))
+    }
+
+    /// Removes the key from the map, returning its value.
+    pub fn remove(&mut self, key: &K) -> Result<Option<V>> {
+        operation(|regions| self.open(regions)?.remove(key))
+    }
+
+    /// Removes all entries from the map.
+    pub fn clear(&mut self) -> Result<()> {
+        operation(|regions| {
+            let mut tree = self.open(regions)?;
+            tree.nodes.clear(tree.regions);
+            tree.set_len(0)
+        })
+    }
+
+    /// Iterates over the entries of the map in the order of the keys.
+    pub fn iter(&self) -> MapIter<K, V> {
+        MapIter {
+            map: Self {
+                nodes: self.nodes.clone(),
+                len: self.len.clone(),
+                _item: PhantomData,
+            },
+            stack: vec![],
+            started: false,
+        }
+    }
+
+    /// Resolves the regions of the map in the directory loaded for an operation.
+    fn open<'a>(&self, regions: &'a mut RawRegions) -> Result<Tree<'a, K, V>> {
+        Ok(Tree {
+            nodes: self.nodes.open(regions)?,
+            len: regions.open(&self.len)?,
+            regions,
+            _item: PhantomData,
+        })
+    }
+}
+
+/// The nodes of a [`StableBTreeMap`] in the directory loaded for one operation.
+struct Tree<'a, K, V> {
+    regions: &'a mut RawRegions,
+    nodes: OpenEntries,
+    len: RawRegion,
+    _item: PhantomData<(K, V)>,
+}
+
+impl<K, V> Tree<'_, K, V>
+where
+    K: CandidType + for<'de> Deserialize<'de> + Ord,
+    V: CandidType + for<'de> Deserialize<'de>,
+{
+    fn len(&self) -> Result<u64> {
+        if self.regions.len(self.len) == 0 {
+            return Ok(0);
+        }
+
+        let mut buf = [0; 8];
+        self.regions.read(self.len, 0, &mut buf)?;
+        Ok(u64::from_le_bytes(buf))
+    }
+
+    fn is_empty(&self) -> bool {
+        self.nodes.len(self.regions) == 0
+    }
+
+    fn get(&self, key: &K) -> Result<Option<V>> {
+        if self.is_empty() {
+            return Ok(None);
+        }
+
+        let mut id = ROOT;
+        loop {
+            let (mut entries, children) = self.load(id)?;
+            match entries.binary_search_by(|(k, _)| k.cmp(key)) {
+                Ok(index) => return Ok(Some(entries.swap_remove(index).1)),
+                Err(_) if children.is_empty() => return Ok(None),
+                Err(index) => id = children[index],
+            }
+        }
+    }
+
+    fn insert(&mut self, key: K, value: V) -> Result<Option<V>> {
+        if self.is_empty() {
+            self.push_node(&(vec![(key, value)], vec![]))?;
+            self.set_len(1)?;
+            return Ok(None);
+        }
+
+        let (previous, split) = self.insert_into(ROOT, key, value)?;
+        if let Some((median, right)) = split {
+            // The root must stay at the same place, so its left half is moved to a new node.
+            let root = self.load(ROOT)?;
+            let left = self.push_node(&root)?;
+            self.store(ROOT, &(vec![median], vec![left, right]))?;
+        }
+
+        if previous.is_none() {
+            self.set_len(self.len()? + 1)?;
+        }
+
+        Ok(previous)
+    }
+
+    fn remove(&mut self, key: &K) -> Result<Option<V>> {
+        if self.is_empty() {
+            return Ok(None);
+        }
+
+        let removed = self.remove_from(ROOT, key)?;
+        if removed.is_some() {
+            self.set_len(self.len()? - 1)?;
+        }
+
+        Ok(removed)
+    }
+
+    fn insert_into(&mut self, id: u64, key: K, value: V) -> Result<Inserted<K, V>> {
+        let (mut entries, mut children) = self.load(id)?;
+        let index = match entries.binary_search_by(|(k, _)| k.cmp(&key)) {
+            Ok(index) => {
+                let previous = std::mem::replace(&mut entries[index].1, value);
+                self.store(id, &(entries, children))?;
+                return Ok((Some(previous), None));
+            }
+            Err(index) => index,
+        };
+
+        if children.is_empty() {
+            entries.insert(index, (key, value));
+        } else {
+            match self.insert_into(children[index], key, value)? {
+                (previous, None) => return Ok((previous, None)),
+                (_, Some((median, right))) => {
+                    entries.insert(index, median);
+                    children.insert(index + 1, right);
+                }
+            }
+        }
+
+        if entries.len() <= MAX_KEYS {
+            self.store(id, &(entries, children))?;
+            return Ok((None, None));
+        }
+
+        let mid = entries.len() / 2;
+        let right_entries = entries.split_off(mid + 1);
+        let median = entries.pop().expect("node is not empty");
+        let right_children = if children.is_empty() {
+            vec![]
+        } else {
+            children.split_off(mid + 1)
+        };
+
+        let right = self.push_node(&(right_entries, right_children))?;
+        self.store(id, &(entries, children))?;
+
+        Ok((None, Some((median, right))))
+    }
+
+    fn remove_from(&mut self, id: u64, key: &K) -> Result<Option<V>> {
+        let (mut entries, mut children) = self.load(id)?;
+        let index = match entries.binary_search_by(|(k, _)| k.cmp(key)) {
+            Ok(index) => index,
+            Err(_) if children.is_empty() => return Ok(None),
+            Err(index) => return self.remove_from(children[index], key),
+        };
+
+        let removed = if children.is_empty() {
+            entries.remove(index).1
+        } else {
+            // Replace the removed entry with the largest entry of its left subtree.
+            match self.pop_max(children[index])? {
+                Some(predecessor) => std::mem::replace(&mut entries[index], predecessor).1,
+                None => {
+                    children.remove(index);
+                    entries.remove(index).1
+                }
+            }
+        };
+
+        self.store(id, &(entries, children))?;
+        Ok(Some(removed))
+    }
+
+    /// Removes the largest entry of the subtree. Returns `Ok(None)` if the subtree is empty.
+    fn pop_max(&mut self, id: u64) -> Result<Option<(K, V)>> {
+        let (mut entries, mut children) = self.load(id)?;
+        if let Some(&last) = children.last() {
+            if let Some(max) = self.pop_max(last)? {
+                return Ok(Some(max));
+            }
+        }
+
+        // The rightmost subtree is empty, so the last entry of the node is the largest one.
+        let max = match entries.pop() {
+            Some(max) => max,
+            None => return Ok(None),
+        };
+
+        children.pop();
+        self.store(id, &(entries, children))?;
+        Ok(Some(max))
+    }
+
+    fn load(&self, id: u64) -> Result<Node<K, V>> {
+        let bytes = self
+            .nodes
+            .get(self.regions, id)?
+            .ok_or(Error::CorruptedLayout)?;
+        decode(&bytes)
+    }
+
+    fn store(&mut self, id: u64, node: &Node<K, V>) -> Result<()> {
+        self.nodes.set(self.regions, id, &encode(node)?)
+    }
+
+    /// Adds a new node, reserving space for the node to grow.
+    fn push_node(&mut self, node: &Node<K, V>) -> Result<u64> {
+        let bytes = encode(node)?;
+        self.nodes
+            .push(self.regions, &bytes, bytes.len() as u64 * 2)
+    }
+
+    fn set_len(&mut self, len: u64) -> Result<()> {
+        self.regions.write(self.len, 0, &len.to_le_bytes())
+    }
+}
+
+impl<K, V> StableCollection for StableBTreeMap<K, V>
+where
+    K: CandidType + for<'de> Deserialize<'de> + Ord,
+    V: CandidType + for<'de> Deserialize<'de>,
+{
+    fn open(name: &str) -> Self {
+        Self::new(name)
+    }
+}
+
+/// Iterator over the entries of a [`StableBTreeMap`] in the order of the keys.
+pub struct MapIter<K, V> {
+    map: StableBTreeMap<K, V>,
+    stack: Vec<NodeIter<K, V>>,
+    started: bool,
+}
+
+impl<K, V> MapIter<K, V>
+where
+    K: CandidType + for<'de> Deserialize<'de> + Ord,
+    V: CandidType + for<'de> Deserialize<'de>,
+{
+    fn next_entry(&mut self) -> Result<Option<(K, V)>> {
+        let map = &self.map;
+        let stack = &mut self.stack;
+        let started = &mut self.started;
+
+        // The nodes read by one step of the iteration are loaded in one operation.
+        operation(|regions| {
+            let tree = map.open(regions)?;
+            if !*started {
+                *started = true;
+                if !tree.is_empty() {
+                    push_leftmost(&tree, stack, ROOT)?;
+                }
+            }
+
+            while let Some((entries, children)) = stack.last_mut() {
+                match entries.next() {
+                    Some(entry) => {
+                        if let Some(child) = children.next() {
+                            push_leftmost(&tree, stack, child)?;
+                        }
+
+                        return Ok(Some(entry));
+                    }
+                    None => {
+                        stack.pop();
+                    }
+                }
+            }
+
+            Ok(None)
+        })
+    }
+}
+
+/// Pushes the nodes on the path from the given node to its leftmost leaf to the stack.
+fn push_leftmost<K, V>(
+    tree: &Tree<K, V>,
+    stack: &mut Vec<NodeIter<K, V>>,
+    mut id: u64,
+) -> Result<()>
+where
+    K: CandidType + for<'de> Deserialize<'de> + Ord,
+    V: CandidType + for<'de> Deserialize<'de>,
+{
+    loop {
+        let (entries, children) = tree.load(id)?;
+        let mut children = children.into_iter();
+        let first_child = children.next();
+        stack.push((entries.into_iter(), children));
+
+        match first_child {
+            Some(child) => id = child,
+            None => return Ok(()),
+        }
+    }
+}
+
+impl<K, V> Iterator for MapIter<K, V>
+where
+    K: CandidType + for<'de> Deserialize<'de> + Ord,
+    V: CandidType + for<'de> Deserialize<'de>,
+{
+    type Item = Result<(K, V)>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let entry = self.next_entry();
+        if entry.is_err() {
+            self.stack.clear();
+        }
+
+        entry.transpose()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::collections::BTreeMap;
+
+    #[test]
+    fn insert_get_and_remove() {
+        let mut map = StableBTreeMap::<String, u64>::new("map");
+        assert!(map.is_empty().unwrap());
+
+        assert_eq!(map.insert("a".into(), 1).unwrap(), None);
+        assert_eq!(map.insert("b".into(), 2).unwrap(), None);
+        assert_eq!(map.insert("a".into(), 3).unwrap(), Some(1));
+
+        assert_eq!(map.len().unwrap(), 2);
+        assert_eq!(map.get(&"a".into()).unwrap(), Some(3));
+        assert!(!map.contains_key(&"c".into()).unwrap());
+
+        assert_eq!(map.remove(&"a".into()).unwrap(), Some(3));
+        assert_eq!(map.remove(&"a".into()).unwrap(), None);
+        assert_eq!(map.len().unwrap(), 1);
+    }
+
+    #[test]
+    fn behaves_like_btreemap() {
+        let mut map = StableBTreeMap::<u64, u64>::new("map");
+        let mut expected = BTreeMap::new();
+
+        // A simple deterministic sequence of keys, so that the tree gets several levels and
+        // the keys are removed from both leaves and internal nodes.
+        let mut key = 7u64;
+        for i in 0..1000 {
+            key = (key * 31 + 17) % 499;
+            if i % 3 == 2 {
+                assert_eq!(map.remove(&key).unwrap(), expected.remove(&key));
+            } else {
+                assert_eq!(map.insert(key, i).unwrap(), expected.insert(key, i));
+            }
+        }
+
+        assert_eq!(map.len().unwrap(), expected.len() as u64);
+        for (key, value) in &expected {
+            assert_eq!(map.get(key).unwrap(), Some(*value));
+        }
+
+        let entries = map.iter().collect::<Result<Vec<_>>>().unwrap();
+        assert_eq!(entries, expected.into_iter().collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn remove_all_and_insert_again() {
+        let mut map = StableBTreeMap::<u64, ()>::new("map");
+        for key in 0..100 {
+            map.insert(key, ()).unwrap();
+        }
+
+        for key in 0..100 {
+            assert_eq!(map.remove(&key).unwrap(), Some(()));
+        }
+
+        assert!(map.is_empty().unwrap());
+        assert!(map.iter().next().is_none());
+
+        map.insert(5, ()).unwrap();
+        assert_eq!(
+            map.iter().collect::<Result<Vec<_>>>().unwrap(),
+            vec![(5, ())]
+        );
+
+        map.clear().unwrap();
+        assert!(map.get(&5).unwrap().is_none());
+    }
+}
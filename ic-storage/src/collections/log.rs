@@ -0,0 +1,91 @@
+use std::marker::PhantomData;
+
+use candid::CandidType;
+use serde::Deserialize;
+
+use super::{decode, encode, operation, Entries, Iter, StableCollection};
+use crate::Result;
+
+/// An append-only log of values stored in stable memory.
+pub struct StableLog<T> {
+    entries: Entries,
+    _item: PhantomData<T>,
+}
+
+impl<T: CandidType + for<'de> Deserialize<'de>> StableLog<T> {
+    /// Creates a handle to the log with the given name.
+    pub fn new(name: &str) -> Self {
+        Self {
+            entries: Entries::new(name),
+            _item: PhantomData,
+        }
+    }
+
+    /// Number of values in the log.
+    pub fn len(&self) -> Result<u64> {
+        operation(|regions| Ok(self.entries.open(regions)?.len(regions)))
+    }
+
+    /// Returns true if the log contains no values.
+    pub fn is_empty(&self) -> Result<bool> {
+        Ok(self.len()? == 0)
+    }
+
+    /// Returns the value with the given index, or `Ok(None)` if the index is out of bounds.
+    pub fn get(&self, index: u64) -> Result<Option<T>> {
+        operation(|regions| self.entries.open(regions)?.get(regions, index))?
+            .map(|bytes| decode(&bytes))
+            .transpose()
+    }
+
+    /// Appends the value to the log and returns its index.
+    pub fn append(&mut self, item: &T) -> Result<u64> {
+        let bytes = encode(item)?;
+        operation(|regions| {
+            let entries = self.entries.open(regions)?;
+            entries.push(regions, &bytes, bytes.len() as u64)
+        })
+    }
+
+    /// Iterates over the values of the log.
+    pub fn iter(&self) -> Iter<T> {
+        Iter::new(&self.entries)
+    }
+}
+
+impl<T: CandidType + for<'de> Deserialize<'de>> StableCollection for StableLog<T> {
+    fn open(name: &str) -> Self {
+        Self::new(name)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::stable::regions;
+
+    #[test]
+    fn append_and_read() {
+        let mut log = StableLog::<(u64, String)>::new("log");
+        for i in 0..100 {
+            assert_eq!(log.append(&(i, i.to_string())).unwrap(), i);
+        }
+
+        assert_eq!(log.len().unwrap(), 100);
+        assert_eq!(log.get(42).unwrap().unwrap(), (42, "42".to_string()));
+
+        let values = log.iter().map(|v| v.unwrap().0).collect::<Vec<_>>();
+        assert_eq!(values, (0..100).collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn log_coexists_with_regions() {
+        let mut log = StableLog::<u64>::new("log");
+        log.append(&1).unwrap();
+        regions::write("state", &()).unwrap();
+        log.append(&2).unwrap();
+
+        assert_eq!(log.iter().collect::<Result<Vec<_>>>().unwrap(), vec![1, 2]);
+        assert_eq!(regions::read::<()>("state").unwrap(), Some(()));
+    }
+}
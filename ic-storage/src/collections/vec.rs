@@ -0,0 +1,137 @@
+use std::marker::PhantomData;
+
+use candid::CandidType;
+use serde::Deserialize;
+
+use super::{decode, encode, operation, Entries, Iter, StableCollection};
+use crate::Result;
+
+/// A vector of values stored in stable memory.
+///
+/// A replaced value is written in place if it is not larger than the previous one, otherwise it
+/// is moved to the end of the data.
+pub struct StableVec<T> {
+    entries: Entries,
+    _item: PhantomData<T>,
+}
+
+impl<T: CandidType + for<'de> Deserialize<'de>> StableVec<T> {
+    /// Creates a handle to the vector with the given name.
+    pub fn new(name: &str) -> Self {
+        Self {
+            entries: Entries::new(name),
+            _item: PhantomData,
+        }
+    }
+
+    /// Number of values in the vector.
+    pub fn len(&self) -> Result<u64> {
+        operation(|regions| Ok(self.entries.open(regions)?.len(regions)))
+    }
+
+    /// Returns true if the vector contains no values.
+    pub fn is_empty(&self) -> Result<bool> {
+        Ok(self.len()? == 0)
+    }
+
+    /// Returns the value with the given index, or `Ok(None)` if the index is out of bounds.
+    pub fn get(&self, index: u64) -> Result<Option<T>> {
+        operation(|regions| self.entries.open(regions)?.get(regions, index))?
+            .map(|bytes| decode(&bytes))
+            .transpose()
+    }
+
+    /// Appends the value to the end of the vector.
+    pub fn push(&mut self, item: &T) -> Result<()> {
+        let bytes = encode(item)?;
+        operation(|regions| {
+            let entries = self.entries.open(regions)?;
+            entries.push(regions, &bytes, bytes.len() as u64)
+        })?;
+        Ok(())
+    }
+
+    /// Replaces the value with the given index.
+    pub fn set(&mut self, index: u64, item: &T) -> Result<()> {
+        let bytes = encode(item)?;
+        operation(|regions| self.entries.open(regions)?.set(regions, index, &bytes))
+    }
+
+    /// Removes the last value from the vector and returns it.
+    pub fn pop(&mut self) -> Result<Option<T>> {
+        operation(|regions| self.entries.open(regions)?.pop(regions))?
+            .map(|bytes| decode(&bytes))
+            .transpose()
+    }
+
+    /// Removes all values from the vector.
+    pub fn clear(&mut self) -> Result<()> {
+        operation(|regions| {
+            self.entries.open(regions)?.clear(regions);
+            Ok(())
+        })
+    }
+
+    /// Iterates over the values of the vector.
+    pub fn iter(&self) -> Iter<T> {
+        Iter::new(&self.entries)
+    }
+}
+
+impl<T: CandidType + for<'de> Deserialize<'de>> StableCollection for StableVec<T> {
+    fn open(name: &str) -> Self {
+        Self::new(name)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::Error;
+
+    #[test]
+    fn push_get_and_pop() {
+        let mut vec = StableVec::<String>::new("vec");
+        assert!(vec.is_empty().unwrap());
+
+        vec.push(&"first".into()).unwrap();
+        vec.push(&"second".into()).unwrap();
+
+        assert_eq!(vec.len().unwrap(), 2);
+        assert_eq!(vec.get(0).unwrap().unwrap(), "first");
+        assert!(vec.get(2).unwrap().is_none());
+
+        assert_eq!(vec.pop().unwrap().unwrap(), "second");
+        assert_eq!(vec.pop().unwrap().unwrap(), "first");
+        assert!(vec.pop().unwrap().is_none());
+    }
+
+    #[test]
+    fn set_values() {
+        let mut vec = StableVec::<String>::new("vec");
+        vec.push(&"first".into()).unwrap();
+        vec.push(&"second".into()).unwrap();
+
+        vec.set(0, &"a".into()).unwrap();
+        vec.set(1, &"a much longer value".into()).unwrap();
+
+        let values = vec.iter().collect::<Result<Vec<_>>>().unwrap();
+        assert_eq!(values, vec!["a", "a much longer value"]);
+
+        let err = vec.set(2, &"third".into()).unwrap_err();
+        assert!(matches!(err, Error::IndexOutOfBounds(2)));
+    }
+
+    #[test]
+    fn handles_share_data() {
+        let mut vec = StableVec::<u64>::new("vec");
+        let other = StableVec::<u64>::new("other");
+        vec.push(&1).unwrap();
+
+        assert_eq!(StableVec::<u64>::new("vec").get(0).unwrap(), Some(1));
+        assert!(other.is_empty().unwrap());
+
+        vec.clear().unwrap();
+        assert!(StableVec::<u64>::new("vec").is_empty().unwrap());
+    }
+}
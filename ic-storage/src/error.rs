@@ -18,8 +18,65 @@ pub enum Error {
     #[error("candid error: {0}")]
     Candid(#[from] ic_cdk::export::candid::Error),
 
+    #[error("serialization error: {0}")]
+    Serialization(String),
+
+    #[error("version {0} does not support downgrading")]
+    DowngradeNotSupported(u32),
+
+    #[error("version {0} is newer than the downgraded value")]
+    InvalidVersion(u32),
+
     #[error("existing version is newer")]
     ExistingVersionIsNewer,
+
+    #[error("stable memory contains data in an unexpected layout")]
+    UnexpectedLayout,
+
+    #[error("stable memory layout is corrupted")]
+    CorruptedLayout,
+
+    #[error("checksum of the data in stable memory does not match")]
+    ChecksumMismatch,
+
+    #[error("invalid stable memory region name: {0}")]
+    InvalidRegionName(String),
+
+    #[error("stable memory region directory is full")]
+    DirectoryFull,
+
+    #[error("chunked data in stable memory was not finished")]
+    UnfinishedChunks,
+
+    #[error("index {0} is out of bounds")]
+    IndexOutOfBounds(u64),
+
+    #[error("entry of {0} bytes is too large to be stored")]
+    EntryTooLarge(u64),
+
+    #[error("failed to upgrade from version {from} to version {to}: {source}")]
+    Migration {
+        from: u32,
+        to: u32,
+        source: MigrationError,
+    },
+}
+
+/// Error returned by [`Versioned::try_upgrade`](crate::stable::Versioned::try_upgrade) when the
+/// previous version of the data cannot be migrated.
+///
+/// The message describes why the value cannot be upgraded. The versions between which the
+/// upgrade failed are added by [`Error::Migration`], which wraps this error when the value is
+/// read from stable storage.
+#[derive(Debug, Error)]
+#[error("{0}")]
+pub struct MigrationError(pub String);
+
+impl MigrationError {
+    /// Creates a migration error with the given message.
+    pub fn new(message: impl Into<String>) -> Self {
+        Self(message.into())
+    }
 }
 
 // Required because `StableMemoryError` doesn't implement Debug
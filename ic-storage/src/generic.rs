@@ -0,0 +1,62 @@
+//! Storage of the generic types deriving [`IcStorage`](crate::IcStorage).
+//!
+//! A `thread_local` static cannot depend on the generic parameters of the function it is declared
+//! in, so the derive macro cannot declare a separate static for each concrete type. Instead, the
+//! states of all the generic types are kept in a single registry indexed by their [`TypeId`].
+
+use std::any::{Any, TypeId};
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::rc::Rc;
+
+#[cfg(not(target_arch = "wasm32"))]
+use ic_cdk::export::Principal;
+
+#[cfg(target_arch = "wasm32")]
+type Key = TypeId;
+
+// Each canister instance has its own states in tests.
+#[cfg(not(target_arch = "wasm32"))]
+type Key = (Principal, TypeId);
+
+thread_local! {
+    static REGISTRY: RefCell<HashMap<Key, Rc<dyn Any>>> = RefCell::new(HashMap::default());
+}
+
+/// Returns the state of the type `T`, creating it with the default value on the first call.
+#[cfg(target_arch = "wasm32")]
+pub fn get<T: Default + 'static>() -> Rc<RefCell<T>> {
+    get_by_key(TypeId::of::<T>(), |_| {})
+}
+
+/// Returns the state of the type `T` of the canister with the given id, creating it with the
+/// default value on the first call.
+#[cfg(not(target_arch = "wasm32"))]
+pub fn get<T: Default + 'static>(id: Principal) -> Rc<RefCell<T>> {
+    // The way to copy the value cannot be selected for a generic type, so it can only be reset.
+    get_by_key((id, TypeId::of::<T>()), |state| {
+        crate::testing::register_state(id, state, None)
+    })
+}
+
+fn get_by_key<T: Default + 'static>(
+    key: Key,
+    on_create: impl FnOnce(&Rc<RefCell<T>>),
+) -> Rc<RefCell<T>> {
+    // The state must not be created while the registry is borrowed, as `T::default()` can get
+    // the states of other types.
+    let stored = REGISTRY.with(|registry| registry.borrow().get(&key).cloned());
+    let state = match stored {
+        Some(state) => state,
+        None => {
+            let state = Rc::new(RefCell::new(T::default()));
+            on_create(&state);
+            REGISTRY.with(|registry| registry.borrow_mut().insert(key, state.clone()));
+            state as Rc<dyn Any>
+        }
+    };
+
+    state
+        .downcast::<RefCell<T>>()
+        .expect("the state is stored under the id of its type")
+}
@@ -1,7 +1,8 @@
 //! This crate provides a safe way to use canister state, as well as versioned storage.
 //!
 //! * For in memory storage use [`IcStorage`]. Structs that derive [`IcStorage`] must also implement `std::fmt::Default`.
-//! * For versioned storage see [`crate::stable`].
+//! * For versioned storage see [`crate::stable`]. Versioned types can be declared with the
+//!   [`Versioned`](crate::stable::Versioned) derive macro.
 //!
 //! ```
 //! # ic_canister::ic_kit::MockContext::new().inject();
@@ -18,9 +19,30 @@
 //! assert_eq!(local_state.borrow().value, 42);
 //! ```
 //!
-//! Unfortunately, you cannot use the derive macro to create storage for generic types, as there is
-//! no way to know in advance, which concrete types are going to be stored. Instead, you can use
-//! `generic_derive!` macro for them:
+//! The derive macro can also be used for generic types. Each concrete type gets its own state:
+//!
+//! ```
+//! # ic_canister::ic_kit::MockContext::new().inject();
+//! use ic_storage::IcStorage;
+//! use std::collections::HashMap;
+//!
+//! #[derive(IcStorage, Default)]
+//! struct Cache<K, V> {
+//!     values: HashMap<K, V>,
+//! }
+//!
+//! Cache::<u32, String>::get().borrow_mut().values.insert(1, "one".into());
+//!
+//! assert_eq!(Cache::<u32, String>::get().borrow().values.len(), 1);
+//! assert!(Cache::<String, u64>::get().borrow().values.is_empty());
+//! ```
+//!
+//! The type parameters of such types must be `'static`. The states of generic types are looked up
+//! by their `TypeId` on every call to `get()`, so getting them is a little slower than getting the
+//! states of non-generic types.
+//!
+//! The `generic_derive!` macro, that implements `IcStorage` for a single concrete type, can still
+//! be used for the types that don't derive it:
 //!
 //! ```
 //! # ic_canister::ic_kit::MockContext::new().inject();
@@ -41,7 +63,9 @@
 //!
 //! `IcStorage` derive macro uses `RefCell` to control the access to the state struct, so all the
 //! borrow checks of `RefCell` apply to using `IcStorage` (e.g. trying to call `state.borrow_mut()`
-//! when there is another borrow of the same type in scope will produce runtime panic).
+//! when there is another borrow of the same type in scope will produce runtime panic). To find out
+//! where the conflicting borrows are made, borrow the state with [`borrow::TrackedBorrow`] and
+//! enable the `track-borrows` feature.
 //!
 //! *IMPORTANT*: `IcStorage` only provides local canister state storage. It DOES NOT in any way
 //! related to the stable storage. See [`crate::stable`] for stable storage.
@@ -72,7 +96,8 @@
 //!    stable storage would require that everything that should be saved is passed as a single data
 //!    type (e.g a tuple or a custom struct), as writing to stable storage overwrites what is
 //!    currently there (meaning if we write one struct and then another, the second would overwrite
-//!    the first).
+//!    the first). Alternatively, each part of the state can be stored in its own named region of
+//!    the stable storage using `stable::regions`.
 //!
 //! # Testing
 //!
@@ -85,11 +110,19 @@
 use std::cell::RefCell;
 use std::rc::Rc;
 
+// Allows the derive macros to refer to this crate as `::ic_storage` inside of it.
+extern crate self as ic_storage;
+
 pub use ic_storage_derive::IcStorage;
 
+pub mod borrow;
+pub mod collections;
 pub mod error;
+#[doc(hidden)]
+pub mod generic;
+pub mod migration;
 pub mod stable;
-pub use error::{Error, Result};
+pub use error::{Error, MigrationError, Result};
 // #[cfg(test)]
 pub mod testing;
 
@@ -104,7 +137,7 @@ pub trait IcStorage {
 macro_rules! generic_derive {
     ($storage:ty) => {
         #[cfg(target_arch = "wasm32")]
-        impl ::ic_cansiter::storage::IcStorage for $storage {
+        impl ::ic_canister::storage::IcStorage for $storage {
             fn get() -> ::std::rc::Rc<::std::cell::RefCell<Self>> {
                 use ::std::rc::Rc;
                 use ::std::cell::RefCell;
@@ -132,7 +165,17 @@ macro_rules! generic_derive {
                 let id = ::ic_canister::ic_kit::ic::id();
                 store.with(|v| {
                     let mut borrowed_store = v.borrow_mut();
-                    (*borrowed_store.entry(id).or_default()).clone()
+                    let state = borrowed_store.entry(id).or_insert_with(|| {
+                        #[allow(unused_imports)]
+                        use ::ic_canister::storage::testing::{CopyByCandid, CopyByClone, CopyUnsupported};
+
+                        let state = Rc::new(RefCell::new(<$storage>::default()));
+                        let copier = (&&&::ic_canister::storage::testing::CopyProbe::<$storage>::new()).copier();
+                        ::ic_canister::storage::testing::register_state(id, &state, copier);
+                        state
+                    });
+
+                    state.clone()
                 })
             }
         }
@@ -0,0 +1,255 @@
+//! Migration of large states in batches across multiple calls.
+//!
+//! [`stable::read`](crate::stable::read) upgrades the whole state at once inside `post_upgrade`,
+//! which can exceed the instruction limit of a single call for large states. Instead, the state
+//! can be stored as a sequence of records with [`ChunkedWriter`](crate::stable::ChunkedWriter)
+//! before the upgrade, and migrated in batches after it:
+//!
+//! * [`start`] is called in `post_upgrade` and puts the canister into the migrating mode.
+//! * [`run_batch`] is called in the following update calls or heartbeats. It upgrades the next
+//!   batch of records to the current version and passes them to the given function, that stores
+//!   them in the canister state. When all the records are migrated, the migrating mode ends.
+//! * [`is_migrating`] can be checked in `PreUpdate` hooks to reject the calls that need the whole
+//!   state until the migration is completed.
+//!
+//! A record that cannot be decoded or upgraded stops the migration at that record: every
+//! following [`run_batch`] returns the same error. [`skip_current`] drops the failed record and
+//! lets the migration continue with the next one.
+//!
+//! The records that are not migrated yet stay in stable memory, so nothing else must be written
+//! to stable memory while the migration is in progress.
+//!
+//! The progress of the migration is kept on the heap, so it is lost when the canister is upgraded
+//! again before the migration is completed. Writing the state in `pre_upgrade` of such an upgrade
+//! overwrites the records that were not migrated yet, and they are lost. Call [`finish`] in
+//! `pre_upgrade` first, so that all the records are in the canister state before it is written.
+//!
+//! ```
+//! use ic_storage::migration::{self, MigrationStatus};
+//! use ic_storage::stable::{ChunkedWriter, Versioned};
+//! # use ic_cdk::export::candid::CandidType;
+//! # use serde::Deserialize;
+//!
+//! #[derive(Debug, Default, Deserialize, CandidType, Versioned)]
+//! struct Account {
+//!     balance: u64,
+//! }
+//!
+//! // #[pre_upgrade] of the previous version of the canister
+//! let mut writer = ChunkedWriter::<Account>::new().unwrap();
+//! for balance in 0..10 {
+//!     writer.push(&Account { balance }).unwrap();
+//! }
+//! writer.finish().unwrap();
+//!
+//! // #[post_upgrade]
+//! migration::start::<Account>().unwrap();
+//! assert!(migration::is_migrating());
+//!
+//! // #[heartbeat] or #[update]
+//! let mut accounts = vec![];
+//! let status = migration::run_batch::<Account>(4, |account| accounts.push(account)).unwrap();
+//! assert_eq!(status, MigrationStatus::InProgress { remaining: 6 });
+//!
+//! migration::finish::<Account>(|account| accounts.push(account)).unwrap();
+//! assert!(!migration::is_migrating());
+//! assert_eq!(accounts.len(), 10);
+//! ```
+//!
+//! In the testing environment all canister instances share the same migration state.
+
+use std::cell::Cell;
+
+use crate::stable::{ChunkCursor, ChunkedReader, Versioned};
+use crate::Result;
+
+thread_local! {
+    static CURSOR: Cell<Option<ChunkCursor>> = Cell::new(None);
+}
+
+/// Progress of the migration.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MigrationStatus {
+    /// Some records are not migrated yet.
+    InProgress {
+        /// Number of records that are left to migrate.
+        remaining: u64,
+    },
+    /// All records are migrated.
+    Completed,
+}
+
+/// Starts the migration of the records stored with
+/// [`ChunkedWriter`](crate::stable::ChunkedWriter), putting the canister into the migrating mode
+/// if there are any records.
+pub fn start<T: Versioned>() -> Result<MigrationStatus> {
+    let reader = ChunkedReader::<T>::new()?;
+    Ok(save_progress(&reader))
+}
+
+/// Returns true if the migration was started and is not completed yet.
+pub fn is_migrating() -> bool {
+    CURSOR.with(|cursor| cursor.get().is_some())
+}
+
+/// Upgrades at most `max_records` of the records that are not migrated yet to the version `T`,
+/// and passes them to `f` in the order they were stored.
+///
+/// If an error occurs, the records passed to `f` before the error are considered migrated, and the
+/// migration can be continued from the failed record, or from the record after it with
+/// [`skip_current`].
+pub fn run_batch<T: Versioned>(max_records: u64, mut f: impl FnMut(T)) -> Result<MigrationStatus> {
+    let cursor = match CURSOR.with(|cursor| cursor.get()) {
+        Some(cursor) => cursor,
+        None => return Ok(MigrationStatus::Completed),
+    };
+
+    let mut reader = ChunkedReader::<T>::resume(cursor)?;
+    for _ in 0..max_records {
+        match reader.next_item()? {
+            Some(record) => {
+                save_progress(&reader);
+                f(record);
+            }
+            None => break,
+        }
+    }
+
+    Ok(save_progress(&reader))
+}
+
+/// Skips the record the migration stopped at, for example because it cannot be upgraded to the
+/// version `T`. The record is not passed to the canister state, so it is lost after the migration.
+pub fn skip_current<T: Versioned>() -> Result<MigrationStatus> {
+    let cursor = match CURSOR.with(|cursor| cursor.get()) {
+        Some(cursor) => cursor,
+        None => return Ok(MigrationStatus::Completed),
+    };
+
+    let mut reader = ChunkedReader::<T>::resume(cursor)?;
+    reader.skip_item()?;
+
+    Ok(save_progress(&reader))
+}
+
+/// Migrates all the records that are not migrated yet, passing them to `f`.
+pub fn finish<T: Versioned>(f: impl FnMut(T)) -> Result<()> {
+    run_batch(u64::MAX, f)?;
+    Ok(())
+}
+
+fn save_progress<T: Versioned>(reader: &ChunkedReader<T>) -> MigrationStatus {
+    let remaining = reader.remaining();
+    let cursor = if remaining > 0 {
+        Some(reader.cursor())
+    } else {
+        None
+    };
+    CURSOR.with(|c| c.set(cursor));
+
+    match remaining {
+        0 => MigrationStatus::Completed,
+        remaining => MigrationStatus::InProgress { remaining },
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::stable::ChunkedWriter;
+    use crate::{Error, MigrationError};
+    use candid::CandidType;
+    use serde::Deserialize;
+
+    #[derive(Debug, CandidType, Deserialize)]
+    struct Record(u32);
+
+    #[derive(Debug, CandidType, Deserialize)]
+    struct RecordV2(u64);
+
+    impl Versioned for Record {
+        type Previous = ();
+
+        fn upgrade(_: Self::Previous) -> Self {
+            Self(0)
+        }
+    }
+
+    impl Versioned for RecordV2 {
+        type Previous = Record;
+
+        fn upgrade(previous: Self::Previous) -> Self {
+            Self(previous.0 as u64 * 10)
+        }
+
+        fn try_upgrade(previous: Self::Previous) -> std::result::Result<Self, MigrationError> {
+            match previous.0 {
+                13 => Err(MigrationError::new("unlucky record")),
+                _ => Ok(Self::upgrade(previous)),
+            }
+        }
+    }
+
+    fn write_records(records: impl IntoIterator<Item = u32>) {
+        let mut writer = ChunkedWriter::<Record>::new().unwrap();
+        for record in records {
+            writer.push(&Record(record)).unwrap();
+        }
+        writer.finish().unwrap();
+    }
+
+    #[test]
+    fn migrate_in_batches() {
+        write_records(0..5);
+
+        assert_eq!(
+            start::<RecordV2>().unwrap(),
+            MigrationStatus::InProgress { remaining: 5 }
+        );
+        assert!(is_migrating());
+
+        let mut migrated = vec![];
+        let status = run_batch::<RecordV2>(2, |record| migrated.push(record.0)).unwrap();
+        assert_eq!(status, MigrationStatus::InProgress { remaining: 3 });
+        assert!(is_migrating());
+
+        let status = run_batch::<RecordV2>(3, |record| migrated.push(record.0)).unwrap();
+        assert_eq!(status, MigrationStatus::Completed);
+        assert!(!is_migrating());
+        assert_eq!(migrated, vec![0, 10, 20, 30, 40]);
+
+        let status = run_batch::<RecordV2>(3, |_| panic!("nothing to migrate")).unwrap();
+        assert_eq!(status, MigrationStatus::Completed);
+    }
+
+    #[test]
+    fn nothing_to_migrate() {
+        write_records([]);
+
+        assert_eq!(start::<RecordV2>().unwrap(), MigrationStatus::Completed);
+        assert!(!is_migrating());
+    }
+
+    #[test]
+    fn continue_after_failed_record() {
+        write_records(11..16);
+        start::<RecordV2>().unwrap();
+
+        let mut migrated = vec![];
+        let err = run_batch::<RecordV2>(5, |record| migrated.push(record.0)).unwrap_err();
+        assert!(matches!(err, Error::Migration { from: 1, to: 2, .. }));
+        assert_eq!(migrated, vec![110, 120]);
+        assert!(is_migrating());
+
+        let err = finish::<RecordV2>(|record| migrated.push(record.0)).unwrap_err();
+        assert!(matches!(err, Error::Migration { .. }));
+        assert_eq!(migrated, vec![110, 120]);
+
+        let status = skip_current::<RecordV2>().unwrap();
+        assert_eq!(status, MigrationStatus::InProgress { remaining: 2 });
+
+        finish::<RecordV2>(|record| migrated.push(record.0)).unwrap();
+        assert_eq!(migrated, vec![110, 120, 140, 150]);
+        assert!(!is_migrating());
+    }
+}
@@ -1,8 +1,9 @@
 #![deny(missing_docs)]
 //! This module provides versioned data for stable storage.
 //!
-//! **IMPORTANT**: do note that it's not possible to store more than one type (and one instance of that type)
-//! in stable storage. Any subsequent writes will overwrite what is currently stored.
+//! **IMPORTANT**: do note that [`write`] can store only one type (and one instance of that type)
+//! in stable storage. Any subsequent writes will overwrite what is currently stored. To store
+//! several values at once use [`Sections`].
 //!
 //! This library makes it possible to change the type that is serialized and written to stable storage.
 //!
@@ -14,15 +15,23 @@
 //! using these functions, the struct needs to implement the [`Versioned`] trait
 //! (which in turn requires [`Deserialize`] and [`CandidType`])
 //!
-//! The first four bytes written is the version number as a [`u32`],
-//! the remaining bytes represent the struct.
+//! The value is written after a header, that contains a marker, the version number, the length
+//! of the serialized value, its CRC-32 checksum and the id of the [`Codec`] it was serialized with:
 //! ```text
-//!  0 1 2 3 ...
-//! +-+-+-+-+-+-+-+-+-+
-//! |V|E|R|S|  Struct |
-//! +-+-+-+-+-+-+-+-+-+
+//!  0 1 2 3 4 5 6 7 8 ... 15 16 ... 19 20    21 ... 23 24 ...
+//! +-+-+-+-+-+-+-+-+--------+---------+-----+--------+--------+
+//! |MARKER |VERSION|  LEN   |CHECKSUM |CODEC|reserved| Struct |
+//! +-+-+-+-+-+-+-+-+--------+---------+-----+--------+--------+
 //! ```
 //!
+//! All numbers are little-endian. [`read`] verifies the length and the checksum before decoding
+//! the value, so data that was only partially written or was corrupted is rejected with
+//! [`Error::ChecksumMismatch`](crate::Error::ChecksumMismatch) or
+//! [`Error::CorruptedLayout`](crate::Error::CorruptedLayout).
+//!
+//! Values written by the older versions of this library, that consist of the version number
+//! followed by the serialized struct, can still be read.
+//!
 //! ## Examples
 //!
 //! ### Creating a versioned struct
@@ -58,6 +67,68 @@
 //! }
 //! ```
 //!
+//! ### Deriving `Versioned`
+//!
+//! Such chains can also be declared with the `Versioned` derive macro:
+//!
+//! ```
+//! use ic_storage::stable::Versioned;
+//! # use ic_cdk::export::candid::CandidType;
+//! # use serde::Deserialize;
+//!
+//! #[derive(Debug, Default, Deserialize, CandidType, Versioned)]
+//! struct First(usize, usize);
+//!
+//! #[derive(Debug, Deserialize, CandidType, Versioned)]
+//! #[versioned(previous = First, upgrade = Second::from_first, version = 3)]
+//! struct Second(String);
+//!
+//! impl Second {
+//!     fn from_first(previous: First) -> Self {
+//!         Second(format!("{}, {}", previous.0, previous.1))
+//!     }
+//! }
+//!
+//! assert_eq!(First::version(), 1);
+//! assert_eq!(Second::version(), 3);
+//! ```
+//!
+//! The `versioned` attribute takes the following optional arguments:
+//!
+//! * `previous` - the previous version of the type, `()` by default. The previous type must also
+//!   derive `Versioned`, so that the whole chain can be checked at compile time.
+//! * `upgrade` - function that upgrades the previous version to this one. If not specified, the
+//!   first version is created with [`Default`], and other versions with [`From`] the previous one.
+//! * `try_upgrade` - fallible function that upgrades the previous version to this one, see
+//!   [`Versioned::try_upgrade`]. Cannot be used together with `upgrade`.
+//! * `downgrade` - function that downgrades this version to the previous one, see
+//!   [`Versioned::downgrade`].
+//! * `version` - the version number, the version of the previous type plus one by default.
+//! * `codec` - the [`Codec`] used to serialize the type, `Candid` by default. The `Cbor` and
+//!   `Binary` codecs require the type to implement [`serde::Serialize`].
+//!
+//! The version numbers must be strictly increasing along the chain, otherwise the code does not
+//! compile:
+//!
+//! ```compile_fail
+//! use ic_storage::stable::Versioned;
+//! # use ic_cdk::export::candid::CandidType;
+//! # use serde::Deserialize;
+//!
+//! #[derive(Default, Deserialize, CandidType, Versioned)]
+//! #[versioned(version = 2)]
+//! struct First(usize);
+//!
+//! #[derive(Deserialize, CandidType, Versioned)]
+//! #[versioned(previous = First, upgrade = Second::from_first, version = 2)]
+//! struct Second(usize);
+//! # impl Second {
+//! #     fn from_first(previous: First) -> Self {
+//! #         Self(previous.0)
+//! #     }
+//! # }
+//! ```
+//!
 //! ## Reading
 //!
 //! Read the latest implementation from stable memory.
@@ -118,23 +189,175 @@
 //!     write(&first).unwrap();
 //! }
 //! ```
+//!
+//! ## Atomic writes
+//!
+//! [`write`] overwrites the stored value in place, so if writing is interrupted, stable memory
+//! contains a part of the old value and a part of the new one. [`write_atomic`] keeps two slots
+//! for the value instead, and writes the new value to the slot that is not in use, switching to it
+//! only after the value is written completely. This way [`read`] always returns either the old or
+//! the new value. Once a value is written atomically, all the following [`write`] calls are atomic
+//! as well.
+//!
+//! ```text
+//!  0 1 2 3 4 5 6 7 8 ... 15 16 ... 23 24 ...
+//! +-+-+-+-+-+-+-+-+--------+---------+-----------------+
+//! |MARKER |ACTIVE |OFFSET 0|OFFSET 1 | Slots ...       |
+//! +-+-+-+-+-+-+-+-+--------+---------+-----------------+
+//! ```
+//!
+//! Each slot contains a value with a header, as written by [`write`]. The slots take turns in
+//! the same space, so atomic writes take at most about twice as much memory as the value itself.
+//!
+//! ## Downgrading
+//!
+//! [`read`] and [`write`] never go back to an older version. To roll a canister back to the
+//! previous version of its code, implement [`Versioned::downgrade`] and write the state with
+//! [`write_downgraded`] before the upgrade. The older code can then read it as usual.
+//!
+//! ```
+//! use ic_storage::stable::{Versioned, read, write, write_downgraded};
+//! # use ic_cdk::export::candid::CandidType;
+//! # use serde::Deserialize;
+//!
+//! # #[derive(Debug, Deserialize, CandidType)]
+//! # struct First(usize, usize);
+//! # impl Versioned for First {
+//! #     type Previous = ();
+//! #     fn version() -> u32 { 1 }
+//! #     fn upgrade((): ()) -> Self {
+//! #         First(0, 0)
+//! #     }
+//! # }
+//! #[derive(Debug, Deserialize, CandidType)]
+//! struct Second(usize, usize, String);
+//!
+//! impl Versioned for Second {
+//!     type Previous = First;
+//!
+//!     fn upgrade(previous: Self::Previous) -> Self {
+//!         Second(previous.0, previous.1, String::new())
+//!     }
+//!
+//!     fn downgrade(self) -> Option<Self::Previous> {
+//!         Some(First(self.0, self.1))
+//!     }
+//! }
+//!
+//! write(&Second(1, 2, "label".into())).unwrap();
+//!
+//! // #[pre_upgrade] of the canister that is rolled back to the version storing `First`
+//! let second = read::<Second>().unwrap();
+//! write_downgraded(second, First::version()).unwrap();
+//!
+//! // #[post_upgrade] of the older version
+//! let first = read::<First>().unwrap();
+//! assert_eq!((first.0, first.1), (1, 2));
+//! ```
+//!
+//! [`read_as`] reads the stored value downgraded to an older version.
+//!
+//! ## Multiple values
+//!
+//! Several [`Versioned`] values can be stored at once using [`Sections`]. Each value is stored in
+//! its own section under a tag with its own version, so each of them can be upgraded independently.
+//!
+//! ```text
+//!  0 1 2 3 4 5 6 7 ...
+//! +-+-+-+-+-+-+-+-+-----------+-----------+
+//! |MARKER |COUNT  | Section 1 | Section 2 | ...
+//! +-+-+-+-+-+-+-+-+-----------+-----------+
+//! ```
+//!
+//! The marker is `u32::MAX`, so it can never be confused with a version number. Each section
+//! contains the tag length, the tag, the version, the codec id and the length of the serialized
+//! value, followed by the value itself. All numbers are little-endian.
+//!
+//! If different parts of the canister need to store their values independently of each other,
+//! use [`regions`] instead. For states that are too large to be serialized at once, see
+//! [`chunked`].
+//!
+//! ```
+//! use ic_storage::stable::{Sections, Versioned};
+//! # use ic_cdk::export::candid::CandidType;
+//! # use serde::Deserialize;
+//!
+//! # #[derive(Debug, Default, Deserialize, CandidType)]
+//! # struct First(usize, usize);
+//! # impl Versioned for First {
+//! #     type Previous = ();
+//! #     fn version() -> u32 { 1 }
+//! #     fn upgrade((): ()) -> Self {
+//! #         First(0, 0)
+//! #     }
+//! # }
+//! // #[pre_upgrade]
+//! fn pre_upgrade_canister() {
+//!     let mut sections = Sections::default();
+//!     sections.insert("first", &First(1, 2)).unwrap();
+//!     sections.insert("second", &First(3, 4)).unwrap();
+//!     sections.write().unwrap();
+//! }
+//!
+//! // #[post_upgrade]
+//! fn post_upgrade_canister() {
+//!     let sections = Sections::read().unwrap();
+//!     let first = sections.get::<First>("first").unwrap().unwrap_or_default();
+//! }
+//! ```
+
 use std::mem::size_of;
 
+pub mod chunked;
+pub mod codec;
+pub mod inspect;
+pub mod regions;
+
+pub use chunked::{ChunkCursor, ChunkedReader, ChunkedWriter};
+pub use codec::{Codec, Encoded};
+pub use ic_storage_derive::Versioned;
+
 #[cfg(not(target_arch = "wasm32"))]
-use crate::testing::{stable_bytes, stable_read, stable_size, StableWriter};
+use crate::testing::{stable64_grow, stable64_read, stable64_size, stable64_write};
 
 #[cfg(target_arch = "wasm32")]
-use ic_cdk::api::stable::{stable_bytes, stable_read, stable_size, StableWriter};
+use ic_cdk::api::stable::{stable64_grow, stable64_read, stable64_size, stable64_write};
 
-use candid::de::IDLDeserialize;
-use candid::ser::IDLBuilder;
 use candid::types::CandidType;
 use serde::Deserialize;
 
-use crate::{Error, Result};
+use crate::{Error, MigrationError, Result};
 
 const VERSION_SIZE: usize = size_of::<u32>();
 
+const PAGE_SIZE: u64 = 1 << 16;
+
+/// Size of the stable memory addressable with the 32-bit API, which the legacy layout was
+/// written with.
+const LEGACY_MEMORY_SIZE: u64 = 1 << 32;
+
+/// Value stored in place of the version number when stable memory contains [`Sections`].
+const SECTIONS_MARKER: u32 = u32::MAX;
+
+/// Value stored in place of the version number when stable memory contains a single value with a
+/// header written by [`write`].
+const FRAME_MARKER: u32 = u32::MAX - 3;
+
+/// Size of the header of a value written by [`write`]: the marker, the version, the length, the
+/// checksum and the codec of the value.
+const FRAME_HEADER_SIZE: u64 = 24;
+
+/// Value stored in place of the version number when stable memory contains a single value written
+/// to one of two alternating slots by [`write_atomic`].
+const SLOTS_MARKER: u32 = u32::MAX - 4;
+
+/// Size of the header of the double-buffered layout: the marker, the index of the active slot and
+/// the offsets of the slots.
+const SLOTS_HEADER_SIZE: u64 = 24;
+
+/// Tag under which [`Sections::read`] returns a value written with [`write`].
+pub const UNTAGGED: &str = "";
+
 /// Versioned data that can be written to, and read from stable storage.
 pub trait Versioned: for<'de> Deserialize<'de> + CandidType {
     /// The previous version of this data.
@@ -153,6 +376,32 @@ pub trait Versioned: for<'de> Deserialize<'de> + CandidType {
 
     /// Upgrade to this version from the previous version.
     fn upgrade(previous: Self::Previous) -> Self;
+
+    /// Upgrade to this version from the previous version, if the previous version can be migrated.
+    ///
+    /// Override this if the migration can fail, e.g. because the previous version contains
+    /// inconsistent data, to report the failure without panicking. Calls [`upgrade`](Self::upgrade)
+    /// by default. A failed migration is returned by [`read`] as
+    /// [`Error::Migration`](crate::Error::Migration).
+    fn try_upgrade(previous: Self::Previous) -> std::result::Result<Self, MigrationError> {
+        Ok(Self::upgrade(previous))
+    }
+
+    /// Downgrade this version to the previous version.
+    ///
+    /// Returns `None` if downgrading is not supported, which is the default.
+    /// Implementing this makes it possible to roll a canister back to the previous
+    /// version of its code, see [`write_downgraded`].
+    fn downgrade(self) -> Option<Self::Previous> {
+        None
+    }
+
+    /// Serialize this version of the data.
+    ///
+    /// The data is serialized with candid by default. Override this to use another [`Codec`].
+    fn encode(&self) -> Result<Encoded> {
+        Encoded::candid(self)
+    }
 }
 
 // -----------------------------------------------------------------------------
@@ -175,82 +424,622 @@ impl Versioned for () {
     }
 }
 
+/// Compile-time version of a [`Versioned`] type, implemented by the `Versioned` derive macro.
+///
+/// It is used to check at compile time that the version numbers in the chain of previous versions
+/// are strictly increasing and that the chain ends with a unit.
+pub trait VersionChain {
+    /// The previous version of this data.
+    type Previous: VersionChain;
+
+    /// The version of the data.
+    const VERSION: u32;
+}
+
+impl VersionChain for () {
+    type Previous = ();
+    const VERSION: u32 = 0;
+}
+
+/// Reads the first 4 bytes of stable memory as a little-endian number.
+///
+/// All the layouts store their marker there, except the legacy layout, that starts with the
+/// version of the stored value. Use [`legacy_version`] to get this version.
 fn read_version() -> Result<u32> {
     let mut version = [0u8; VERSION_SIZE];
-    if ((stable_size() << 16) as usize) < version.len() {
+    if stable64_size() * PAGE_SIZE < version.len() as u64 {
         return Err(Error::InsufficientSpace);
     }
 
-    stable_read(0, &mut version);
-    Ok(u32::from_ne_bytes(version))
+    stable64_read(0, &mut version);
+    Ok(u32::from_le_bytes(version))
+}
+
+/// Version of the value stored in the legacy layout, given the number returned by
+/// [`read_version`]. The legacy layout stores the version in the native byte order, which is
+/// little-endian on wasm32.
+fn legacy_version(version: u32) -> u32 {
+    u32::from_ne_bytes(version.to_le_bytes())
+}
+
+/// Header of a value written by [`write`].
+struct FrameHeader {
+    version: u32,
+    len: u64,
+    checksum: u32,
+    codec: Codec,
+}
+
+impl FrameHeader {
+    fn new(version: u32, encoded: &Encoded) -> Self {
+        Self {
+            version,
+            len: encoded.data.len() as u64,
+            checksum: crc32fast::hash(&encoded.data),
+            codec: encoded.codec,
+        }
+    }
+
+    fn read(offset: u64) -> Result<Self> {
+        let mut header = [0; FRAME_HEADER_SIZE as usize];
+        if stable64_size() * PAGE_SIZE < offset + FRAME_HEADER_SIZE {
+            return Err(Error::CorruptedLayout);
+        }
+
+        stable64_read(offset, &mut header);
+        Self::from_bytes(&header)
+    }
+
+    fn from_bytes(header: &[u8; FRAME_HEADER_SIZE as usize]) -> Result<Self> {
+        if u32::from_le_bytes(header[0..4].try_into().expect("slice has correct length"))
+            != FRAME_MARKER
+        {
+            return Err(Error::CorruptedLayout);
+        }
+
+        Ok(Self {
+            version: u32::from_le_bytes(header[4..8].try_into().expect("slice has correct length")),
+            len: u64::from_le_bytes(header[8..16].try_into().expect("slice has correct length")),
+            checksum: u32::from_le_bytes(
+                header[16..20].try_into().expect("slice has correct length"),
+            ),
+            codec: Codec::from_id(header[20])?,
+        })
+    }
+
+    fn to_bytes(&self) -> [u8; FRAME_HEADER_SIZE as usize] {
+        let mut header = [0; FRAME_HEADER_SIZE as usize];
+        header[0..4].copy_from_slice(&FRAME_MARKER.to_le_bytes());
+        header[4..8].copy_from_slice(&self.version.to_le_bytes());
+        header[8..16].copy_from_slice(&self.len.to_le_bytes());
+        header[16..20].copy_from_slice(&self.checksum.to_le_bytes());
+        header[20] = self.codec.id();
+        header
+    }
+}
+
+/// Header of the double-buffered layout written by [`write_atomic`]: the index of the active slot
+/// and the offsets of both slots.
+struct SlotsHeader {
+    active: usize,
+    offsets: [u64; 2],
+}
+
+impl SlotsHeader {
+    fn read() -> Result<Self> {
+        let mut header = [0; SLOTS_HEADER_SIZE as usize];
+        if stable64_size() * PAGE_SIZE < SLOTS_HEADER_SIZE {
+            return Err(Error::CorruptedLayout);
+        }
+
+        stable64_read(0, &mut header);
+        Self::from_bytes(&header)
+    }
+
+    fn from_bytes(header: &[u8; SLOTS_HEADER_SIZE as usize]) -> Result<Self> {
+        let active = u32::from_le_bytes(header[4..8].try_into().expect("slice has correct length"));
+        if active > 1 {
+            return Err(Error::CorruptedLayout);
+        }
+
+        Ok(Self {
+            active: active as usize,
+            offsets: [
+                u64::from_le_bytes(header[8..16].try_into().expect("slice has correct length")),
+                u64::from_le_bytes(header[16..24].try_into().expect("slice has correct length")),
+            ],
+        })
+    }
+
+    fn to_bytes(&self) -> [u8; SLOTS_HEADER_SIZE as usize] {
+        let mut header = [0; SLOTS_HEADER_SIZE as usize];
+        header[0..4].copy_from_slice(&SLOTS_MARKER.to_le_bytes());
+        header[4..8].copy_from_slice(&(self.active as u32).to_le_bytes());
+        header[8..16].copy_from_slice(&self.offsets[0].to_le_bytes());
+        header[16..24].copy_from_slice(&self.offsets[1].to_le_bytes());
+        header
+    }
+
+    fn active_offset(&self) -> u64 {
+        self.offsets[self.active]
+    }
+}
+
+/// Version of the single value stored in stable memory, or `None` if the memory contains
+/// something else.
+fn read_value_version() -> Result<Option<u32>> {
+    match read_version() {
+        Ok(FRAME_MARKER) => Ok(Some(FrameHeader::read(0)?.version)),
+        Ok(SLOTS_MARKER) => Ok(Some(
+            FrameHeader::read(SlotsHeader::read()?.active_offset())?.version,
+        )),
+        Ok(SECTIONS_MARKER | chunked::CHUNKS_MARKER) => Ok(None),
+        Ok(regions::REGIONS_MARKER) => Err(Error::UnexpectedLayout),
+        Ok(version) => Ok(Some(legacy_version(version))),
+        Err(Error::InsufficientSpace) => Ok(None),
+        Err(e) => Err(e),
+    }
+}
+
+/// Reads the version, the codec and the serialized data of the single value stored in stable
+/// memory, verifying its checksum.
+fn read_value() -> Result<(u32, Codec, Vec<u8>)> {
+    match read_version()? {
+        FRAME_MARKER => read_frame(0),
+        SLOTS_MARKER => read_frame(SlotsHeader::read()?.active_offset()),
+        version if is_layout_marker(version) => Err(Error::UnexpectedLayout),
+        // Legacy layout without a header, so the length of the value is not known. It was written
+        // with the 32-bit stable memory API, so it is read only from the first 4 GiB of memory.
+        version => {
+            let size = (stable64_size() * PAGE_SIZE).min(LEGACY_MEMORY_SIZE);
+            let data = read_stable_bytes(VERSION_SIZE as u64, size - VERSION_SIZE as u64)?;
+            Ok((legacy_version(version), Codec::Candid, data))
+        }
+    }
+}
+
+/// Reads the value with a header stored at the given offset, verifying its checksum.
+fn read_frame(offset: u64) -> Result<(u32, Codec, Vec<u8>)> {
+    let header = FrameHeader::read(offset)?;
+    let data = read_stable_bytes(offset + FRAME_HEADER_SIZE, header.len)?;
+    if crc32fast::hash(&data) != header.checksum {
+        return Err(Error::ChecksumMismatch);
+    }
+
+    Ok((header.version, header.codec, data))
+}
+
+/// Writes the serialized value with its header to stable memory.
+///
+/// If the memory already contains the double-buffered layout, or `atomic` is set, the value is
+/// written to the inactive slot, which is made active only after the whole value is written.
+fn write_value(version: u32, encoded: &Encoded, atomic: bool) -> Result<()> {
+    let current = match read_version() {
+        Ok(SLOTS_MARKER) => Some(SlotsHeader::read()?),
+        _ => None,
+    };
+
+    if current.is_none() && !atomic {
+        let mut writer = StableWriter::default();
+        writer.write(&FrameHeader::new(version, encoded).to_bytes())?;
+        writer.write(&encoded.data)?;
+        return Ok(());
+    }
+
+    let frame_len = FRAME_HEADER_SIZE + encoded.data.len() as u64;
+    let header = match current {
+        Some(current) => {
+            // Put the value before the active slot if there is enough space there, and right
+            // after it otherwise, so that the two slots take turns in the same space.
+            let active_offset = current.active_offset();
+            let offset = if SLOTS_HEADER_SIZE + frame_len <= active_offset {
+                SLOTS_HEADER_SIZE
+            } else {
+                active_offset + FRAME_HEADER_SIZE + FrameHeader::read(active_offset)?.len
+            };
+
+            let mut header = current;
+            header.active ^= 1;
+            header.offsets[header.active] = offset;
+            header
+        }
+        None => {
+            // The data currently stored is kept intact until the header is written, so the new
+            // value is put after all of it.
+            let offset = (stable64_size() * PAGE_SIZE).max(SLOTS_HEADER_SIZE);
+            SlotsHeader {
+                active: 0,
+                offsets: [offset, offset],
+            }
+        }
+    };
+
+    let mut writer = StableWriter {
+        offset: header.active_offset(),
+    };
+    writer.write(&FrameHeader::new(version, encoded).to_bytes())?;
+    writer.write(&encoded.data)?;
+
+    // The new slot becomes active with a single write of the header.
+    stable64_write(0, &header.to_bytes());
+
+    Ok(())
 }
 
 /// Load a [`Versioned`] from stable storage.
 pub fn read<T: Versioned>() -> Result<T> {
-    let version = read_version()?;
+    let (version, codec, bytes) = read_value()?;
+
     if T::version() < version {
         return Err(Error::AttemptedDowngrade);
     }
 
-    let bytes = stable_bytes();
-    let res = recursive_upgrade::<T>(version, &bytes[VERSION_SIZE..])?;
+    let res = recursive_upgrade::<T>(version, codec, &bytes)?;
     Ok(res)
 }
 
 /// Write a [`Versioned`] to stable storage.
 /// This will overwrite anything that was previously stored, however
 /// it is not allowed to write an older version than what is currently stored.
+///
+/// If the value was previously written with [`write_atomic`], this write is atomic as well.
 pub fn write<T: Versioned>(payload: &T) -> Result<()> {
-    let current_version = match read_version() {
-        Ok(v) => Some(v),
-        Err(Error::InsufficientSpace) => None,
-        Err(e) => return Err(e),
-    };
+    write_versioned(payload, false)
+}
 
+/// Write a [`Versioned`] to stable storage atomically.
+///
+/// The value is written to an inactive slot of stable memory, and the slot becomes active only
+/// after the whole value is written, so if writing is interrupted, [`read`] returns the previously
+/// written value. The subsequent [`write`] calls keep writing to alternating slots.
+///
+/// It is not allowed to write an older version than what is currently stored.
+pub fn write_atomic<T: Versioned>(payload: &T) -> Result<()> {
+    write_versioned(payload, true)
+}
+
+fn write_versioned<T: Versioned>(payload: &T, atomic: bool) -> Result<()> {
     let version = T::version();
 
-    if let Some(current_version) = current_version {
+    if let Some(current_version) = read_value_version()? {
         if current_version > version {
             return Err(Error::ExistingVersionIsNewer);
         }
     }
 
-    let mut writer = StableWriter::default();
+    write_value(version, &payload.encode()?, atomic)
+}
+
+/// Write a [`Versioned`] to stable storage, downgraded to the given version.
+///
+/// This is used to roll a canister back to the previous version of its code: the newer code
+/// downgrades its state before the upgrade, so that the older code can read it.
+/// Unlike [`write`], this overwrites anything that was previously stored, even if it has a newer
+/// version.
+pub fn write_downgraded<T: Versioned>(payload: T, version: u32) -> Result<()> {
+    let encoded = encode_downgraded(payload, version)?;
+    write_value(version, &encoded, false)
+}
 
-    // Write the version number to the first four bytes.
-    // There is no point in checking that the four bytes were actually
-    // written as one would normally do with with an `io::Write`,
-    // as the failure point lies in growing the stable storage,
-    // and the usize returned from the `write` call isn't actually the number
-    // of bytes written, but rather the size of the slice that was passed in.
-    writer.write(&version.to_ne_bytes())?;
+/// Load a [`Versioned`] stored as the version `S` (or any version before it) from stable storage,
+/// and downgrade it to the older version `T`.
+pub fn read_as<S: Versioned, T: Versioned>() -> Result<T> {
+    let stored = read::<S>()?;
+    let encoded = encode_downgraded(stored, T::version())?;
+    recursive_upgrade::<T>(T::version(), encoded.codec, &encoded.data)
+}
 
-    // Serialize and write the `Versioned`
-    let mut serializer = IDLBuilder::new();
-    serializer.arg(payload)?.serialize(writer)?;
+/// A set of [`Versioned`] values, each stored under its own tag, that can be written to and read
+/// from stable storage at once.
+///
+/// Writing the sections overwrites anything that was previously stored, including the values
+/// with the tags that are not present in the written `Sections`.
+#[derive(Debug, Default)]
+pub struct Sections {
+    sections: Vec<Section>,
+}
+
+#[derive(Debug)]
+struct Section {
+    tag: String,
+    version: u32,
+    codec: Codec,
+    data: Vec<u8>,
+    /// The value was added with [`Sections::insert_downgraded`], so it may replace a newer
+    /// version stored under the same tag.
+    downgraded: bool,
+}
+
+impl Sections {
+    /// Serializes the value and adds it to the sections under the given tag, replacing the value
+    /// that was previously stored under this tag.
+    pub fn insert<T: Versioned>(&mut self, tag: &str, value: &T) -> Result<()> {
+        let Encoded { codec, data } = value.encode()?;
+
+        self.sections.retain(|section| section.tag != tag);
+        self.sections.push(Section {
+            tag: tag.to_string(),
+            version: T::version(),
+            codec,
+            data,
+            downgraded: false,
+        });
+
+        Ok(())
+    }
+
+    /// Downgrades the value to the given version and adds it to the sections under the given tag.
+    ///
+    /// The sections written with downgraded values can be read by the older version of the code,
+    /// see [`write_downgraded`]. Writing such sections overwrites newer versions of the values
+    /// stored under the same tags.
+    pub fn insert_downgraded<T: Versioned>(
+        &mut self,
+        tag: &str,
+        value: T,
+        version: u32,
+    ) -> Result<()> {
+        let Encoded { codec, data } = encode_downgraded(value, version)?;
+
+        self.sections.retain(|section| section.tag != tag);
+        self.sections.push(Section {
+            tag: tag.to_string(),
+            version,
+            codec,
+            data,
+            downgraded: true,
+        });
+
+        Ok(())
+    }
+
+    /// Returns the value stored under the given tag, upgrading it to the version `T` if needed.
+    ///
+    /// Returns `Ok(None)` if there is no value with the tag.
+    pub fn get<T: Versioned>(&self, tag: &str) -> Result<Option<T>> {
+        let section = match self.sections.iter().find(|section| section.tag == tag) {
+            Some(section) => section,
+            None => return Ok(None),
+        };
+
+        if T::version() < section.version {
+            return Err(Error::AttemptedDowngrade);
+        }
+
+        recursive_upgrade::<T>(section.version, section.codec, &section.data).map(Some)
+    }
+
+    /// Reads the sections from stable storage.
+    ///
+    /// If the stable storage contains a single value written with [`write`], it is returned as a
+    /// section with the [`UNTAGGED`] tag. This allows to add more stored values to a canister that
+    /// used to store only one.
+    pub fn read() -> Result<Self> {
+        if read_version()? != SECTIONS_MARKER {
+            let (version, codec, data) = read_value()?;
+            return Ok(Self {
+                sections: vec![Section {
+                    tag: UNTAGGED.to_string(),
+                    version,
+                    codec,
+                    data,
+                    downgraded: false,
+                }],
+            });
+        }
+
+        // The sections are read one by one, so only the stored values are copied to the heap.
+        let mut reader = StableReader {
+            offset: VERSION_SIZE as u64,
+        };
+        Ok(Self {
+            sections: Self::parse_from(&mut reader)?,
+        })
+    }
+
+    /// Parses the sections stored after the marker.
+    fn parse(bytes: &[u8]) -> Result<Vec<Section>> {
+        Self::parse_from(&mut SliceReader { bytes })
+    }
+
+    fn parse_from(reader: &mut impl SectionsReader) -> Result<Vec<Section>> {
+        let count = reader.read_u32()?;
+        let mut sections = vec![];
+        for _ in 0..count {
+            let tag_len = reader.read_u32()?;
+            let tag = String::from_utf8(reader.read_bytes(tag_len as u64)?)
+                .map_err(|_| Error::CorruptedLayout)?;
+            let version = reader.read_u32()?;
+            let codec = Codec::from_id(reader.read_bytes(1)?[0])?;
+            let data_len = reader.read_u64()?;
+            let data = reader.read_bytes(data_len)?;
+
+            sections.push(Section {
+                tag,
+                version,
+                codec,
+                data,
+                downgraded: false,
+            });
+        }
+
+        Ok(sections)
+    }
+
+    /// Writes the sections to stable storage, overwriting anything that was previously stored.
+    ///
+    /// It is not allowed to write a section with an older version than what is currently stored
+    /// under the same tag, unless the section was added with
+    /// [`insert_downgraded`](Self::insert_downgraded).
+    pub fn write(&self) -> Result<()> {
+        match read_version() {
+            Ok(SECTIONS_MARKER) if self.sections.iter().any(|section| !section.downgraded) => {
+                let current = Self::read()?;
+                for section in self.sections.iter().filter(|section| !section.downgraded) {
+                    let is_newer = current.sections.iter().any(|stored| {
+                        stored.tag == section.tag && stored.version > section.version
+                    });
+                    if is_newer {
+                        return Err(Error::ExistingVersionIsNewer);
+                    }
+                }
+            }
+            Ok(regions::REGIONS_MARKER) => return Err(Error::UnexpectedLayout),
+            Ok(_) | Err(Error::InsufficientSpace) => {}
+            Err(e) => return Err(e),
+        }
+
+        let mut bytes = vec![];
+        bytes.extend_from_slice(&SECTIONS_MARKER.to_le_bytes());
+        bytes.extend_from_slice(&(self.sections.len() as u32).to_le_bytes());
+        for section in &self.sections {
+            bytes.extend_from_slice(&(section.tag.len() as u32).to_le_bytes());
+            bytes.extend_from_slice(section.tag.as_bytes());
+            bytes.extend_from_slice(&section.version.to_le_bytes());
+            bytes.push(section.codec.id());
+            bytes.extend_from_slice(&(section.data.len() as u64).to_le_bytes());
+            bytes.extend_from_slice(&section.data);
+        }
+
+        StableWriter::default().write(&bytes)?;
+        Ok(())
+    }
+}
+
+/// Source of the serialized [`Sections`]: either stable memory or a copy of it.
+trait SectionsReader {
+    fn read_bytes(&mut self, len: u64) -> Result<Vec<u8>>;
+
+    fn read_u32(&mut self) -> Result<u32> {
+        let bytes = self.read_bytes(size_of::<u32>() as u64)?;
+        Ok(u32::from_le_bytes(
+            bytes[..].try_into().expect("slice has correct length"),
+        ))
+    }
+
+    fn read_u64(&mut self) -> Result<u64> {
+        let bytes = self.read_bytes(size_of::<u64>() as u64)?;
+        Ok(u64::from_le_bytes(
+            bytes[..].try_into().expect("slice has correct length"),
+        ))
+    }
+}
+
+struct SliceReader<'a> {
+    bytes: &'a [u8],
+}
+
+impl SectionsReader for SliceReader<'_> {
+    fn read_bytes(&mut self, len: u64) -> Result<Vec<u8>> {
+        if (self.bytes.len() as u64) < len {
+            return Err(Error::CorruptedLayout);
+        }
+
+        let (head, tail) = self.bytes.split_at(len as usize);
+        self.bytes = tail;
+        Ok(head.to_vec())
+    }
+}
+
+struct StableReader {
+    offset: u64,
+}
+
+impl SectionsReader for StableReader {
+    fn read_bytes(&mut self, len: u64) -> Result<Vec<u8>> {
+        let bytes = read_stable_bytes(self.offset, len)?;
+        self.offset += len;
+        Ok(bytes)
+    }
+}
+
+/// Returns true if the value stored in place of the version number denotes one of the stable
+/// memory layouts rather than a version of a single stored value.
+fn is_layout_marker(version: u32) -> bool {
+    version >= SLOTS_MARKER
+}
+
+/// Grow stable memory so that it contains at least `size` bytes.
+fn ensure_size(size: u64) -> Result<()> {
+    let required_pages = (size + PAGE_SIZE - 1) / PAGE_SIZE;
+    let current_pages = stable64_size();
+    if required_pages > current_pages {
+        stable64_grow(required_pages - current_pages)?;
+    }
 
     Ok(())
 }
 
+/// Copies `len` bytes of stable memory starting from the given offset to the heap.
+///
+/// Returns [`Error::CorruptedLayout`] if the bytes are out of the memory bounds, and
+/// [`Error::InsufficientSpace`] if they don't fit into the address space of the heap.
+fn read_stable_bytes(offset: u64, len: u64) -> Result<Vec<u8>> {
+    let end = offset.checked_add(len).ok_or(Error::CorruptedLayout)?;
+    if end > stable64_size() * PAGE_SIZE {
+        return Err(Error::CorruptedLayout);
+    }
+
+    let mut bytes = vec![0; usize::try_from(len).map_err(|_| Error::InsufficientSpace)?];
+    stable64_read(offset, &mut bytes);
+    Ok(bytes)
+}
+
+/// A writer to the stable memory, growing it as needed.
+#[derive(Default)]
+struct StableWriter {
+    offset: u64,
+}
+
+impl StableWriter {
+    fn write(&mut self, buf: &[u8]) -> Result<usize> {
+        ensure_size(self.offset + buf.len() as u64)?;
+        stable64_write(self.offset, buf);
+        self.offset += buf.len() as u64;
+        Ok(buf.len())
+    }
+}
+
 // -----------------------------------------------------------------------------
 //     - Recursively upgrade -
 //     Recursively upgrade a `Versioned`.
 // -----------------------------------------------------------------------------
-fn recursive_upgrade<T: Versioned>(version: u32, bytes: &[u8]) -> Result<T> {
+fn recursive_upgrade<T: Versioned>(version: u32, codec: Codec, bytes: &[u8]) -> Result<T> {
     if version == T::version() {
-        let mut de = IDLDeserialize::new(bytes)?;
-        let res = de.get_value()?;
-        Ok(res)
+        codec.decode(bytes)
     } else {
-        let val = recursive_upgrade::<T::Previous>(version, bytes)?;
-        Ok(T::upgrade(val))
+        let val = recursive_upgrade::<T::Previous>(version, codec, bytes)?;
+        T::try_upgrade(val).map_err(|source| Error::Migration {
+            from: T::Previous::version(),
+            to: T::version(),
+            source,
+        })
+    }
+}
+
+// -----------------------------------------------------------------------------
+//     - Recursively downgrade -
+//     Recursively downgrade a `Versioned` and serialize it.
+// -----------------------------------------------------------------------------
+fn encode_downgraded<T: Versioned>(value: T, version: u32) -> Result<Encoded> {
+    if version == T::version() {
+        value.encode()
+    } else if version > T::version() {
+        Err(Error::InvalidVersion(version))
+    } else {
+        let previous = value
+            .downgrade()
+            .ok_or_else(|| Error::DowngradeNotSupported(T::version()))?;
+        encode_downgraded::<T::Previous>(previous, version)
     }
 }
 
 #[cfg(test)]
 mod test {
     use super::*;
+    use candid::ser::IDLBuilder;
     use candid::CandidType;
+    use serde::Serialize;
 
     #[derive(Debug, CandidType, Deserialize)]
     struct Version1(u32);
@@ -279,6 +1068,10 @@ mod test {
         fn upgrade(previous: Self::Previous) -> Self {
             Self(previous.0, 5)
         }
+
+        fn downgrade(self) -> Option<Self::Previous> {
+            Some(Version1(self.0))
+        }
     }
 
     impl Versioned for Version3 {
@@ -290,6 +1083,10 @@ mod test {
         fn upgrade(previous: Self::Previous) -> Self {
             Self(previous.0, previous.1, 900)
         }
+
+        fn downgrade(self) -> Option<Self::Previous> {
+            Some(Version2(self.0, self.1))
+        }
     }
 
     #[test]
@@ -301,7 +1098,7 @@ mod test {
             .unwrap()
             .serialize(&mut v1_bytes)
             .unwrap();
-        let v2 = super::recursive_upgrade::<Version2>(1, &v1_bytes).unwrap();
+        let v2 = super::recursive_upgrade::<Version2>(1, Codec::Candid, &v1_bytes).unwrap();
         let Version2(a, b) = v2;
         assert_eq!((a, b), (1, 5));
     }
@@ -315,7 +1112,7 @@ mod test {
             .unwrap()
             .serialize(&mut v1_bytes)
             .unwrap();
-        let v3 = super::recursive_upgrade::<Version3>(1, &v1_bytes).unwrap();
+        let v3 = super::recursive_upgrade::<Version3>(1, Codec::Candid, &v1_bytes).unwrap();
         let Version3(a, b, c) = v3;
         assert_eq!((a, b, c), (1, 5, 900));
     }
@@ -339,7 +1136,7 @@ mod test {
 
     #[test]
     fn try_to_downgrade() {
-        // Downgrading is not currently supported
+        // Reading never downgrades the stored value
         let second = Version2(1, 2);
         write(&second).unwrap();
 
@@ -370,4 +1167,365 @@ mod test {
         let err = write(&Version1(1)).unwrap_err();
         panic!("{err}");
     }
+
+    #[test]
+    fn write_and_upgrade_sections() {
+        let mut sections = Sections::default();
+        sections.insert("first", &Version1(1)).unwrap();
+        sections.insert("second", &Version2(2, 3)).unwrap();
+        sections.write().unwrap();
+
+        let sections = Sections::read().unwrap();
+        let Version3(a, b, c) = sections.get::<Version3>("first").unwrap().unwrap();
+        assert_eq!((a, b, c), (1, 5, 900));
+        let Version2(a, b) = sections.get::<Version2>("second").unwrap().unwrap();
+        assert_eq!((a, b), (2, 3));
+        assert!(sections.get::<Version1>("third").unwrap().is_none());
+    }
+
+    #[test]
+    fn read_single_value_as_sections() {
+        write(&Version1(42)).unwrap();
+
+        let sections = Sections::read().unwrap();
+        let Version2(a, b) = sections.get::<Version2>(UNTAGGED).unwrap().unwrap();
+        assert_eq!((a, b), (42, 5));
+    }
+
+    #[test]
+    fn read_sections_as_single_value() {
+        let mut sections = Sections::default();
+        sections.insert("first", &Version1(1)).unwrap();
+        sections.write().unwrap();
+
+        let err = read::<Version1>().unwrap_err();
+        assert!(matches!(err, Error::UnexpectedLayout));
+    }
+
+    #[test]
+    fn write_an_older_section_version() {
+        let mut sections = Sections::default();
+        sections.insert("first", &Version2(0, 0)).unwrap();
+        sections.write().unwrap();
+
+        let mut sections = Sections::default();
+        sections.insert("first", &Version1(1)).unwrap();
+        let err = sections.write().unwrap_err();
+        assert!(matches!(err, Error::ExistingVersionIsNewer));
+    }
+
+    #[test]
+    fn write_downgraded_across_two_versions() {
+        write(&Version3(1, 2, 3)).unwrap();
+
+        let stored = read::<Version3>().unwrap();
+        write_downgraded(stored, Version1::version()).unwrap();
+
+        let Version1(a) = read::<Version1>().unwrap();
+        assert_eq!(a, 1);
+    }
+
+    #[test]
+    fn read_as_older_version() {
+        write(&Version2(1, 2)).unwrap();
+
+        let Version2(a, b) = read_as::<Version3, Version2>().unwrap();
+        assert_eq!((a, b), (1, 2));
+        let Version1(a) = read_as::<Version3, Version1>().unwrap();
+        assert_eq!(a, 1);
+    }
+
+    #[test]
+    fn downgrade_not_supported() {
+        write(&Version1(1)).unwrap();
+
+        let err = read_as::<Version1, ()>().unwrap_err();
+        assert!(matches!(err, Error::DowngradeNotSupported(1)));
+
+        let err = write_downgraded(Version1(1), 2).unwrap_err();
+        assert!(matches!(err, Error::InvalidVersion(2)));
+    }
+
+    #[test]
+    fn write_downgraded_sections() {
+        let mut sections = Sections::default();
+        sections.insert("first", &Version3(1, 2, 3)).unwrap();
+        sections.insert("second", &Version2(4, 5)).unwrap();
+        sections.write().unwrap();
+
+        let stored = Sections::read().unwrap();
+        let mut sections = Sections::default();
+        let first = stored.get::<Version3>("first").unwrap().unwrap();
+        sections.insert_downgraded("first", first, 2).unwrap();
+        let second = stored.get::<Version2>("second").unwrap().unwrap();
+        sections.insert_downgraded("second", second, 1).unwrap();
+        sections.write().unwrap();
+
+        let sections = Sections::read().unwrap();
+        let Version2(a, b) = sections.get::<Version2>("first").unwrap().unwrap();
+        assert_eq!((a, b), (1, 2));
+        let Version1(a) = sections.get::<Version1>("second").unwrap().unwrap();
+        assert_eq!(a, 4);
+    }
+
+    #[test]
+    fn downgraded_section_does_not_allow_older_versions_of_other_sections() {
+        let mut sections = Sections::default();
+        sections.insert("first", &Version2(1, 2)).unwrap();
+        sections.insert("second", &Version2(3, 4)).unwrap();
+        sections.write().unwrap();
+
+        let mut sections = Sections::default();
+        sections
+            .insert_downgraded("first", Version2(1, 2), 1)
+            .unwrap();
+        sections.insert("second", &Version1(3)).unwrap();
+        let err = sections.write().unwrap_err();
+        assert!(matches!(err, Error::ExistingVersionIsNewer));
+    }
+
+    #[test]
+    fn read_legacy_layout() {
+        let mut bytes = 1u32.to_ne_bytes().to_vec();
+        IDLBuilder::new()
+            .arg(&Version1(42))
+            .unwrap()
+            .serialize(&mut bytes)
+            .unwrap();
+        ensure_size(bytes.len() as u64).unwrap();
+        stable64_write(0, &bytes);
+
+        let Version2(a, b) = read::<Version2>().unwrap();
+        assert_eq!((a, b), (42, 5));
+
+        let err = write(&()).unwrap_err();
+        assert!(matches!(err, Error::ExistingVersionIsNewer));
+    }
+
+    #[test]
+    fn corrupted_value_is_rejected() {
+        write(&Version2(1, 2)).unwrap();
+
+        // Flip a bit in the serialized value.
+        let mut byte = [0];
+        stable64_read(FRAME_HEADER_SIZE + 3, &mut byte);
+        stable64_write(FRAME_HEADER_SIZE + 3, &[byte[0] ^ 1]);
+
+        let err = read::<Version2>().unwrap_err();
+        assert!(matches!(err, Error::ChecksumMismatch));
+        let err = Sections::read().unwrap_err();
+        assert!(matches!(err, Error::ChecksumMismatch));
+    }
+
+    #[test]
+    fn truncated_value_is_rejected() {
+        write(&Version1(1)).unwrap();
+
+        // Pretend that the value is longer than the stable memory.
+        stable64_write(8, &(PAGE_SIZE * 2).to_le_bytes());
+
+        let err = read::<Version1>().unwrap_err();
+        assert!(matches!(err, Error::CorruptedLayout));
+    }
+
+    #[test]
+    fn truncated_region_is_rejected() {
+        regions::write("value", &Version1(1)).unwrap();
+
+        // Pretend that the region is longer than the stable memory. The length of the first entry
+        // is stored after the 16 bytes of the directory header.
+        stable64_write(16 + 48, &(PAGE_SIZE * 2).to_le_bytes());
+
+        let err = regions::read::<Version1>("value").unwrap_err();
+        assert!(matches!(err, Error::CorruptedLayout));
+    }
+
+    #[derive(Debug, Default, CandidType, Deserialize, Versioned)]
+    struct DerivedV1(u32);
+
+    #[derive(Debug, CandidType, Deserialize, Versioned)]
+    #[versioned(previous = DerivedV1, downgrade = DerivedV1::from, version = 5)]
+    struct DerivedV2(u32, String);
+
+    #[derive(Debug, CandidType, Deserialize, Versioned)]
+    #[versioned(previous = DerivedV2, upgrade = DerivedV3::from_v2)]
+    struct DerivedV3(String);
+
+    impl From<DerivedV1> for DerivedV2 {
+        fn from(previous: DerivedV1) -> Self {
+            Self(previous.0, previous.0.to_string())
+        }
+    }
+
+    impl From<DerivedV2> for DerivedV1 {
+        fn from(value: DerivedV2) -> Self {
+            Self(value.0)
+        }
+    }
+
+    impl DerivedV3 {
+        fn from_v2(previous: DerivedV2) -> Self {
+            Self(previous.1)
+        }
+    }
+
+    #[test]
+    fn derived_versions() {
+        assert_eq!(DerivedV1::version(), 1);
+        assert_eq!(DerivedV2::version(), 5);
+        assert_eq!(DerivedV3::version(), 6);
+
+        write(&DerivedV1(42)).unwrap();
+        let DerivedV3(a) = read::<DerivedV3>().unwrap();
+        assert_eq!(a, "42");
+
+        write(&DerivedV2(1, "one".into())).unwrap();
+        let DerivedV1(a) = read_as::<DerivedV2, DerivedV1>().unwrap();
+        assert_eq!(a, 1);
+
+        let err = read_as::<DerivedV3, DerivedV1>().unwrap_err();
+        assert!(matches!(err, Error::DowngradeNotSupported(6)));
+    }
+
+    #[test]
+    fn write_atomic_alternates_slots() {
+        write(&Version1(1)).unwrap();
+        write_atomic(&Version2(2, 3)).unwrap();
+
+        let Version2(a, b) = read::<Version2>().unwrap();
+        assert_eq!((a, b), (2, 3));
+
+        for i in 0..10 {
+            write(&Version3(i, i, i)).unwrap();
+            let Version3(a, _, _) = read::<Version3>().unwrap();
+            assert_eq!(a, i);
+        }
+
+        assert_eq!(read_version().unwrap(), SLOTS_MARKER);
+        assert_eq!(stable64_size(), 2);
+
+        let err = write(&Version2(0, 0)).unwrap_err();
+        assert!(matches!(err, Error::ExistingVersionIsNewer));
+    }
+
+    #[test]
+    fn interrupted_atomic_write_keeps_previous_value() {
+        write_atomic(&Version1(1)).unwrap();
+        write_atomic(&Version1(2)).unwrap();
+
+        // Garbage in the inactive slot, as if the next write was interrupted.
+        let header = SlotsHeader::read().unwrap();
+        stable64_write(
+            header.offsets[header.active ^ 1],
+            &[0xff; FRAME_HEADER_SIZE as usize],
+        );
+
+        let Version1(a) = read::<Version1>().unwrap();
+        assert_eq!(a, 2);
+
+        let sections = Sections::read().unwrap();
+        let Version1(a) = sections.get::<Version1>(UNTAGGED).unwrap().unwrap();
+        assert_eq!(a, 2);
+    }
+
+    #[derive(Debug, Default, CandidType, Deserialize, Serialize, Versioned)]
+    #[versioned(codec = Cbor)]
+    struct CborV1(u32, String);
+
+    #[derive(Debug, CandidType, Deserialize, Serialize, Versioned)]
+    #[versioned(previous = CborV1, upgrade = BinaryV2::from_v1, codec = Binary)]
+    struct BinaryV2 {
+        id: u32,
+        name: String,
+    }
+
+    impl BinaryV2 {
+        fn from_v1(previous: CborV1) -> Self {
+            Self {
+                id: previous.0,
+                name: previous.1,
+            }
+        }
+    }
+
+    #[test]
+    fn switch_codecs_between_versions() {
+        write(&CborV1(1, "one".into())).unwrap();
+        let (_, codec, _) = read_value().unwrap();
+        assert_eq!(codec, Codec::Cbor);
+
+        let BinaryV2 { id, name } = read::<BinaryV2>().unwrap();
+        assert_eq!((id, name.as_str()), (1, "one"));
+
+        let mut sections = Sections::default();
+        sections.insert("first", &CborV1(2, "two".into())).unwrap();
+        sections
+            .insert("second", &BinaryV2::from_v1(CborV1(3, "three".into())))
+            .unwrap();
+        sections.write().unwrap();
+
+        let sections = Sections::read().unwrap();
+        let first = sections.get::<BinaryV2>("first").unwrap().unwrap();
+        assert_eq!((first.id, first.name.as_str()), (2, "two"));
+        let second = sections.get::<BinaryV2>("second").unwrap().unwrap();
+        assert_eq!((second.id, second.name.as_str()), (3, "three"));
+    }
+
+    #[derive(Debug, CandidType, Deserialize)]
+    struct Checked(u32);
+
+    impl Versioned for Checked {
+        type Previous = Version1;
+
+        fn upgrade(previous: Self::Previous) -> Self {
+            Self(previous.0)
+        }
+
+        fn try_upgrade(previous: Self::Previous) -> std::result::Result<Self, MigrationError> {
+            match previous.0 {
+                0 => Err(MigrationError::new("zero is not allowed")),
+                value => Ok(Self(value)),
+            }
+        }
+    }
+
+    #[derive(Debug, CandidType, Deserialize, Versioned)]
+    #[versioned(previous = DerivedV1, try_upgrade = DerivedChecked::from_v1)]
+    struct DerivedChecked(u32);
+
+    impl DerivedChecked {
+        fn from_v1(previous: DerivedV1) -> std::result::Result<Self, MigrationError> {
+            previous
+                .0
+                .checked_mul(2)
+                .map(Self)
+                .ok_or_else(|| MigrationError::new("overflow"))
+        }
+    }
+
+    #[test]
+    fn failed_migration() {
+        write(&Version1(1)).unwrap();
+        let Checked(a) = read::<Checked>().unwrap();
+        assert_eq!(a, 1);
+
+        write(&Version1(0)).unwrap();
+        let err = read::<Checked>().unwrap_err();
+        assert!(matches!(err, Error::Migration { from: 1, to: 2, .. }));
+        assert_eq!(
+            err.to_string(),
+            "failed to upgrade from version 1 to version 2: zero is not allowed"
+        );
+    }
+
+    #[test]
+    fn failed_derived_migration() {
+        write(&DerivedV1(2)).unwrap();
+        let DerivedChecked(a) = read::<DerivedChecked>().unwrap();
+        assert_eq!(a, 4);
+
+        write(&DerivedV1(u32::MAX)).unwrap();
+        let err = read::<DerivedChecked>().unwrap_err();
+        assert!(matches!(err, Error::Migration { from: 1, to: 2, .. }));
+    }
 }
@@ -0,0 +1,474 @@
+//! Chunked serialization of large states.
+//!
+//! [`read`](super::read) copies the whole serialized state to the heap before decoding it, and
+//! [`write`](super::write) serializes the whole state at once. For large states this can exceed
+//! the heap size or the instruction limit of a single call.
+//!
+//! [`ChunkedWriter`] and [`ChunkedReader`] store the state as a sequence of separately serialized
+//! items instead. Only one item is kept in the heap at a time, and the work can be split across
+//! multiple calls: the position of the writer or reader can be saved as a [`ChunkCursor`] and used
+//! to resume the work later.
+//!
+//! ```text
+//!  0 1 2 3 4 5 6 7 8 ... 15 16  20    21           ...
+//! +-+-+-+-+-+-+-+-+--------+----+-----+------------+----+-----+------------+
+//! |MARKER |VERSION| COUNT  |LEN |CODEC| ITEM 1     |LEN |CODEC| ITEM 2     | ...
+//! +-+-+-+-+-+-+-+-+--------+----+-----+------------+----+-----+------------+
+//! ```
+//!
+//! All numbers are little-endian. Each item is serialized with [`Versioned::encode`], and the id
+//! of its codec is stored before the item. The items count is written when the writer is
+//! finished, so unfinished data cannot be read.
+//!
+//! ```
+//! use ic_storage::stable::{ChunkedReader, ChunkedWriter, Versioned};
+//! # use ic_cdk::export::candid::CandidType;
+//! # use serde::Deserialize;
+//!
+//! #[derive(Debug, Deserialize, CandidType)]
+//! struct Entry(u64, String);
+//!
+//! impl Versioned for Entry {
+//!     type Previous = ();
+//!     fn version() -> u32 { 1 }
+//!     fn upgrade((): ()) -> Self {
+//!         Self(0, String::new())
+//!     }
+//! }
+//!
+//! let mut writer = ChunkedWriter::<Entry>::new().unwrap();
+//! writer.push(&Entry(1, "first".into())).unwrap();
+//!
+//! // The cursor can be stored in the canister state to continue writing in another call.
+//! let cursor = writer.cursor();
+//! let mut writer = ChunkedWriter::<Entry>::resume(cursor).unwrap();
+//! writer.push(&Entry(2, "second".into())).unwrap();
+//! writer.finish().unwrap();
+//!
+//! let reader = ChunkedReader::<Entry>::new().unwrap();
+//! let entries = reader.collect::<Result<Vec<_>, _>>().unwrap();
+//! assert_eq!(entries.len(), 2);
+//! ```
+
+use std::marker::PhantomData;
+use std::mem::size_of;
+
+#[cfg(not(target_arch = "wasm32"))]
+use crate::testing::{stable64_read, stable64_size, stable64_write};
+
+#[cfg(target_arch = "wasm32")]
+use ic_cdk::api::stable::{stable64_read, stable64_size, stable64_write};
+
+use candid::CandidType;
+use serde::Deserialize;
+
+use super::{
+    ensure_size, read_value_version, read_version, recursive_upgrade, Codec, Encoded, Versioned,
+};
+use crate::{Error, Result};
+
+/// Value stored in place of the version number when stable memory contains chunked data.
+pub(super) const CHUNKS_MARKER: u32 = u32::MAX - 2;
+
+pub(super) const HEADER_SIZE: u64 = 16;
+pub(super) const LEN_SIZE: u64 = size_of::<u32>() as u64;
+
+/// Size of the length and the codec id stored before each item.
+pub(super) const ITEM_HEADER_SIZE: u64 = LEN_SIZE + 1;
+
+/// Items count stored in the header while the writer is not finished.
+pub(super) const UNFINISHED: u64 = u64::MAX;
+
+/// Position of a [`ChunkedWriter`] or [`ChunkedReader`] in stable memory.
+///
+/// The cursor can be stored in the canister state to resume writing or reading in a later call.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, CandidType, Deserialize)]
+pub struct ChunkCursor {
+    offset: u64,
+    index: u64,
+}
+
+impl ChunkCursor {
+    /// Number of items written or read so far.
+    pub fn index(&self) -> u64 {
+        self.index
+    }
+}
+
+/// Writes a sequence of [`Versioned`] items to stable memory one by one.
+pub struct ChunkedWriter<T: Versioned> {
+    cursor: ChunkCursor,
+    _item: PhantomData<T>,
+}
+
+impl<T: Versioned> ChunkedWriter<T> {
+    /// Starts writing a new sequence, overwriting anything that was previously stored.
+    ///
+    /// As with [`write`](super::write), it is not allowed to write an older version than what is
+    /// currently stored.
+    pub fn new() -> Result<Self> {
+        let current_version = match read_version() {
+            Ok(CHUNKS_MARKER) => Some(Header::read()?.version),
+            _ => read_value_version()?,
+        };
+
+        if matches!(current_version, Some(version) if version > T::version()) {
+            return Err(Error::ExistingVersionIsNewer);
+        }
+
+        ensure_size(HEADER_SIZE)?;
+
+        let mut header = [0; HEADER_SIZE as usize];
+        header[0..4].copy_from_slice(&CHUNKS_MARKER.to_le_bytes());
+        header[4..8].copy_from_slice(&T::version().to_le_bytes());
+        header[8..16].copy_from_slice(&UNFINISHED.to_le_bytes());
+        stable64_write(0, &header);
+
+        Ok(Self {
+            cursor: ChunkCursor {
+                offset: HEADER_SIZE,
+                index: 0,
+            },
+            _item: PhantomData,
+        })
+    }
+
+    /// Continues writing the unfinished sequence from the given cursor.
+    pub fn resume(cursor: ChunkCursor) -> Result<Self> {
+        let header = Header::read()?;
+        if header.version != T::version() || header.count != UNFINISHED {
+            return Err(Error::UnexpectedLayout);
+        }
+
+        Ok(Self {
+            cursor,
+            _item: PhantomData,
+        })
+    }
+
+    /// Serializes the item and writes it to stable memory.
+    ///
+    /// Returns [`Error::EntryTooLarge`] if the serialized item doesn't fit into 4 GiB.
+    pub fn push(&mut self, item: &T) -> Result<()> {
+        let Encoded { codec, data } = item.encode()?;
+        let len = u32::try_from(data.len()).map_err(|_| Error::EntryTooLarge(data.len() as u64))?;
+
+        let offset = self.cursor.offset;
+        let end = offset + ITEM_HEADER_SIZE + u64::from(len);
+        ensure_size(end)?;
+
+        let mut header = [0; ITEM_HEADER_SIZE as usize];
+        header[0..4].copy_from_slice(&len.to_le_bytes());
+        header[4] = codec.id();
+        stable64_write(offset, &header);
+        stable64_write(offset + ITEM_HEADER_SIZE, &data);
+
+        self.cursor = ChunkCursor {
+            offset: end,
+            index: self.cursor.index + 1,
+        };
+
+        Ok(())
+    }
+
+    /// Current position of the writer.
+    pub fn cursor(&self) -> ChunkCursor {
+        self.cursor
+    }
+
+    /// Finishes the sequence, making it available for reading.
+    pub fn finish(self) -> Result<()> {
+        stable64_write(8, &self.cursor.index.to_le_bytes());
+        Ok(())
+    }
+}
+
+/// Reads a sequence of [`Versioned`] items written by [`ChunkedWriter`] one by one, upgrading
+/// each of them to the version `T` if needed.
+pub struct ChunkedReader<T: Versioned> {
+    cursor: ChunkCursor,
+    version: u32,
+    count: u64,
+    _item: PhantomData<T>,
+}
+
+impl<T: Versioned> ChunkedReader<T> {
+    /// Starts reading the sequence from the first item.
+    pub fn new() -> Result<Self> {
+        Self::resume(ChunkCursor {
+            offset: HEADER_SIZE,
+            index: 0,
+        })
+    }
+
+    /// Continues reading the sequence from the given cursor.
+    pub fn resume(cursor: ChunkCursor) -> Result<Self> {
+        let header = Header::read()?;
+        if header.count == UNFINISHED {
+            return Err(Error::UnfinishedChunks);
+        }
+
+        if T::version() < header.version {
+            return Err(Error::AttemptedDowngrade);
+        }
+
+        Ok(Self {
+            cursor,
+            version: header.version,
+            count: header.count,
+            _item: PhantomData,
+        })
+    }
+
+    /// Reads the next item, or returns `Ok(None)` if all items were read.
+    ///
+    /// If the item cannot be decoded or upgraded, the reader stays at the same item, which can be
+    /// passed over with [`skip_item`](Self::skip_item).
+    pub fn next_item(&mut self) -> Result<Option<T>> {
+        if self.cursor.index >= self.count {
+            return Ok(None);
+        }
+
+        let (len, codec) = self.item_header()?;
+        let mut data = vec![0; len as usize];
+        read_checked(self.cursor.offset + ITEM_HEADER_SIZE, &mut data)?;
+
+        let item = recursive_upgrade::<T>(self.version, codec, &data)?;
+        self.advance(data.len() as u64);
+
+        Ok(Some(item))
+    }
+
+    /// Moves the reader to the next item without reading the current one. Returns `Ok(false)` if
+    /// all items were read.
+    pub fn skip_item(&mut self) -> Result<bool> {
+        if self.cursor.index >= self.count {
+            return Ok(false);
+        }
+
+        let (len, _) = self.item_header()?;
+        self.advance(u64::from(len));
+
+        Ok(true)
+    }
+
+    /// Current position of the reader.
+    pub fn cursor(&self) -> ChunkCursor {
+        self.cursor
+    }
+
+    /// Number of items that are left to read.
+    pub fn remaining(&self) -> u64 {
+        self.count.saturating_sub(self.cursor.index)
+    }
+
+    /// Length and codec of the current item.
+    fn item_header(&self) -> Result<(u32, Codec)> {
+        let mut header = [0; ITEM_HEADER_SIZE as usize];
+        read_checked(self.cursor.offset, &mut header)?;
+
+        let len = u32::from_le_bytes(header[0..4].try_into().expect("slice has correct length"));
+        Ok((len, Codec::from_id(header[4])?))
+    }
+
+    fn advance(&mut self, data_len: u64) {
+        self.cursor = ChunkCursor {
+            offset: self.cursor.offset + ITEM_HEADER_SIZE + data_len,
+            index: self.cursor.index + 1,
+        };
+    }
+}
+
+impl<T: Versioned> Iterator for ChunkedReader<T> {
+    type Item = Result<T>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.next_item().transpose()
+    }
+}
+
+pub(super) struct Header {
+    pub(super) version: u32,
+    pub(super) count: u64,
+}
+
+impl Header {
+    fn read() -> Result<Self> {
+        let mut header = [0; HEADER_SIZE as usize];
+        read_checked(0, &mut header)?;
+        Self::from_bytes(&header)
+    }
+
+    pub(super) fn from_bytes(header: &[u8; HEADER_SIZE as usize]) -> Result<Self> {
+        let marker = u32::from_le_bytes(header[0..4].try_into().expect("slice has correct length"));
+        if marker != CHUNKS_MARKER {
+            return Err(Error::UnexpectedLayout);
+        }
+
+        Ok(Self {
+            version: u32::from_le_bytes(header[4..8].try_into().expect("slice has correct length")),
+            count: u64::from_le_bytes(header[8..16].try_into().expect("slice has correct length")),
+        })
+    }
+}
+
+/// Reads from stable memory, checking that the read doesn't go out of the memory bounds.
+fn read_checked(offset: u64, buf: &mut [u8]) -> Result<()> {
+    let size = stable64_size() << 16;
+    if offset + buf.len() as u64 > size {
+        return Err(Error::InsufficientSpace);
+    }
+
+    stable64_read(offset, buf);
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[derive(Debug, CandidType, Deserialize)]
+    struct Item(u32);
+
+    #[derive(Debug, CandidType, Deserialize)]
+    struct ItemV2(u32, String);
+
+    impl Versioned for Item {
+        type Previous = ();
+        fn version() -> u32 {
+            1
+        }
+
+        fn upgrade(_: Self::Previous) -> Self {
+            Self(0)
+        }
+    }
+
+    impl Versioned for ItemV2 {
+        type Previous = Item;
+        fn version() -> u32 {
+            2
+        }
+
+        fn upgrade(previous: Self::Previous) -> Self {
+            Self(previous.0, previous.0.to_string())
+        }
+    }
+
+    #[test]
+    fn write_and_read_in_batches() {
+        let mut writer = ChunkedWriter::<Item>::new().unwrap();
+        for i in 0..5 {
+            writer.push(&Item(i)).unwrap();
+        }
+
+        let cursor = writer.cursor();
+        assert_eq!(cursor.index(), 5);
+
+        let mut writer = ChunkedWriter::<Item>::resume(cursor).unwrap();
+        for i in 5..10 {
+            writer.push(&Item(i)).unwrap();
+        }
+        writer.finish().unwrap();
+
+        let mut reader = ChunkedReader::<Item>::new().unwrap();
+        let first = (0..3)
+            .map(|_| reader.next_item().unwrap().unwrap().0)
+            .collect::<Vec<_>>();
+        assert_eq!(first, vec![0, 1, 2]);
+        assert_eq!(reader.remaining(), 7);
+
+        let reader = ChunkedReader::<Item>::resume(reader.cursor()).unwrap();
+        let rest = reader.map(|item| item.unwrap().0).collect::<Vec<_>>();
+        assert_eq!(rest, vec![3, 4, 5, 6, 7, 8, 9]);
+    }
+
+    #[test]
+    fn items_are_upgraded() {
+        let mut writer = ChunkedWriter::<Item>::new().unwrap();
+        writer.push(&Item(42)).unwrap();
+        writer.finish().unwrap();
+
+        let mut reader = ChunkedReader::<ItemV2>::new().unwrap();
+        let ItemV2(a, b) = reader.next_item().unwrap().unwrap();
+        assert_eq!((a, b.as_str()), (42, "42"));
+        assert!(reader.next_item().unwrap().is_none());
+
+        let err = ChunkedReader::<()>::new().err().unwrap();
+        assert!(matches!(err, Error::AttemptedDowngrade));
+    }
+
+    #[derive(Debug, CandidType, Deserialize, serde::Serialize)]
+    struct CborItem(u32, String);
+
+    impl Versioned for CborItem {
+        type Previous = ();
+        fn version() -> u32 {
+            1
+        }
+
+        fn upgrade(_: Self::Previous) -> Self {
+            Self(0, String::new())
+        }
+
+        fn encode(&self) -> Result<Encoded> {
+            Codec::Cbor.encode(self)
+        }
+    }
+
+    #[test]
+    fn items_are_encoded_with_their_codec() {
+        let mut writer = ChunkedWriter::<CborItem>::new().unwrap();
+        writer.push(&CborItem(1, "one".into())).unwrap();
+        writer.push(&CborItem(2, "two".into())).unwrap();
+        writer.finish().unwrap();
+
+        let mut codec = [0];
+        stable64_read(HEADER_SIZE + LEN_SIZE, &mut codec);
+        assert_eq!(codec[0], Codec::Cbor.id());
+
+        let reader = ChunkedReader::<CborItem>::new().unwrap();
+        let items = reader
+            .map(|item| item.map(|CborItem(id, name)| (id, name)))
+            .collect::<Result<Vec<_>>>()
+            .unwrap();
+        assert_eq!(items, vec![(1, "one".into()), (2, "two".into())]);
+    }
+
+    #[test]
+    fn unfinished_data_cannot_be_read() {
+        let mut writer = ChunkedWriter::<Item>::new().unwrap();
+        writer.push(&Item(1)).unwrap();
+
+        let err = ChunkedReader::<Item>::new().err().unwrap();
+        assert!(matches!(err, Error::UnfinishedChunks));
+    }
+
+    #[test]
+    fn newer_version_is_not_overwritten() {
+        super::super::write(&ItemV2(1, "1".into())).unwrap();
+        let err = ChunkedWriter::<Item>::new().err().unwrap();
+        assert!(matches!(err, Error::ExistingVersionIsNewer));
+
+        let mut writer = ChunkedWriter::<ItemV2>::new().unwrap();
+        writer.push(&ItemV2(1, "1".into())).unwrap();
+        writer.finish().unwrap();
+
+        let err = ChunkedWriter::<Item>::new().err().unwrap();
+        assert!(matches!(err, Error::ExistingVersionIsNewer));
+        assert!(ChunkedWriter::<ItemV2>::new().is_ok());
+    }
+
+    #[test]
+    fn resume_another_layout() {
+        super::super::write(&Item(1)).unwrap();
+
+        let err = ChunkedWriter::<Item>::resume(ChunkCursor {
+            offset: HEADER_SIZE,
+            index: 0,
+        })
+        .err()
+        .unwrap();
+        assert!(matches!(err, Error::UnexpectedLayout));
+    }
+}
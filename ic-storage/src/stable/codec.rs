@@ -0,0 +1,168 @@
+//! Serialization formats of [`Versioned`](super::Versioned) values.
+//!
+//! By default the values are serialized with candid. Candid is self-describing and can be
+//! decoded by any canister, but it is relatively slow and the serialized data is bulky, which
+//! matters for large states. A [`Versioned`](super::Versioned) type can choose another codec by
+//! implementing [`Versioned::encode`](super::Versioned::encode):
+//!
+//! ```
+//! use ic_storage::stable::{Codec, Encoded, Versioned};
+//! use ic_storage::Result;
+//! # use ic_cdk::export::candid::CandidType;
+//! use serde::{Deserialize, Serialize};
+//! use std::collections::HashMap;
+//!
+//! #[derive(Default, Deserialize, Serialize, CandidType)]
+//! struct Balances(HashMap<String, u64>);
+//!
+//! impl Versioned for Balances {
+//!     type Previous = ();
+//!
+//!     fn upgrade((): ()) -> Self {
+//!         Self::default()
+//!     }
+//!
+//!     fn encode(&self) -> Result<Encoded> {
+//!         Codec::Binary.encode(self)
+//!     }
+//! }
+//! ```
+//!
+//! The id of the codec is stored together with the value, so the value is always decoded with
+//! the codec it was encoded with, and different versions of a type can use different codecs.
+//! Values stored with [`ChunkedWriter`](super::ChunkedWriter) are always serialized with candid.
+
+use candid::de::IDLDeserialize;
+use candid::ser::IDLBuilder;
+use candid::CandidType;
+use serde::{Deserialize, Serialize};
+
+use crate::{Error, Result};
+
+/// Serialization format of a stored value.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Codec {
+    /// Candid, the default codec.
+    Candid,
+    /// CBOR, a compact self-describing format.
+    Cbor,
+    /// Bincode, a fast non self-describing binary format. As it doesn't contain the field names,
+    /// fields cannot be added to or removed from a type within the same version.
+    Binary,
+}
+
+impl Codec {
+    /// Serializes the value with this codec.
+    pub fn encode<T: CandidType + Serialize>(self, value: &T) -> Result<Encoded> {
+        let data = match self {
+            Self::Candid => return Encoded::candid(value),
+            Self::Cbor => {
+                serde_cbor::to_vec(value).map_err(|e| Error::Serialization(e.to_string()))?
+            }
+            Self::Binary => {
+                bincode::serialize(value).map_err(|e| Error::Serialization(e.to_string()))?
+            }
+        };
+
+        Ok(Encoded { codec: self, data })
+    }
+
+    /// Deserializes the value encoded with this codec.
+    pub fn decode<T: CandidType + for<'de> Deserialize<'de>>(self, bytes: &[u8]) -> Result<T> {
+        match self {
+            Self::Candid => {
+                let mut de = IDLDeserialize::new(bytes)?;
+                Ok(de.get_value()?)
+            }
+            Self::Cbor => {
+                serde_cbor::from_slice(bytes).map_err(|e| Error::Serialization(e.to_string()))
+            }
+            Self::Binary => {
+                bincode::deserialize(bytes).map_err(|e| Error::Serialization(e.to_string()))
+            }
+        }
+    }
+
+    /// Id of the codec stored in stable memory.
+    pub(crate) fn id(self) -> u8 {
+        match self {
+            Self::Candid => 0,
+            Self::Cbor => 1,
+            Self::Binary => 2,
+        }
+    }
+
+    pub(crate) fn from_id(id: u8) -> Result<Self> {
+        match id {
+            0 => Ok(Self::Candid),
+            1 => Ok(Self::Cbor),
+            2 => Ok(Self::Binary),
+            _ => Err(Error::CorruptedLayout),
+        }
+    }
+}
+
+/// A value serialized with a [`Codec`].
+#[derive(Debug)]
+pub struct Encoded {
+    pub(crate) codec: Codec,
+    pub(crate) data: Vec<u8>,
+}
+
+impl Encoded {
+    /// Serializes the value with candid.
+    ///
+    /// Unlike [`Codec::encode`], this doesn't require the value to implement [`Serialize`].
+    pub fn candid<T: CandidType>(value: &T) -> Result<Self> {
+        let mut data = vec![];
+        IDLBuilder::new().arg(value)?.serialize(&mut data)?;
+
+        Ok(Self {
+            codec: Codec::Candid,
+            data,
+        })
+    }
+
+    /// The codec the value was serialized with.
+    pub fn codec(&self) -> Codec {
+        self.codec
+    }
+
+    /// The serialized value.
+    pub fn data(&self) -> &[u8] {
+        &self.data
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[derive(Debug, PartialEq, CandidType, Deserialize, Serialize)]
+    struct Value {
+        id: u64,
+        tags: Vec<String>,
+    }
+
+    #[test]
+    fn encode_and_decode() {
+        let value = Value {
+            id: 42,
+            tags: vec!["a".into(), "b".into()],
+        };
+
+        for codec in [Codec::Candid, Codec::Cbor, Codec::Binary] {
+            let encoded = codec.encode(&value).unwrap();
+            assert_eq!(encoded.codec(), codec);
+            assert_eq!(Codec::from_id(codec.id()).unwrap(), codec);
+            assert_eq!(codec.decode::<Value>(encoded.data()).unwrap(), value);
+        }
+    }
+
+    #[test]
+    fn decode_with_another_codec() {
+        let encoded = Codec::Cbor.encode(&1u32).unwrap();
+        assert!(Codec::Binary.decode::<Value>(encoded.data()).is_err());
+        assert!(matches!(Codec::from_id(3), Err(Error::CorruptedLayout)));
+    }
+}
@@ -0,0 +1,524 @@
+//! Inspection and export of the data stored in stable memory.
+//!
+//! [`inspect`] decodes a copy of stable memory without knowing the types of the stored values. It
+//! recognizes all the layouts written by this crate and returns the versions and the serialized
+//! data of the stored values, which can be printed in the Candid text format with
+//! [`StoredValue::pretty`]. This can be used to debug the data stored by a canister, or to look
+//! into a backup of the canister state.
+//!
+//! The copy of stable memory can be taken from a canister snapshot, or downloaded from the
+//! canister page by page with [`read_page`]:
+//!
+//! ```
+//! use ic_storage::stable::inspect::{self, Layout, MAX_PAGE_SIZE};
+//! use ic_storage::stable::{write, Versioned};
+//! # use ic_cdk::export::candid::CandidType;
+//! # use serde::Deserialize;
+//!
+//! #[derive(Debug, Default, Deserialize, CandidType, Versioned)]
+//! struct State {
+//!     counter: u64,
+//! }
+//!
+//! write(&State { counter: 42 }).unwrap();
+//!
+//! // Usually called from a query method of the canister, see `inspect::read_page`.
+//! let mut dump = vec![];
+//! loop {
+//!     let page = inspect::read_page(dump.len() as u64, MAX_PAGE_SIZE);
+//!     dump.extend_from_slice(&page.data);
+//!     if dump.len() as u64 >= page.total_size {
+//!         break;
+//!     }
+//! }
+//!
+//! let info = inspect::inspect(&dump).unwrap();
+//! assert_eq!(info.layout, Layout::Value);
+//! assert_eq!(info.values[0].version, 1);
+//! println!("{}", info.values[0].pretty().unwrap());
+//! ```
+
+#[cfg(not(target_arch = "wasm32"))]
+use crate::testing::{stable64_read, stable64_size};
+
+#[cfg(target_arch = "wasm32")]
+use ic_cdk::api::stable::{stable64_read, stable64_size};
+
+use candid::{CandidType, Deserialize, IDLArgs};
+
+use super::{
+    chunked, is_layout_marker, legacy_version, regions, Codec, FrameHeader, Sections, SlotsHeader,
+    FRAME_HEADER_SIZE, FRAME_MARKER, PAGE_SIZE, SECTIONS_MARKER, SLOTS_MARKER, UNTAGGED,
+    VERSION_SIZE,
+};
+use crate::{Error, Result};
+
+/// Maximum number of bytes returned by [`read_page`], so that a page fits into a single reply.
+pub const MAX_PAGE_SIZE: u64 = 2 * 1024 * 1024;
+
+/// Layout of the data in stable memory.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Layout {
+    /// Stable memory is empty.
+    Empty,
+    /// A single value written by an older version of this crate, without a header.
+    Legacy,
+    /// A single value written by [`write`](super::write).
+    Value,
+    /// A single value written by [`write_atomic`](super::write_atomic).
+    Slots {
+        /// Index of the slot that contains the current value.
+        active: u32,
+    },
+    /// Values written with [`Sections`].
+    Sections,
+    /// A directory of [`regions`].
+    Regions,
+    /// Items written with [`ChunkedWriter`](super::ChunkedWriter).
+    Chunks {
+        /// Whether the writer was finished.
+        finished: bool,
+    },
+}
+
+/// A value found in stable memory.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct StoredValue {
+    /// Tag of the section, name of the region, index of the chunked item, or [`UNTAGGED`] for a
+    /// single value.
+    pub tag: String,
+
+    /// Version of the value.
+    pub version: u32,
+
+    /// Codec the value was serialized with, or `None` for the raw data of the
+    /// [stable collections](crate::collections).
+    pub codec: Option<Codec>,
+
+    /// Length of the serialized value in bytes, as stored in its header.
+    pub len: u64,
+
+    /// Checksum of the serialized value stored in its header, or `None` for the layouts that
+    /// don't store checksums. Only the values written by [`write`](super::write) and
+    /// [`write_atomic`](super::write_atomic) have a checksum.
+    pub checksum: Option<u32>,
+
+    /// The serialized value.
+    pub data: Vec<u8>,
+}
+
+impl StoredValue {
+    /// Decodes the value without knowing its type and formats it as text.
+    ///
+    /// Values serialized with [`Codec::Candid`] are printed in the Candid text format, and values
+    /// serialized with [`Codec::Cbor`] as CBOR values. Values serialized with [`Codec::Binary`]
+    /// and the raw data of the collections cannot be decoded without knowing their types.
+    pub fn pretty(&self) -> Result<String> {
+        match self.codec {
+            Some(Codec::Candid) => Ok(IDLArgs::from_bytes(&self.data)?.to_string()),
+            Some(Codec::Cbor) => {
+                let value: serde_cbor::Value = serde_cbor::from_slice(&self.data)
+                    .map_err(|e| Error::Serialization(e.to_string()))?;
+                Ok(format!("{:?}", value))
+            }
+            Some(Codec::Binary) => Err(Error::Serialization(
+                "values serialized with bincode cannot be decoded without their type".into(),
+            )),
+            None => Err(Error::UnexpectedLayout),
+        }
+    }
+}
+
+/// Contents of stable memory returned by [`inspect`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct StableInfo {
+    /// Layout of the data.
+    pub layout: Layout,
+
+    /// The values stored in stable memory, in the order they are stored.
+    pub values: Vec<StoredValue>,
+}
+
+/// A part of stable memory returned by [`read_page`].
+#[derive(Debug, Clone, CandidType, Deserialize)]
+pub struct StablePage {
+    /// Offset of the page in stable memory.
+    pub offset: u64,
+
+    /// Size of the whole stable memory in bytes.
+    pub total_size: u64,
+
+    /// Contents of the page.
+    pub data: Vec<u8>,
+}
+
+/// Decodes a copy of stable memory.
+///
+/// The checksums of the values are verified, so a corrupted copy is rejected with
+/// [`Error::ChecksumMismatch`] or [`Error::CorruptedLayout`].
+pub fn inspect(bytes: &[u8]) -> Result<StableInfo> {
+    if bytes.len() < VERSION_SIZE {
+        return Ok(StableInfo {
+            layout: Layout::Empty,
+            values: vec![],
+        });
+    }
+
+    match read_u32(bytes, 0)? {
+        FRAME_MARKER => Ok(StableInfo {
+            layout: Layout::Value,
+            values: vec![frame(bytes, 0)?],
+        }),
+        SLOTS_MARKER => {
+            let header = SlotsHeader::from_bytes(array(bytes, 0)?)?;
+            Ok(StableInfo {
+                layout: Layout::Slots {
+                    active: header.active as u32,
+                },
+                values: vec![frame(bytes, header.active_offset())?],
+            })
+        }
+        SECTIONS_MARKER => Ok(StableInfo {
+            layout: Layout::Sections,
+            values: Sections::parse(&bytes[VERSION_SIZE..])?
+                .into_iter()
+                .map(|section| StoredValue {
+                    tag: section.tag,
+                    version: section.version,
+                    codec: Some(section.codec),
+                    len: section.data.len() as u64,
+                    checksum: None,
+                    data: section.data,
+                })
+                .collect(),
+        }),
+        regions::REGIONS_MARKER => inspect_regions(bytes),
+        chunked::CHUNKS_MARKER => inspect_chunks(bytes),
+        marker if is_layout_marker(marker) => Err(Error::CorruptedLayout),
+        version => Ok(StableInfo {
+            layout: Layout::Legacy,
+            values: vec![StoredValue {
+                tag: UNTAGGED.to_string(),
+                version: legacy_version(version),
+                codec: Some(Codec::Candid),
+                len: (bytes.len() - VERSION_SIZE) as u64,
+                checksum: None,
+                data: bytes[VERSION_SIZE..].to_vec(),
+            }],
+        }),
+    }
+}
+
+/// Reads at most `len` bytes of stable memory starting from the given offset, but no more than
+/// [`MAX_PAGE_SIZE`].
+///
+/// The whole stable memory can be downloaded by reading the pages until
+/// [`StablePage::total_size`] is reached. This is usually done from a query method, e.g.:
+///
+/// ```ignore
+/// #[query]
+/// fn export_stable_memory(&self, offset: u64, len: u64) -> StablePage {
+///     // Check that the caller is allowed to read the whole state.
+///     inspect::read_page(offset, len)
+/// }
+/// ```
+pub fn read_page(offset: u64, len: u64) -> StablePage {
+    let total_size = stable64_size() * PAGE_SIZE;
+    let len = len
+        .min(MAX_PAGE_SIZE)
+        .min(total_size.saturating_sub(offset));
+
+    let mut data = vec![0; len as usize];
+    if len > 0 {
+        stable64_read(offset, &mut data);
+    }
+
+    StablePage {
+        offset,
+        total_size,
+        data,
+    }
+}
+
+fn inspect_regions(bytes: &[u8]) -> Result<StableInfo> {
+    let header = array(bytes, 0)?;
+    let count = regions::Directory::entries_count(header)?;
+    let entries = slice_from(
+        bytes,
+        regions::HEADER_SIZE as u64,
+        (count * regions::ENTRY_SIZE) as u64,
+    )?;
+
+    let values = regions::Directory::from_entries(entries)?
+        .entries
+        .into_iter()
+        .map(|entry| {
+            let raw = entry.version == regions::RAW_VERSION;
+            Ok(StoredValue {
+                data: slice_from(bytes, entry.offset, entry.len)?.to_vec(),
+                tag: entry.name,
+                version: entry.version,
+                codec: if raw { None } else { Some(entry.codec) },
+                len: entry.len,
+                checksum: None,
+            })
+        })
+        .collect::<Result<_>>()?;
+
+    Ok(StableInfo {
+        layout: Layout::Regions,
+        values,
+    })
+}
+
+fn inspect_chunks(bytes: &[u8]) -> Result<StableInfo> {
+    let header = chunked::Header::from_bytes(array(bytes, 0)?)?;
+    let finished = header.count != chunked::UNFINISHED;
+
+    // The number of items of an unfinished sequence is unknown, so the items are read until an
+    // empty or truncated one is found.
+    let mut values = vec![];
+    let mut offset = chunked::HEADER_SIZE;
+    while !finished || (values.len() as u64) < header.count {
+        let len = match read_u32(bytes, offset) {
+            Ok(len) => len as u64,
+            Err(_) if !finished => break,
+            Err(e) => return Err(e),
+        };
+
+        let codec = match array::<1>(bytes, offset + chunked::LEN_SIZE)
+            .and_then(|[id]| Codec::from_id(*id))
+        {
+            Ok(codec) => codec,
+            Err(_) if !finished => break,
+            Err(e) => return Err(e),
+        };
+
+        let data = match slice_from(bytes, offset + chunked::ITEM_HEADER_SIZE, len) {
+            Ok(data) if finished || len > 0 => data,
+            Err(e) if finished => return Err(e),
+            _ => break,
+        };
+
+        values.push(StoredValue {
+            tag: values.len().to_string(),
+            version: header.version,
+            codec: Some(codec),
+            len,
+            checksum: None,
+            data: data.to_vec(),
+        });
+        offset += chunked::ITEM_HEADER_SIZE + len;
+    }
+
+    Ok(StableInfo {
+        layout: Layout::Chunks { finished },
+        values,
+    })
+}
+
+/// Reads the value with a header stored at the given offset, verifying its checksum.
+fn frame(bytes: &[u8], offset: u64) -> Result<StoredValue> {
+    let header = FrameHeader::from_bytes(array(bytes, offset)?)?;
+    let data = slice_from(bytes, offset + FRAME_HEADER_SIZE, header.len)?;
+    if crc32fast::hash(data) != header.checksum {
+        return Err(Error::ChecksumMismatch);
+    }
+
+    Ok(StoredValue {
+        tag: UNTAGGED.to_string(),
+        version: header.version,
+        codec: Some(header.codec),
+        len: header.len,
+        checksum: Some(header.checksum),
+        data: data.to_vec(),
+    })
+}
+
+fn slice_from(bytes: &[u8], offset: u64, len: u64) -> Result<&[u8]> {
+    let end = offset.checked_add(len).ok_or(Error::CorruptedLayout)?;
+    if end > bytes.len() as u64 {
+        return Err(Error::CorruptedLayout);
+    }
+
+    Ok(&bytes[offset as usize..end as usize])
+}
+
+fn array<const N: usize>(bytes: &[u8], offset: u64) -> Result<&[u8; N]> {
+    Ok(slice_from(bytes, offset, N as u64)?
+        .try_into()
+        .expect("slice has correct length"))
+}
+
+fn read_u32(bytes: &[u8], offset: u64) -> Result<u32> {
+    Ok(u32::from_le_bytes(*array(bytes, offset)?))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::collections::StableVec;
+    use crate::stable::{write, write_atomic, ChunkedWriter, Versioned};
+    use crate::testing::clear_storage;
+    use serde::Serialize;
+
+    #[derive(Debug, Default, CandidType, Deserialize, Serialize)]
+    struct Value {
+        id: u32,
+    }
+
+    impl Versioned for Value {
+        type Previous = ();
+
+        fn upgrade(_: Self::Previous) -> Self {
+            Self::default()
+        }
+    }
+
+    #[derive(Debug, Default, CandidType, Deserialize, Serialize)]
+    struct CborValue {
+        id: u32,
+    }
+
+    impl Versioned for CborValue {
+        type Previous = Value;
+
+        fn upgrade(previous: Self::Previous) -> Self {
+            Self { id: previous.id }
+        }
+
+        fn encode(&self) -> Result<crate::stable::Encoded> {
+            Codec::Cbor.encode(self)
+        }
+    }
+
+    fn dump() -> Vec<u8> {
+        let mut dump = vec![];
+        loop {
+            let page = read_page(dump.len() as u64, MAX_PAGE_SIZE);
+            assert_eq!(page.offset, dump.len() as u64);
+            dump.extend_from_slice(&page.data);
+            if dump.len() as u64 >= page.total_size {
+                return dump;
+            }
+        }
+    }
+
+    #[test]
+    fn inspect_empty_memory() {
+        clear_storage();
+        let info = inspect(&dump()).unwrap();
+        assert_eq!(info.layout, Layout::Empty);
+        assert!(info.values.is_empty());
+    }
+
+    #[test]
+    fn inspect_single_value() {
+        clear_storage();
+        write(&Value { id: 7 }).unwrap();
+
+        let info = inspect(&dump()).unwrap();
+        assert_eq!(info.layout, Layout::Value);
+        assert_eq!(info.values.len(), 1);
+        assert_eq!(info.values[0].tag, UNTAGGED);
+        assert_eq!(info.values[0].version, 1);
+        assert_eq!(info.values[0].codec, Some(Codec::Candid));
+        assert_eq!(info.values[0].len, info.values[0].data.len() as u64);
+        assert_eq!(
+            info.values[0].checksum,
+            Some(crc32fast::hash(&info.values[0].data))
+        );
+        assert!(info.values[0].pretty().unwrap().contains('7'));
+
+        write_atomic(&CborValue { id: 8 }).unwrap();
+        let info = inspect(&dump()).unwrap();
+        assert_eq!(info.layout, Layout::Slots { active: 0 });
+        assert_eq!(info.values[0].version, 2);
+        assert_eq!(info.values[0].codec, Some(Codec::Cbor));
+        assert_eq!(
+            info.values[0].checksum,
+            Some(crc32fast::hash(&info.values[0].data))
+        );
+        assert!(info.values[0].pretty().unwrap().contains('8'));
+    }
+
+    #[test]
+    fn inspect_corrupted_value() {
+        clear_storage();
+        write(&Value { id: 7 }).unwrap();
+
+        let mut bytes = dump();
+        bytes[FRAME_HEADER_SIZE as usize] ^= 0xff;
+        assert!(matches!(inspect(&bytes), Err(Error::ChecksumMismatch)));
+        assert!(matches!(
+            inspect(&bytes[..FRAME_HEADER_SIZE as usize]),
+            Err(Error::CorruptedLayout)
+        ));
+    }
+
+    #[test]
+    fn inspect_sections() {
+        clear_storage();
+        let mut sections = Sections::default();
+        sections.insert("first", &Value { id: 1 }).unwrap();
+        sections.insert("second", &CborValue { id: 2 }).unwrap();
+        sections.write().unwrap();
+
+        let info = inspect(&dump()).unwrap();
+        assert_eq!(info.layout, Layout::Sections);
+        let tags = info.values.iter().map(|v| (v.tag.as_str(), v.version));
+        assert_eq!(tags.collect::<Vec<_>>(), vec![("first", 1), ("second", 2)]);
+    }
+
+    #[test]
+    fn inspect_regions() {
+        clear_storage();
+        regions::write("value", &Value { id: 3 }).unwrap();
+        let mut vec = StableVec::<u64>::new("vec");
+        vec.push(&4).unwrap();
+
+        let info = inspect(&dump()).unwrap();
+        assert_eq!(info.layout, Layout::Regions);
+        assert_eq!(info.values[0].tag, "value");
+        assert_eq!(info.values[0].len, info.values[0].data.len() as u64);
+        assert_eq!(info.values[0].checksum, None);
+        assert!(info.values[0].pretty().unwrap().contains('3'));
+
+        // The collections are stored in raw regions.
+        assert!(info.values.len() > 1);
+        for value in &info.values[1..] {
+            assert!(value.tag.starts_with("vec"));
+            assert_eq!(value.codec, None);
+            assert!(matches!(value.pretty(), Err(Error::UnexpectedLayout)));
+        }
+    }
+
+    #[test]
+    fn inspect_chunks() {
+        clear_storage();
+        let mut writer = ChunkedWriter::<Value>::new().unwrap();
+        writer.push(&Value { id: 5 }).unwrap();
+        writer.push(&Value { id: 6 }).unwrap();
+
+        let info = inspect(&dump()).unwrap();
+        assert_eq!(info.layout, Layout::Chunks { finished: false });
+        assert_eq!(info.values.len(), 2);
+
+        writer.finish().unwrap();
+        let info = inspect(&dump()).unwrap();
+        assert_eq!(info.layout, Layout::Chunks { finished: true });
+        assert_eq!(info.values[1].tag, "1");
+        assert!(info.values[1].pretty().unwrap().contains('6'));
+    }
+
+    #[test]
+    fn read_page_bounds() {
+        clear_storage();
+        write(&Value { id: 7 }).unwrap();
+
+        let page = read_page(PAGE_SIZE - 10, 100);
+        assert_eq!(page.total_size, PAGE_SIZE);
+        assert_eq!(page.data.len(), 10);
+        assert!(read_page(PAGE_SIZE * 2, 100).data.is_empty());
+    }
+}
@@ -0,0 +1,679 @@
+//! Named regions of stable memory.
+//!
+//! [`write`](super::write) and [`Sections`](super::Sections) always overwrite the whole stable
+//! memory, so all the data must be stored at once. Regions allow different parts of a canister
+//! (the state, metrics, logs, etc.) to store their own [`Versioned`] values independently of each
+//! other, without clobbering what the others have stored.
+//!
+//! The first page of the stable memory contains a directory that maps region names to the
+//! location of the region data:
+//!
+//! ```text
+//!  0 1 2 3 4 5 6 7 8 ... 15 16        80        144
+//! +-+-+-+-+-+-+-+-+--------+---------+---------+-----
+//! |MARKER |COUNT  |reserved| Entry 1 | Entry 2 | ...
+//! +-+-+-+-+-+-+-+-+--------+---------+---------+-----
+//! ```
+//!
+//! Each entry takes 64 bytes:
+//!
+//! ```text
+//!  0       1     32      36    37       40     48    56        64
+//! +-------+-----+-------+-----+--------+------+-----+----------+
+//! |NAMELEN|NAME |VERSION|CODEC|reserved|OFFSET|LEN  |CAPACITY  |
+//! +-------+-----+-------+-----+--------+------+-----+----------+
+//! ```
+//!
+//! All numbers are little-endian. The data of the regions is stored after the directory. When a
+//! region outgrows its capacity, it is moved to a larger free space, and the space it occupied
+//! before can be reused by other regions.
+//!
+//! Regions cannot be used together with [`write`](super::write) or [`Sections`](super::Sections),
+//! as these overwrite the directory. [`init`] can be used to move to regions from these layouts.
+//! Regions also contain the data of the [stable collections](crate::collections).
+//!
+//! ```
+//! use ic_storage::stable::{regions, Versioned};
+//! # use ic_cdk::export::candid::CandidType;
+//! # use serde::Deserialize;
+//!
+//! #[derive(Debug, Default, Deserialize, CandidType)]
+//! struct Metrics {
+//!     calls: u64,
+//! }
+//!
+//! impl Versioned for Metrics {
+//!     type Previous = ();
+//!     fn version() -> u32 { 1 }
+//!     fn upgrade((): ()) -> Self {
+//!         Self::default()
+//!     }
+//! }
+//!
+//! regions::write("metrics", &Metrics { calls: 42 }).unwrap();
+//!
+//! let metrics = regions::read::<Metrics>("metrics").unwrap().unwrap_or_default();
+//! assert_eq!(metrics.calls, 42);
+//! ```
+
+use std::mem::size_of;
+
+#[cfg(not(target_arch = "wasm32"))]
+use crate::testing::{stable64_read, stable64_size, stable64_write};
+
+#[cfg(target_arch = "wasm32")]
+use ic_cdk::api::stable::{stable64_read, stable64_size, stable64_write};
+
+use super::{
+    ensure_size, read_stable_bytes, recursive_upgrade, Codec, Encoded, Sections, Versioned,
+    PAGE_SIZE,
+};
+use crate::{Error, Result};
+
+/// Value stored in place of the version number when stable memory contains a region directory.
+pub(super) const REGIONS_MARKER: u32 = u32::MAX - 1;
+
+/// Maximum length of a region name in bytes.
+pub const MAX_NAME_LEN: usize = 31;
+
+pub(super) const HEADER_SIZE: usize = 16;
+pub(super) const ENTRY_SIZE: usize = 64;
+const MAX_ENTRIES: usize = (PAGE_SIZE as usize - HEADER_SIZE) / ENTRY_SIZE;
+
+/// The directory takes the first page, the data of the regions is stored after it.
+const DATA_START: u64 = PAGE_SIZE;
+
+/// Version stored for the regions that contain raw data used by [`collections`](crate::collections)
+/// rather than a [`Versioned`] value.
+pub(crate) const RAW_VERSION: u32 = u32::MAX;
+
+/// Minimal capacity allocated for a region, so that small regions don't have to be moved every
+/// time they grow a little.
+const MIN_CAPACITY: u64 = 1024;
+
+/// Information about a region stored in stable memory.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RegionInfo {
+    /// Name of the region.
+    pub name: String,
+
+    /// Version of the [`Versioned`] value stored in the region.
+    pub version: u32,
+
+    /// Length of the stored value in bytes.
+    pub len: u64,
+}
+
+/// Load a [`Versioned`] from the region with the given name.
+///
+/// Returns `Ok(None)` if there is no such region.
+pub fn read<T: Versioned>(name: &str) -> Result<Option<T>> {
+    let directory = Directory::load()?;
+    let entry = match directory.find(name) {
+        Some(index) => &directory.entries[index],
+        None => return Ok(None),
+    };
+
+    if entry.version == RAW_VERSION {
+        return Err(Error::UnexpectedLayout);
+    }
+
+    if T::version() < entry.version {
+        return Err(Error::AttemptedDowngrade);
+    }
+
+    let bytes = read_stable_bytes(entry.offset, entry.len)?;
+
+    recursive_upgrade::<T>(entry.version, entry.codec, &bytes).map(Some)
+}
+
+/// Write a [`Versioned`] to the region with the given name, creating the region if it doesn't
+/// exist. Other regions are not affected.
+///
+/// It is not allowed to write an older version than what is currently stored in the region.
+pub fn write<T: Versioned>(name: &str, value: &T) -> Result<()> {
+    let Encoded { codec, data } = value.encode()?;
+
+    let mut directory = Directory::load()?;
+    let index = directory.find_or_insert(name, T::version())?;
+
+    let entry = &directory.entries[index];
+    if entry.version == RAW_VERSION {
+        return Err(Error::UnexpectedLayout);
+    }
+
+    if entry.version > T::version() {
+        return Err(Error::ExistingVersionIsNewer);
+    }
+
+    directory.reserve(index, data.len() as u64, false)?;
+
+    let entry = &mut directory.entries[index];
+    entry.version = T::version();
+    entry.codec = codec;
+    entry.len = data.len() as u64;
+    stable64_write(entry.offset, &data);
+
+    directory.store()
+}
+
+/// Prepare stable memory to store regions.
+///
+/// If stable memory contains the data written with [`write`](super::write) or
+/// [`Sections`](super::Sections), it is returned as `Sections`, so that it can be restored, and
+/// the memory is reformatted to contain an empty region directory. Otherwise, nothing is changed
+/// and `Ok(None)` is returned.
+pub fn init() -> Result<Option<Sections>> {
+    if stable64_size() == 0 {
+        return Ok(None);
+    }
+
+    let mut marker = [0; size_of::<u32>()];
+    stable64_read(0, &mut marker);
+    if u32::from_le_bytes(marker) == REGIONS_MARKER {
+        return Ok(None);
+    }
+
+    let sections = Sections::read()?;
+    Directory { entries: vec![] }.store()?;
+
+    Ok(Some(sections))
+}
+
+/// Raw regions used by the [collections](crate::collections).
+///
+/// The directory is loaded once for a whole collection operation, and the regions are addressed
+/// by [`RawRegion`] handles rather than by their names. The changes of the directory are stored by
+/// [`RawRegions::commit`].
+pub(crate) struct RawRegions {
+    directory: Directory,
+    /// Number of entries loaded from stable memory. The entries after them were added by
+    /// [`RawRegions::open`] during this operation.
+    loaded: usize,
+    changed: bool,
+}
+
+/// Handle to a region in the directory loaded by [`RawRegions::load`].
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct RawRegion(usize);
+
+impl RawRegions {
+    pub(crate) fn load() -> Result<Self> {
+        let directory = Directory::load()?;
+        Ok(Self {
+            loaded: directory.entries.len(),
+            directory,
+            changed: false,
+        })
+    }
+
+    /// Returns the handle to the raw region with the given name. A region that doesn't exist is
+    /// empty, and it is added to the directory only if something is written to it.
+    pub(crate) fn open(&mut self, name: &str) -> Result<RawRegion> {
+        let index = match self.directory.find(name) {
+            Some(index) => index,
+            None => {
+                self.directory.entries.push(Entry {
+                    name: name.to_string(),
+                    version: RAW_VERSION,
+                    codec: Codec::Candid,
+                    offset: 0,
+                    len: 0,
+                    capacity: 0,
+                });
+                self.directory.entries.len() - 1
+            }
+        };
+
+        if self.directory.entries[index].version != RAW_VERSION {
+            return Err(Error::UnexpectedLayout);
+        }
+
+        Ok(RawRegion(index))
+    }
+
+    /// Length of the region in bytes.
+    pub(crate) fn len(&self, region: RawRegion) -> u64 {
+        self.directory.entries[region.0].len
+    }
+
+    /// Read bytes from the region starting at the given offset.
+    pub(crate) fn read(&self, region: RawRegion, offset: u64, buf: &mut [u8]) -> Result<()> {
+        let entry = &self.directory.entries[region.0];
+        let end = offset
+            .checked_add(buf.len() as u64)
+            .ok_or(Error::CorruptedLayout)?;
+        if end > entry.len {
+            return Err(Error::CorruptedLayout);
+        }
+
+        stable64_read(entry.offset + offset, buf);
+        Ok(())
+    }
+
+    /// Write bytes to the region starting at the given offset, extending it if needed. The data
+    /// already stored in the region is preserved when the region is moved.
+    pub(crate) fn write(&mut self, region: RawRegion, offset: u64, buf: &[u8]) -> Result<()> {
+        let end = offset + buf.len() as u64;
+        self.directory.reserve(region.0, end, true)?;
+
+        let entry = &mut self.directory.entries[region.0];
+        stable64_write(entry.offset + offset, buf);
+
+        if entry.len < end {
+            entry.len = end;
+        }
+
+        self.changed = true;
+        Ok(())
+    }
+
+    /// Shrink the region to the given length. The capacity of the region is not changed.
+    pub(crate) fn truncate(&mut self, region: RawRegion, len: u64) {
+        let entry = &mut self.directory.entries[region.0];
+        if entry.len > len {
+            entry.len = len;
+            self.changed = true;
+        }
+    }
+
+    /// Stores the directory if it was changed. The regions that were opened, but never written
+    /// to, are not added to it.
+    pub(crate) fn commit(mut self) -> Result<()> {
+        if !self.changed {
+            return Ok(());
+        }
+
+        let loaded = self.loaded;
+        let mut index = 0;
+        self.directory.entries.retain(|entry| {
+            index += 1;
+            index <= loaded || entry.capacity > 0
+        });
+
+        if let Some(entry) = self.directory.entries[loaded..]
+            .iter()
+            .find(|entry| entry.name.len() > MAX_NAME_LEN)
+        {
+            return Err(Error::InvalidRegionName(entry.name.clone()));
+        }
+
+        if self.directory.entries.len() > MAX_ENTRIES {
+            return Err(Error::DirectoryFull);
+        }
+
+        self.directory.store()
+    }
+}
+
+/// Remove the region with the given name. The space used by the region can then be reused by
+/// other regions.
+///
+/// Returns `false` if there was no such region.
+pub fn remove(name: &str) -> Result<bool> {
+    let mut directory = Directory::load()?;
+    match directory.find(name) {
+        Some(index) => {
+            directory.entries.remove(index);
+            directory.store()?;
+            Ok(true)
+        }
+        None => Ok(false),
+    }
+}
+
+/// List all the regions stored in stable memory.
+pub fn list() -> Result<Vec<RegionInfo>> {
+    let directory = Directory::load()?;
+    Ok(directory
+        .entries
+        .into_iter()
+        .map(|entry| RegionInfo {
+            name: entry.name,
+            version: entry.version,
+            len: entry.len,
+        })
+        .collect())
+}
+
+pub(super) struct Entry {
+    pub(super) name: String,
+    pub(super) version: u32,
+    pub(super) codec: Codec,
+    pub(super) offset: u64,
+    pub(super) len: u64,
+    capacity: u64,
+}
+
+pub(super) struct Directory {
+    pub(super) entries: Vec<Entry>,
+}
+
+impl Directory {
+    /// Load the directory from stable memory. If stable memory is empty, an empty directory is
+    /// returned.
+    ///
+    /// Returns [`Error::CorruptedLayout`] if the directory or the space reserved for a region is
+    /// out of the memory bounds.
+    fn load() -> Result<Self> {
+        if stable64_size() == 0 {
+            return Ok(Self { entries: vec![] });
+        }
+
+        let mut header = [0; HEADER_SIZE];
+        stable64_read(0, &mut header);
+        let count = Self::entries_count(&header)?;
+
+        let bytes = read_stable_bytes(HEADER_SIZE as u64, (count * ENTRY_SIZE) as u64)?;
+        let directory = Self::from_entries(&bytes)?;
+
+        let memory_size = stable64_size() * PAGE_SIZE;
+        for entry in &directory.entries {
+            let end = entry
+                .offset
+                .checked_add(entry.capacity)
+                .ok_or(Error::CorruptedLayout)?;
+            if entry.len > entry.capacity || end > memory_size {
+                return Err(Error::CorruptedLayout);
+            }
+        }
+
+        Ok(directory)
+    }
+
+    /// Number of entries in the directory with the given header.
+    pub(super) fn entries_count(header: &[u8; HEADER_SIZE]) -> Result<usize> {
+        if read_u32(&header[0..]) != REGIONS_MARKER {
+            return Err(Error::UnexpectedLayout);
+        }
+
+        let count = read_u32(&header[4..]) as usize;
+        if count > MAX_ENTRIES {
+            return Err(Error::CorruptedLayout);
+        }
+
+        Ok(count)
+    }
+
+    /// Parses the entries stored after the directory header.
+    pub(super) fn from_entries(bytes: &[u8]) -> Result<Self> {
+        let entries = bytes
+            .chunks_exact(ENTRY_SIZE)
+            .map(|entry| {
+                let name_len = entry[0] as usize;
+                if name_len > MAX_NAME_LEN {
+                    return Err(Error::CorruptedLayout);
+                }
+
+                let name = String::from_utf8(entry[1..1 + name_len].to_vec())
+                    .map_err(|_| Error::CorruptedLayout)?;
+
+                Ok(Entry {
+                    name,
+                    version: read_u32(&entry[32..]),
+                    codec: Codec::from_id(entry[36])?,
+                    offset: read_u64(&entry[40..]),
+                    len: read_u64(&entry[48..]),
+                    capacity: read_u64(&entry[56..]),
+                })
+            })
+            .collect::<Result<_>>()?;
+
+        Ok(Self { entries })
+    }
+
+    fn store(&self) -> Result<()> {
+        let mut bytes = vec![0; HEADER_SIZE + self.entries.len() * ENTRY_SIZE];
+        bytes[0..4].copy_from_slice(&REGIONS_MARKER.to_le_bytes());
+        bytes[4..8].copy_from_slice(&(self.entries.len() as u32).to_le_bytes());
+
+        for (entry, buf) in self
+            .entries
+            .iter()
+            .zip(bytes[HEADER_SIZE..].chunks_exact_mut(ENTRY_SIZE))
+        {
+            buf[0] = entry.name.len() as u8;
+            buf[1..1 + entry.name.len()].copy_from_slice(entry.name.as_bytes());
+            buf[32..36].copy_from_slice(&entry.version.to_le_bytes());
+            buf[36] = entry.codec.id();
+            buf[40..48].copy_from_slice(&entry.offset.to_le_bytes());
+            buf[48..56].copy_from_slice(&entry.len.to_le_bytes());
+            buf[56..64].copy_from_slice(&entry.capacity.to_le_bytes());
+        }
+
+        ensure_size(DATA_START)?;
+        stable64_write(0, &bytes);
+
+        Ok(())
+    }
+
+    fn find(&self, name: &str) -> Option<usize> {
+        self.entries.iter().position(|entry| entry.name == name)
+    }
+
+    /// Returns the index of the entry with the given name, adding an empty entry if there is none.
+    fn find_or_insert(&mut self, name: &str, version: u32) -> Result<usize> {
+        if let Some(index) = self.find(name) {
+            return Ok(index);
+        }
+
+        if name.len() > MAX_NAME_LEN {
+            return Err(Error::InvalidRegionName(name.to_string()));
+        }
+
+        if self.entries.len() == MAX_ENTRIES {
+            return Err(Error::DirectoryFull);
+        }
+
+        self.entries.push(Entry {
+            name: name.to_string(),
+            version,
+            codec: Codec::Candid,
+            offset: 0,
+            len: 0,
+            capacity: 0,
+        });
+
+        Ok(self.entries.len() - 1)
+    }
+
+    /// Ensures that the entry has at least the given capacity, moving it to a larger free space
+    /// if needed. If `preserve` is true, the data of the entry is copied to the new location.
+    fn reserve(&mut self, index: usize, required: u64, preserve: bool) -> Result<()> {
+        if self.entries[index].capacity >= required {
+            return Ok(());
+        }
+
+        let capacity = (required + required / 2).max(MIN_CAPACITY);
+        let offset = self.allocate(index, capacity);
+        ensure_size(offset + capacity)?;
+
+        let entry = &mut self.entries[index];
+        if preserve {
+            copy_within(entry.offset, offset, entry.len);
+        }
+
+        entry.offset = offset;
+        entry.capacity = capacity;
+
+        Ok(())
+    }
+
+    /// Find the first free space of the given capacity, ignoring the space currently occupied by
+    /// the entry with index `moved`.
+    fn allocate(&self, moved: usize, capacity: u64) -> u64 {
+        let mut used = self
+            .entries
+            .iter()
+            .enumerate()
+            .filter(|(index, entry)| *index != moved && entry.capacity > 0)
+            .map(|(_, entry)| (entry.offset, entry.capacity))
+            .collect::<Vec<_>>();
+        used.sort_unstable();
+
+        let mut start = DATA_START;
+        for (offset, used_capacity) in used {
+            if offset >= start + capacity {
+                break;
+            }
+
+            start = start.max(offset + used_capacity);
+        }
+
+        start
+    }
+}
+
+/// Copy `len` bytes of stable memory from `from` to `to`. The ranges may overlap.
+fn copy_within(from: u64, to: u64, len: u64) {
+    let mut buf = vec![0; len.min(PAGE_SIZE) as usize];
+    let mut copied = 0;
+    while copied < len {
+        let chunk = (len - copied).min(PAGE_SIZE);
+        // When moving the data forward, copy it from the end, so that the source is not
+        // overwritten before it is copied.
+        let position = if to > from {
+            len - copied - chunk
+        } else {
+            copied
+        };
+
+        let buf = &mut buf[..chunk as usize];
+        stable64_read(from + position, buf);
+        stable64_write(to + position, buf);
+        copied += chunk;
+    }
+}
+
+fn read_u32(bytes: &[u8]) -> u32 {
+    u32::from_le_bytes(
+        bytes[..size_of::<u32>()]
+            .try_into()
+            .expect("slice has correct length"),
+    )
+}
+
+fn read_u64(bytes: &[u8]) -> u64 {
+    u64::from_le_bytes(
+        bytes[..size_of::<u64>()]
+            .try_into()
+            .expect("slice has correct length"),
+    )
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use candid::CandidType;
+    use serde::Deserialize;
+
+    #[derive(Debug, Default, CandidType, Deserialize)]
+    struct Blob(Vec<u8>);
+
+    impl Versioned for Blob {
+        type Previous = ();
+        fn version() -> u32 {
+            1
+        }
+
+        fn upgrade(_: Self::Previous) -> Self {
+            Self::default()
+        }
+    }
+
+    #[derive(Debug, CandidType, Deserialize)]
+    struct Counter(u32);
+
+    #[derive(Debug, CandidType, Deserialize)]
+    struct CounterV2(u32, u32);
+
+    impl Versioned for Counter {
+        type Previous = ();
+        fn version() -> u32 {
+            1
+        }
+
+        fn upgrade(_: Self::Previous) -> Self {
+            Self(0)
+        }
+    }
+
+    impl Versioned for CounterV2 {
+        type Previous = Counter;
+        fn version() -> u32 {
+            2
+        }
+
+        fn upgrade(previous: Self::Previous) -> Self {
+            Self(previous.0, 10)
+        }
+    }
+
+    #[test]
+    fn regions_are_independent() {
+        write("first", &Counter(1)).unwrap();
+        write("second", &Counter(2)).unwrap();
+        write("first", &Counter(3)).unwrap();
+
+        assert_eq!(read::<Counter>("first").unwrap().unwrap().0, 3);
+        assert_eq!(read::<Counter>("second").unwrap().unwrap().0, 2);
+        assert!(read::<Counter>("third").unwrap().is_none());
+    }
+
+    #[test]
+    fn upgrade_region() {
+        write("counter", &Counter(1)).unwrap();
+
+        let CounterV2(a, b) = read::<CounterV2>("counter").unwrap().unwrap();
+        assert_eq!((a, b), (1, 10));
+
+        write("counter", &CounterV2(2, 3)).unwrap();
+        let err = write("counter", &Counter(4)).unwrap_err();
+        assert!(matches!(err, Error::ExistingVersionIsNewer));
+
+        let err = read::<Counter>("counter").unwrap_err();
+        assert!(matches!(err, Error::AttemptedDowngrade));
+    }
+
+    #[test]
+    fn grown_region_is_relocated() {
+        write("first", &Blob(vec![1; 10])).unwrap();
+        write("second", &Blob(vec![2; 10])).unwrap();
+        write("first", &Blob(vec![3; 5000])).unwrap();
+
+        assert_eq!(read::<Blob>("first").unwrap().unwrap().0, vec![3; 5000]);
+        assert_eq!(read::<Blob>("second").unwrap().unwrap().0, vec![2; 10]);
+
+        // The space freed by the first region is reused.
+        write("third", &Blob(vec![4; 10])).unwrap();
+        assert_eq!(read::<Blob>("third").unwrap().unwrap().0, vec![4; 10]);
+        assert_eq!(read::<Blob>("first").unwrap().unwrap().0, vec![3; 5000]);
+    }
+
+    #[test]
+    fn remove_and_list_regions() {
+        write("first", &Counter(1)).unwrap();
+        write("second", &CounterV2(1, 2)).unwrap();
+
+        assert!(remove("first").unwrap());
+        assert!(!remove("first").unwrap());
+
+        let list = list().unwrap();
+        assert_eq!(list.len(), 1);
+        assert_eq!(list[0].name, "second");
+        assert_eq!(list[0].version, 2);
+    }
+
+    #[test]
+    fn invalid_region_name() {
+        let err = write(&"a".repeat(MAX_NAME_LEN + 1), &Counter(1)).unwrap_err();
+        assert!(matches!(err, Error::InvalidRegionName(_)));
+    }
+
+    #[test]
+    fn regions_do_not_mix_with_single_value() {
+        super::super::write(&Counter(1)).unwrap();
+        let err = write("first", &Counter(1)).unwrap_err();
+        assert!(matches!(err, Error::UnexpectedLayout));
+    }
+}
@@ -1,84 +1,151 @@
 use std::cell::RefCell;
+use std::collections::HashMap;
 use std::io;
 
 use ic_cdk::api::stable::StableMemoryError;
 
+mod states;
+pub use states::*;
+
+const PAGE_SIZE: u64 = 1 << 16;
+
+/// Maximum number of pages the mocked stable memory can be grown to.
+pub const MAX_PAGES: u64 = 1 << 32;
+
+/// Mocked 64-bit stable memory. Only the pages that were written to are allocated, so tests can
+/// grow the memory far beyond the available heap.
+#[derive(Default)]
+struct Storage {
+    /// Size of the memory in pages.
+    size: u64,
+    pages: HashMap<u64, Box<[u8]>>,
+}
+
 thread_local! {
-    static STORAGE: RefCell<Vec<u8>> = RefCell::new(vec![]);
+    static STORAGE: RefCell<Storage> = RefCell::new(Storage::default());
 }
 
 pub fn clear_storage() {
     STORAGE.with(|s| {
-        s.borrow_mut().clear();
+        *s.borrow_mut() = Storage::default();
     });
 }
 
 /// Return the page count, not the total bytes in storage.
 /// This is how ic_cdk works
-pub fn stable_size() -> u32 {
-    STORAGE.with(|s| s.borrow().len()) as u32 >> 16
-}
-
-pub fn stable_bytes() -> Vec<u8> {
-    let size = (stable_size() as usize) << 16;
-    let mut vec = Vec::with_capacity(size);
-
-    // This is super dodgy, don't do this.
-    // This is copied from the current implementation of stable storage.
-    #[allow(clippy::uninit_vec)]
-    unsafe {
-        vec.set_len(size);
-    }
-
-    stable_read(0, vec.as_mut_slice());
-
-    vec
+pub fn stable64_size() -> u64 {
+    STORAGE.with(|s| s.borrow().size)
 }
 
-pub fn stable_read(offset: u32, buf: &mut [u8]) {
+pub fn stable64_read(offset: u64, buf: &mut [u8]) {
     STORAGE.with(|storage| {
-        let offset = offset as usize;
-        buf.copy_from_slice(&storage.borrow()[offset..offset + buf.len()]);
+        let storage = storage.borrow();
+        check_bounds(storage.size, offset, buf.len());
+
+        for_each_page(
+            offset,
+            buf.len(),
+            |page, page_offset, range| match storage.pages.get(&page) {
+                Some(data) => buf[range.clone()]
+                    .copy_from_slice(&data[page_offset..page_offset + range.len()]),
+                None => buf[range].fill(0),
+            },
+        );
     });
 }
 
-pub fn stable_write(offset: u32, buf: &[u8]) {
+pub fn stable64_write(offset: u64, buf: &[u8]) {
     STORAGE.with(|storage| {
-        let offset = offset as usize;
-        storage.borrow_mut()[offset..offset + buf.len()].copy_from_slice(buf);
+        let mut storage = storage.borrow_mut();
+        check_bounds(storage.size, offset, buf.len());
+
+        for_each_page(offset, buf.len(), |page, page_offset, range| {
+            let data = storage
+                .pages
+                .entry(page)
+                .or_insert_with(|| vec![0; PAGE_SIZE as usize].into_boxed_slice());
+            data[page_offset..page_offset + range.len()].copy_from_slice(&buf[range]);
+        });
     });
 }
 
-pub fn stable_grow(new_pages: u32) -> Result<u32, StableMemoryError> {
+pub fn stable64_grow(new_pages: u64) -> Result<u64, StableMemoryError> {
     STORAGE.with(|storage| {
-        let additional_len = (new_pages << 16) as usize;
-        let len = storage.borrow().len();
-        match len + additional_len >= u32::MAX as usize {
-            false => {
-                let previous_size = storage.borrow().len() >> 16;
-                storage.borrow_mut().append(&mut vec![0u8; additional_len]);
-                Ok(previous_size as u32)
+        let mut storage = storage.borrow_mut();
+        let previous_size = storage.size;
+        match previous_size.checked_add(new_pages) {
+            Some(size) if size <= MAX_PAGES => {
+                storage.size = size;
+                Ok(previous_size)
             }
-            true => Err(StableMemoryError::OutOfMemory),
+            _ => Err(StableMemoryError::OutOfMemory),
         }
     })
 }
 
+/// Return the page count, not the total bytes in storage.
+/// This is how ic_cdk works
+pub fn stable_size() -> u32 {
+    stable64_size() as u32
+}
+
+pub fn stable_bytes() -> Vec<u8> {
+    let mut vec = vec![0; (stable64_size() * PAGE_SIZE) as usize];
+    stable64_read(0, &mut vec);
+    vec
+}
+
+pub fn stable_read(offset: u32, buf: &mut [u8]) {
+    stable64_read(offset as u64, buf)
+}
+
+pub fn stable_write(offset: u32, buf: &[u8]) {
+    stable64_write(offset as u64, buf)
+}
+
+pub fn stable_grow(new_pages: u32) -> Result<u32, StableMemoryError> {
+    if stable64_size() + new_pages as u64 > u32::MAX as u64 >> 16 {
+        return Err(StableMemoryError::OutOfMemory);
+    }
+
+    stable64_grow(new_pages as u64).map(|size| size as u32)
+}
+
+/// Panics if the range is out of the memory bounds, like the IC traps in this case.
+fn check_bounds(size: u64, offset: u64, len: usize) {
+    if offset + len as u64 > size * PAGE_SIZE {
+        panic!("stable memory out of bounds");
+    }
+}
+
+/// Splits the byte range into the parts lying in different pages, calling `f` with the page
+/// index, the offset inside the page and the corresponding range of the buffer.
+fn for_each_page(offset: u64, len: usize, mut f: impl FnMut(u64, usize, std::ops::Range<usize>)) {
+    let mut done = 0;
+    while done < len {
+        let position = offset + done as u64;
+        let page_offset = (position % PAGE_SIZE) as usize;
+        let chunk = (PAGE_SIZE as usize - page_offset).min(len - done);
+        f(position / PAGE_SIZE, page_offset, done..done + chunk);
+        done += chunk;
+    }
+}
+
 /// A writer to the stable memory.
 ///
 /// Will attempt to grow the memory as it writes,
 /// and keep offsets and total capacity.
 pub struct StableWriter {
     /// The offset of the next write.
-    offset: usize,
+    offset: u64,
 
     /// The capacity, in pages.
-    capacity: u32,
+    capacity: u64,
 }
 
 impl Default for StableWriter {
     fn default() -> Self {
-        let capacity = stable_size();
+        let capacity = stable64_size();
 
         Self {
             offset: 0,
@@ -89,8 +156,8 @@ impl Default for StableWriter {
 
 impl StableWriter {
     /// Attempts to grow the memory by adding new pages.
-    pub fn grow(&mut self, added_pages: u32) -> Result<(), StableMemoryError> {
-        let old_page_count = stable_grow(added_pages)?;
+    pub fn grow(&mut self, added_pages: u64) -> Result<(), StableMemoryError> {
+        let old_page_count = stable64_grow(added_pages)?;
         self.capacity = old_page_count + added_pages;
         Ok(())
     }
@@ -100,12 +167,13 @@ impl StableWriter {
     /// The only condition where this will
     /// error out is if it cannot grow the memory.
     pub fn write(&mut self, buf: &[u8]) -> Result<usize, StableMemoryError> {
-        if self.offset + buf.len() > ((self.capacity as usize) << 16) {
-            self.grow((buf.len() >> 16) as u32 + 1)?;
+        let end = self.offset + buf.len() as u64;
+        if end > self.capacity * PAGE_SIZE {
+            self.grow((end - self.capacity * PAGE_SIZE + PAGE_SIZE - 1) / PAGE_SIZE)?;
         }
 
-        stable_write(self.offset as u32, buf);
-        self.offset += buf.len();
+        stable64_write(self.offset, buf);
+        self.offset = end;
         Ok(buf.len())
     }
 }
@@ -121,3 +189,38 @@ impl io::Write for StableWriter {
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn sparse_memory_beyond_4_gib() {
+        let offset = 5 << 30;
+        assert!(stable64_grow(offset / PAGE_SIZE + 1).is_ok());
+
+        // The write crosses the page boundary.
+        let position = offset + PAGE_SIZE - 2;
+        assert!(stable64_grow(1).is_ok());
+        stable64_write(position, &[1, 2, 3, 4]);
+
+        let mut buf = [0; 6];
+        stable64_read(position - 1, &mut buf);
+        assert_eq!(buf, [0, 1, 2, 3, 4, 0]);
+
+        STORAGE.with(|s| assert_eq!(s.borrow().pages.len(), 2));
+    }
+
+    #[test]
+    fn grow_beyond_max_pages() {
+        assert!(stable64_grow(MAX_PAGES).is_ok());
+        assert!(stable64_grow(1).is_err());
+    }
+
+    #[test]
+    #[should_panic]
+    fn read_out_of_bounds() {
+        assert!(stable64_grow(1).is_ok());
+        stable64_read(PAGE_SIZE - 1, &mut [0; 2]);
+    }
+}
@@ -0,0 +1,334 @@
+//! Access to the [`IcStorage`](crate::IcStorage) states of the mocked canisters.
+//!
+//! In the testing environment `IcStorage::get()` returns a separate state for each canister id.
+//! The functions of this module work with all the states of one canister at once, so a test can
+//! reset a canister or roll it back to a known state between test cases without re-creating the
+//! whole `MockContext`:
+//!
+//! ```
+//! # ic_canister::ic_kit::MockContext::new().inject();
+//! use ic_storage::testing::{reset_storage, snapshot_storage};
+//! use ic_storage::IcStorage;
+//! # use ic_cdk::export::candid::{CandidType, Deserialize};
+//!
+//! #[derive(IcStorage, Default, CandidType, Deserialize)]
+//! struct Balances {
+//!     total: u64,
+//! }
+//!
+//! let canister = ic_canister::ic_kit::ic::id();
+//! Balances::get().borrow_mut().total = 100;
+//! let snapshot = snapshot_storage(canister);
+//!
+//! Balances::get().borrow_mut().total = 0;
+//! snapshot.restore();
+//! assert_eq!(Balances::get().borrow().total, 100);
+//!
+//! reset_storage(canister);
+//! assert_eq!(Balances::get().borrow().total, 0);
+//! ```
+//!
+//! A snapshot copies the states with `Clone` if they implement it, or by serializing them with
+//! candid otherwise. The states of generic types can only be reset.
+
+use std::any::{type_name, TypeId};
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::marker::PhantomData;
+use std::rc::Rc;
+
+use ic_cdk::export::candid::{decode_one, encode_one, CandidType, Deserialize};
+use ic_cdk::export::Principal;
+
+/// Function that sets the state to a previously saved value.
+type Restore = Rc<dyn Fn()>;
+
+struct Instance {
+    type_id: TypeId,
+    type_name: &'static str,
+    reset: Restore,
+    /// Copies the current value of the state and returns the function that restores it.
+    copy: Option<Rc<dyn Fn() -> Restore>>,
+}
+
+thread_local! {
+    static INSTANCES: RefCell<HashMap<Principal, Vec<Instance>>> = RefCell::new(HashMap::default());
+}
+
+/// Adds the state of the canister to the list of its states. Used by the `IcStorage` derive
+/// macro.
+#[doc(hidden)]
+pub fn register_state<T: Default + 'static>(
+    principal: Principal,
+    state: &Rc<RefCell<T>>,
+    copier: Option<fn(&T) -> T>,
+) {
+    let reset_state = state.clone();
+    let reset = Rc::new(move || {
+        reset_state.replace(T::default());
+    });
+
+    let copy = copier.map(|copier| {
+        let state = state.clone();
+        Rc::new(move || {
+            let saved = copier(&state.borrow());
+            let state = state.clone();
+            Rc::new(move || {
+                state.replace(copier(&saved));
+            }) as Restore
+        }) as Rc<dyn Fn() -> Restore>
+    });
+
+    INSTANCES.with(|instances| {
+        instances
+            .borrow_mut()
+            .entry(principal)
+            .or_default()
+            .push(Instance {
+                type_id: TypeId::of::<T>(),
+                type_name: type_name::<T>(),
+                reset,
+                copy,
+            })
+    });
+}
+
+/// Returns the names of the types of all the states of the canister, that were accessed with
+/// `IcStorage::get()`.
+pub fn storage_types(principal: Principal) -> Vec<&'static str> {
+    INSTANCES.with(|instances| {
+        instances
+            .borrow()
+            .get(&principal)
+            .map(|states| states.iter().map(|state| state.type_name).collect())
+            .unwrap_or_default()
+    })
+}
+
+/// Resets all the states of the canister to their default values.
+///
+/// The states are reset in place, so the canister instances that hold references to them see the
+/// default values as well.
+pub fn reset_storage(principal: Principal) {
+    let resets = INSTANCES.with(|instances| {
+        instances
+            .borrow()
+            .get(&principal)
+            .map(|states| states.iter().map(|state| state.reset.clone()).collect())
+            .unwrap_or_else(Vec::new)
+    });
+
+    for reset in resets {
+        reset();
+    }
+}
+
+/// Takes a snapshot of all the states of the canister.
+///
+/// # Panics
+///
+/// Panics if one of the states implements neither `Clone`, nor `CandidType` and `Deserialize`, or
+/// is a generic type, as such states cannot be copied.
+pub fn snapshot_storage(principal: Principal) -> StorageSnapshot {
+    let copies = INSTANCES.with(|instances| {
+        instances
+            .borrow()
+            .get(&principal)
+            .map(|states| {
+                states
+                    .iter()
+                    .map(|state| match &state.copy {
+                        Some(copy) => (state.type_id, copy.clone()),
+                        None => panic!(
+                            "cannot take a snapshot of `{}`: the state must implement `Clone` or \
+                             `CandidType` and `Deserialize`, and must not be generic",
+                            state.type_name
+                        ),
+                    })
+                    .collect::<Vec<_>>()
+            })
+            .unwrap_or_default()
+    });
+
+    // The states are copied after the list is released, as copying a state value can access
+    // other states.
+    let states = copies
+        .into_iter()
+        .map(|(type_id, copy)| (type_id, copy()))
+        .collect();
+
+    StorageSnapshot { principal, states }
+}
+
+/// A copy of all the states of a canister taken by [`snapshot_storage`].
+pub struct StorageSnapshot {
+    principal: Principal,
+    states: Vec<(TypeId, Restore)>,
+}
+
+impl StorageSnapshot {
+    /// The id of the canister the snapshot was taken of.
+    pub fn principal(&self) -> Principal {
+        self.principal
+    }
+
+    /// Rolls all the states of the canister back to the snapshot. The states that were first
+    /// accessed after the snapshot was taken are reset to their default values.
+    ///
+    /// The snapshot can be restored any number of times.
+    pub fn restore(&self) {
+        let restores = INSTANCES.with(|instances| {
+            instances
+                .borrow()
+                .get(&self.principal)
+                .map(|states| {
+                    states
+                        .iter()
+                        .map(|state| {
+                            self.states
+                                .iter()
+                                .find(|(type_id, _)| *type_id == state.type_id)
+                                .map(|(_, restore)| restore.clone())
+                                .unwrap_or_else(|| state.reset.clone())
+                        })
+                        .collect::<Vec<_>>()
+                })
+                .unwrap_or_default()
+        });
+
+        // The states are changed after the list is released, as dropping or creating a state
+        // value can access other states.
+        for restore in restores {
+            restore();
+        }
+    }
+}
+
+/// Selects the way to copy the state of type `T` for snapshots. Used by the `IcStorage` derive
+/// macro as `(&&&CopyProbe::<T>::new()).copier()` with the `Copy*` traits in scope: the method of
+/// the first trait, which bounds are satisfied by `T`, is called.
+#[doc(hidden)]
+pub struct CopyProbe<T>(PhantomData<T>);
+
+impl<T> CopyProbe<T> {
+    #[allow(clippy::new_without_default)]
+    pub fn new() -> Self {
+        Self(PhantomData)
+    }
+}
+
+#[doc(hidden)]
+pub trait CopyByClone<T> {
+    fn copier(&self) -> Option<fn(&T) -> T>;
+}
+
+impl<T: Clone> CopyByClone<T> for &&CopyProbe<T> {
+    fn copier(&self) -> Option<fn(&T) -> T> {
+        Some(T::clone)
+    }
+}
+
+#[doc(hidden)]
+pub trait CopyByCandid<T> {
+    fn copier(&self) -> Option<fn(&T) -> T>;
+}
+
+impl<T: CandidType + for<'de> Deserialize<'de>> CopyByCandid<T> for &CopyProbe<T> {
+    fn copier(&self) -> Option<fn(&T) -> T> {
+        Some(|value| {
+            let bytes = encode_one(value).unwrap_or_else(|e| {
+                panic!("failed to take a snapshot of `{}`: {e}", type_name::<T>())
+            });
+            decode_one(&bytes).unwrap_or_else(|e| {
+                panic!(
+                    "failed to restore a snapshot of `{}`: {e}",
+                    type_name::<T>()
+                )
+            })
+        })
+    }
+}
+
+#[doc(hidden)]
+pub trait CopyUnsupported<T> {
+    fn copier(&self) -> Option<fn(&T) -> T>;
+}
+
+impl<T> CopyUnsupported<T> for CopyProbe<T> {
+    fn copier(&self) -> Option<fn(&T) -> T> {
+        None
+    }
+}
+
+#[cfg(test)]
+// The probe must be referenced as in the derive macro to select the same copier.
+#[allow(clippy::needless_borrow)]
+mod test {
+    use super::*;
+
+    #[derive(Default, Clone)]
+    struct Cloned(u32);
+
+    #[derive(Default, CandidType, Deserialize)]
+    struct Serialized(u32);
+
+    #[derive(Default)]
+    struct Opaque(u32);
+
+    fn principal(id: u8) -> Principal {
+        Principal::from_slice(&[id])
+    }
+
+    fn register<T: Default + 'static>(id: u8, copier: Option<fn(&T) -> T>) -> Rc<RefCell<T>> {
+        let state = Rc::new(RefCell::new(T::default()));
+        register_state(principal(id), &state, copier);
+        state
+    }
+
+    #[test]
+    fn copier_selection() {
+        assert!((&&&CopyProbe::<Cloned>::new()).copier().is_some());
+        assert!((&&&CopyProbe::<Serialized>::new()).copier().is_some());
+        assert!((&&&CopyProbe::<Opaque>::new()).copier().is_none());
+    }
+
+    #[test]
+    fn snapshot_and_restore() {
+        let cloned = register(1, (&&&CopyProbe::<Cloned>::new()).copier());
+        let serialized = register(1, (&&&CopyProbe::<Serialized>::new()).copier());
+        let other = register(2, (&&&CopyProbe::<Cloned>::new()).copier());
+
+        cloned.borrow_mut().0 = 1;
+        serialized.borrow_mut().0 = 2;
+        other.borrow_mut().0 = 3;
+        assert_eq!(storage_types(principal(1)).len(), 2);
+
+        let snapshot = snapshot_storage(principal(1));
+        cloned.borrow_mut().0 = 10;
+        serialized.borrow_mut().0 = 20;
+        let added = register::<Opaque>(1, None);
+        added.borrow_mut().0 = 30;
+
+        snapshot.restore();
+        assert_eq!(cloned.borrow().0, 1);
+        assert_eq!(serialized.borrow().0, 2);
+        assert_eq!(added.borrow().0, 0);
+        assert_eq!(other.borrow().0, 3);
+
+        cloned.borrow_mut().0 = 10;
+        snapshot.restore();
+        assert_eq!(cloned.borrow().0, 1);
+
+        reset_storage(principal(1));
+        assert_eq!(cloned.borrow().0, 0);
+        assert_eq!(serialized.borrow().0, 0);
+        assert_eq!(other.borrow().0, 3);
+    }
+
+    #[test]
+    #[should_panic(expected = "cannot take a snapshot of")]
+    fn snapshot_of_opaque_state() {
+        register::<Opaque>(1, None);
+        snapshot_storage(principal(1));
+    }
+}
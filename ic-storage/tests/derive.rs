@@ -1,7 +1,10 @@
-use ic_canister::ic_kit::MockContext;
+use std::collections::HashMap;
+
+use ic_canister::ic_kit::{mock_principals, MockContext};
+use ic_storage::testing::{reset_storage, snapshot_storage};
 use ic_storage::{generic_derive, IcStorage};
 
-#[derive(IcStorage, Default)]
+#[derive(IcStorage, Default, Clone)]
 struct TestStorage {
     val: u32,
 }
@@ -13,6 +16,11 @@ struct GenericStorage<T> {
 
 generic_derive!(GenericStorage<u128>);
 
+#[derive(IcStorage, Default)]
+struct Cache<K, V> {
+    values: HashMap<K, V>,
+}
+
 #[test]
 fn storage_derive_macro() {
     MockContext::new().inject();
@@ -28,3 +36,50 @@ fn generic_storage_derive() {
     let storage = GenericStorage::<u128>::get();
     assert_eq!(storage.borrow().val, 0);
 }
+
+#[test]
+fn generic_storage_derive_macro() {
+    MockContext::new().inject();
+
+    Cache::<u32, String>::get()
+        .borrow_mut()
+        .values
+        .insert(1, "one".into());
+
+    assert_eq!(Cache::<u32, String>::get().borrow().values[&1], "one");
+    assert!(Cache::<u32, u64>::get().borrow().values.is_empty());
+}
+
+#[test]
+fn generic_storage_per_canister() {
+    let context = MockContext::new()
+        .with_id(mock_principals::alice())
+        .inject();
+    Cache::<u32, u32>::get().borrow_mut().values.insert(1, 1);
+
+    context.update_id(mock_principals::bob());
+    assert!(Cache::<u32, u32>::get().borrow().values.is_empty());
+
+    context.update_id(mock_principals::alice());
+    assert_eq!(Cache::<u32, u32>::get().borrow().values.len(), 1);
+}
+
+#[test]
+fn snapshot_and_reset_per_canister() {
+    let context = MockContext::new()
+        .with_id(mock_principals::alice())
+        .inject();
+    TestStorage::get().borrow_mut().val = 1;
+    let snapshot = snapshot_storage(mock_principals::alice());
+
+    context.update_id(mock_principals::bob());
+    TestStorage::get().borrow_mut().val = 2;
+    reset_storage(mock_principals::alice());
+    assert_eq!(TestStorage::get().borrow().val, 2);
+
+    context.update_id(mock_principals::alice());
+    assert_eq!(TestStorage::get().borrow().val, 0);
+
+    snapshot.restore();
+    assert_eq!(TestStorage::get().borrow().val, 1);
+}